@@ -1 +1,10 @@
-fn main() {}
+use clap::Clap;
+use leader_elect::error::ThreadSafeResult;
+use leader_elect::logger;
+use leader_elect::ring::ring::{run, Opts};
+
+fn main() -> ThreadSafeResult<()> {
+    let opts: Opts = Opts::parse();
+    logger::init(opts.log_level.as_ref()).expect("fail to set the logger");
+    run(&opts)
+}
@@ -0,0 +1,109 @@
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use derive_more::Display;
+use serde::{Deserialize, Deserializer};
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::str::FromStr;
+
+/// Address is where a node can be reached, either an IPv4 or IPv6 socket
+/// address, or a host name with a port. Host names are resolved on every
+/// connection, so that a node can move to another IP once it restarts.
+#[derive(Display, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone)]
+pub enum Address {
+    #[display(fmt = "{}", _0)]
+    Socket(SocketAddr),
+    #[display(fmt = "{}:{}", _0, _1)]
+    Host(String, u16),
+}
+
+impl Address {
+    /// port returns the port of the address.
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Socket(addr) => addr.port(),
+            Address::Host(_, port) => *port,
+        }
+    }
+
+    /// resolve returns the socket addresses of the address.
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Address::Socket(addr) => Ok(vec![*addr]),
+            Address::Host(host, port) => Ok((host.as_str(), *port).to_socket_addrs()?.collect()),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> ThreadSafeResult<Address> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Address::Socket(addr));
+        }
+        let invalid = || new_box_err!(format!("invalid address({}), expect host:port", s));
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        // a bare IPv6 address is ambiguous, it should be put in brackets
+        if host.is_empty() || host.contains(':') {
+            return Err(invalid());
+        }
+        Ok(Address::Host(host.to_owned(), port.parse()?))
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Socket(addr)
+    }
+}
+
+impl From<SocketAddrV4> for Address {
+    fn from(addr: SocketAddrV4) -> Address {
+        Address::Socket(addr.into())
+    }
+}
+
+impl From<SocketAddrV6> for Address {
+    fn from(addr: SocketAddrV6) -> Address {
+        Address::Socket(addr.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Address;
+
+    #[test]
+    fn from_str() {
+        for s in [
+            "127.0.0.1:7001",
+            "[::1]:7001",
+            "localhost:7001",
+            "node-1.svc:80",
+        ]
+        .iter()
+        {
+            assert_eq!(s.parse::<Address>().unwrap().to_string(), *s);
+        }
+        assert!(matches!(
+            "[::1]:7001".parse::<Address>().unwrap(),
+            Address::Socket(_)
+        ));
+        assert!(matches!(
+            "localhost:7001".parse::<Address>().unwrap(),
+            Address::Host(_, 7001)
+        ));
+        for s in ["localhost", ":7001", "::1:7001", "localhost:port"].iter() {
+            assert!(s.parse::<Address>().is_err(), "{}", s);
+        }
+        let resolved = "localhost:7001".parse::<Address>().unwrap().resolve();
+        assert!(resolved.unwrap().iter().all(|addr| addr.port() == 7001));
+    }
+}
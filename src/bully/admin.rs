@@ -0,0 +1,123 @@
+use crate::bully::bully::NodeHandle;
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// AdminServer serves an HTTP API with JSON bodies to inspect and control a
+/// node:
+///
+/// - `GET /status`: the node, its leader, term and peers
+/// - `GET /healthz`: 200 while the node runs
+/// - `GET /readyz`: 200 once the node knows a leader
+/// - `GET /metrics`: metrics in the Prometheus text format
+/// - `POST /elect`: starts an election right away
+/// - `POST /resign`: makes the leader step down and stand by
+pub struct AdminServer {
+    server: Arc<Server>,
+    handler: Option<JoinHandle<()>>,
+}
+
+impl AdminServer {
+    /// serve listens on the `address`, and serves requests about the node
+    /// of the `handle` in a background thread.
+    pub fn serve(handle: Arc<NodeHandle>, address: &str) -> ThreadSafeResult<AdminServer> {
+        let server = Server::http(address)
+            .map_err(|e| new_box_err!(format!("fail to serve admin API on {}: {}", address, e)))?;
+        let server = Arc::new(server);
+        info!("serve admin API on {}", server.server_addr());
+        let sv_clone = Arc::clone(&server);
+        let handler = thread::spawn(move || {
+            for request in sv_clone.incoming_requests() {
+                respond(&handle, request);
+            }
+        });
+        Ok(AdminServer {
+            server,
+            handler: Some(handler),
+        })
+    }
+
+    /// local_addr returns the address the server listens on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// shutdown stops serving, and waits for the pending request to finish.
+    pub fn shutdown(mut self) {
+        self.server.unblock();
+        if let Some(handler) = self.handler.take() {
+            let _ = handler.join();
+        }
+    }
+}
+
+/// respond routes the `request` and replies the result, which is in JSON
+/// except for metrics.
+fn respond(handle: &NodeHandle, request: Request) {
+    let path = request.url().split('?').next().unwrap_or_default();
+    let (code, body, content_type) = match (request.method(), path) {
+        (Method::Get, "/metrics") => match handle.metrics() {
+            Ok(metrics) => (200, metrics, TEXT_FORMAT),
+            Err(e) => (500, json!({ "error": e.to_string() }).to_string(), JSON),
+        },
+        (method, path) => {
+            let (code, body) = route(handle, method, path);
+            (code, body.to_string(), JSON)
+        }
+    };
+    let content_type = Header::from_bytes("Content-Type", content_type).unwrap();
+    let response = Response::from_string(body)
+        .with_status_code(code)
+        .with_header(content_type);
+    if let Err(e) = request.respond(response) {
+        warn!("fail to respond to admin request: {}", e);
+    }
+}
+
+const JSON: &str = "application/json";
+/// TEXT_FORMAT is the content type of the Prometheus text format.
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+/// route handles the JSON request `method` on `path`, and returns the status
+/// code and body of the response.
+fn route(handle: &NodeHandle, method: &Method, path: &str) -> (u16, Value) {
+    match (method, path) {
+        (Method::Get, "/status") => (200, json!(handle.status())),
+        (Method::Get, "/healthz") => match handle.is_running() {
+            true => (200, json!({ "status": "ok" })),
+            false => (503, json!({ "status": "stopped" })),
+        },
+        (Method::Get, "/readyz") => match handle.leader() {
+            Some(leader) => (200, json!({ "status": "ok", "leader": leader })),
+            None => (503, json!({ "status": "no leader" })),
+        },
+        (Method::Post, "/elect") => match handle.elect() {
+            Ok(won) => (
+                200,
+                json!({ "won": won, "leader": handle.leader(), "term": handle.term() }),
+            ),
+            Err(e) => (500, json!({ "error": e.to_string() })),
+        },
+        (Method::Post, "/resign") => match handle.resign() {
+            true => (200, json!({ "resigned": true, "term": handle.term() })),
+            false => (
+                409,
+                json!({ "error": format!("node({}) is not the leader", handle.id()) }),
+            ),
+        },
+        (_, "/status")
+        | (_, "/healthz")
+        | (_, "/readyz")
+        | (_, "/metrics")
+        | (_, "/elect")
+        | (_, "/resign") => (
+            405,
+            json!({ "error": format!("method {} is not allowed", method) }),
+        ),
+        _ => (404, json!({ "error": format!("{} is not found", path) })),
+    }
+}
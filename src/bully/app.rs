@@ -0,0 +1,270 @@
+use crate::bully::message::NodeId;
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// AppMessage is a message of the application carried by the `App` message
+/// between members of a cluster: the kind registered by the application,
+/// and the body in any format it likes, e.g., "config:v2". The kind is not
+/// empty and has no colons.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AppMessage {
+    pub kind: String,
+    pub body: String,
+}
+
+impl AppMessage {
+    /// new creates the message of the `kind`, which should be valid.
+    pub fn new(kind: &str, body: &str) -> ThreadSafeResult<AppMessage> {
+        check_kind(kind)?;
+        Ok(AppMessage {
+            kind: kind.to_owned(),
+            body: body.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for AppMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind, self.body)
+    }
+}
+
+impl FromStr for AppMessage {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> ThreadSafeResult<AppMessage> {
+        // the body may contain colons
+        let (kind, body) = s
+            .split_once(':')
+            .ok_or(new_box_err!(format!("invalid application message({})", s)))?;
+        AppMessage::new(kind, body)
+    }
+}
+
+fn check_kind(kind: &str) -> ThreadSafeResult<()> {
+    if kind.is_empty() || kind.contains(':') {
+        return Err(new_box_err!(format!(
+            "invalid application message kind({})",
+            kind
+        )));
+    }
+    Ok(())
+}
+
+/// AppHandler receives the bodies of the application messages of a kind
+/// with the ids of their senders. Handlers are called from the thread
+/// reading the connection the message arrives on, without locking the node,
+/// so they may use the node, but a slow handler delays the messages behind
+/// it on the same connection.
+pub trait AppHandler: Send + Sync {
+    fn receive(&self, sender: NodeId, body: &str);
+}
+
+impl<F: Fn(NodeId, &str) + Send + Sync> AppHandler for F {
+    fn receive(&self, sender: NodeId, body: &str) {
+        self(sender, body)
+    }
+}
+
+/// AppHandlers holds the handlers registered on a node by kinds of
+/// application messages.
+#[derive(Default)]
+pub struct AppHandlers(HashMap<String, Box<dyn AppHandler>>);
+
+impl AppHandlers {
+    /// insert registers the `handler` of the `kind`, replacing the previous
+    /// one if any.
+    pub fn insert(&mut self, kind: &str, handler: Box<dyn AppHandler>) -> ThreadSafeResult<()> {
+        check_kind(kind)?;
+        self.0.insert(kind.to_owned(), handler);
+        Ok(())
+    }
+
+    /// receive passes the `msg` from the `sender` to the handler of its
+    /// kind, and drops messages of kinds the node does not handle.
+    pub fn receive(&self, sender: NodeId, msg: &AppMessage) {
+        match self.0.get(&msg.kind) {
+            Some(handler) => handler.receive(sender, &msg.body),
+            None => debug!(
+                "drop application message of kind {} from node({})",
+                msg.kind, sender
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for AppHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kinds: Vec<&String> = self.0.keys().collect();
+        kinds.sort();
+        write!(f, "AppHandlers({:?})", kinds)
+    }
+}
+
+/// RequestHandler handles the bodies of the requests of a kind forwarded to
+/// the leader with the ids of the nodes they come from, and returns the body
+/// of the reply. Like `AppHandler`, it is called without locking the node.
+pub trait RequestHandler: Send + Sync {
+    fn handle(&self, sender: NodeId, body: &str) -> ThreadSafeResult<String>;
+}
+
+impl<F: Fn(NodeId, &str) -> ThreadSafeResult<String> + Send + Sync> RequestHandler for F {
+    fn handle(&self, sender: NodeId, body: &str) -> ThreadSafeResult<String> {
+        self(sender, body)
+    }
+}
+
+/// RequestHandlers holds the handlers registered on a node by kinds of
+/// requests.
+#[derive(Default)]
+pub struct RequestHandlers(HashMap<String, Box<dyn RequestHandler>>);
+
+impl RequestHandlers {
+    /// insert registers the `handler` of the `kind`, replacing the previous
+    /// one if any.
+    pub fn insert(&mut self, kind: &str, handler: Box<dyn RequestHandler>) -> ThreadSafeResult<()> {
+        check_kind(kind)?;
+        self.0.insert(kind.to_owned(), handler);
+        Ok(())
+    }
+
+    /// handle passes the `request` from the `sender` to the handler of its
+    /// kind, and fails requests of kinds the node does not handle.
+    pub fn handle(&self, sender: NodeId, request: &AppMessage) -> ForwardAck {
+        let handler = match self.0.get(&request.kind) {
+            Some(handler) => handler,
+            None => return ForwardAck::Failed(format!("no handler of kind {}", request.kind)),
+        };
+        match handler.handle(sender, &request.body) {
+            Ok(body) => ForwardAck::Handled(body),
+            Err(e) => ForwardAck::Failed(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Debug for RequestHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kinds: Vec<&String> = self.0.keys().collect();
+        kinds.sort();
+        write!(f, "RequestHandlers({:?})", kinds)
+    }
+}
+
+/// ForwardAck is carried by the `ForwardAck` replied to `Forward`: the body
+/// of the reply, e.g., "handled: v2", why the leader fails the request,
+/// e.g., "failed: no handler of kind config", or "not leader" if the node
+/// does not lead any more.
+#[derive(Debug, PartialEq)]
+pub enum ForwardAck {
+    Handled(String),
+    Failed(String),
+    NotLeader,
+}
+
+const HANDLED: &str = "handled: ";
+const FAILED: &str = "failed: ";
+const NOT_LEADER: &str = "not leader";
+
+impl fmt::Display for ForwardAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardAck::Handled(body) => write!(f, "{}{}", HANDLED, body),
+            ForwardAck::Failed(reason) => write!(f, "{}{}", FAILED, reason),
+            ForwardAck::NotLeader => write!(f, "{}", NOT_LEADER),
+        }
+    }
+}
+
+impl FromStr for ForwardAck {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> ThreadSafeResult<ForwardAck> {
+        if let Some(body) = s.strip_prefix(HANDLED) {
+            return Ok(ForwardAck::Handled(body.to_owned()));
+        }
+        if let Some(reason) = s.strip_prefix(FAILED) {
+            return Ok(ForwardAck::Failed(reason.to_owned()));
+        }
+        if s == NOT_LEADER {
+            return Ok(ForwardAck::NotLeader);
+        }
+        Err(new_box_err!(format!("invalid forward ack({})", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppHandlers, AppMessage, ForwardAck, RequestHandlers};
+    use crate::error::{LeaderElectError, ThreadSafeResult};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn parse_and_receive() {
+        let msg: AppMessage = "config:v2:eu".parse().unwrap();
+        assert_eq!(msg, AppMessage::new("config", "v2:eu").unwrap());
+        assert_eq!(msg.to_string(), "config:v2:eu");
+        assert_eq!("ping:".parse::<AppMessage>().unwrap().body, "");
+        assert!("config".parse::<AppMessage>().is_err());
+        assert!(":v2".parse::<AppMessage>().is_err());
+        assert!(AppMessage::new("a:b", "v2").is_err());
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers = AppHandlers::default();
+        let clone = Arc::clone(&received);
+        handlers
+            .insert(
+                "config",
+                Box::new(move |sender, body: &str| {
+                    clone.lock().unwrap().push((sender, body.to_owned()))
+                }),
+            )
+            .unwrap();
+        assert!(handlers.insert("", Box::new(|_, _: &str| {})).is_err());
+        handlers.receive(3, &msg);
+        handlers.receive(3, &AppMessage::new("unknown", "v3").unwrap());
+        assert_eq!(*received.lock().unwrap(), vec![(3, "v2:eu".to_owned())]);
+    }
+
+    #[test]
+    fn handle_requests() {
+        let mut handlers = RequestHandlers::default();
+        handlers
+            .insert(
+                "lock",
+                Box::new(|sender, body: &str| -> ThreadSafeResult<String> {
+                    match body {
+                        "" => Err(new_box_err!("missing name".to_owned())),
+                        name => Ok(format!("{} locked by node({})", name, sender)),
+                    }
+                }),
+            )
+            .unwrap();
+        let request = |body| AppMessage::new("lock", body).unwrap();
+        assert_eq!(
+            handlers.handle(2, &request("jobs")),
+            ForwardAck::Handled("jobs locked by node(2)".to_owned())
+        );
+        assert_eq!(
+            handlers.handle(2, &request("")),
+            ForwardAck::Failed("missing name".to_owned())
+        );
+        let unknown = AppMessage::new("unknown", "jobs").unwrap();
+        assert!(matches!(
+            handlers.handle(2, &unknown),
+            ForwardAck::Failed(_)
+        ));
+
+        for ack in [
+            ForwardAck::Handled("a: b".to_owned()),
+            ForwardAck::Handled("".to_owned()),
+            ForwardAck::Failed("missing name".to_owned()),
+            ForwardAck::NotLeader,
+        ] {
+            assert_eq!(ack.to_string().parse::<ForwardAck>().unwrap(), ack);
+        }
+        assert!("leader".parse::<ForwardAck>().is_err());
+    }
+}
@@ -0,0 +1,1370 @@
+use crate::bully::address::Address;
+use crate::bully::auth::Auth;
+use crate::bully::bully::{parse_peer_addresses, ElectionResult, Liveness};
+use crate::bully::handshake::{
+    Hello, HelloAck, APP_MESSAGES_VERSION, DEFAULT_CLUSTER, FORWARD_VERSION,
+};
+use crate::bully::membership::Member;
+use crate::bully::message::{
+    self, Codec, ElectResponse, Message,
+    MessageType::{self, *},
+    NodeId, Rank, TextCodec,
+};
+use crate::bully::timing::Timing;
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use log::{debug, error, info, trace, warn};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Mutex, MutexGuard};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::WatchStream;
+
+/// send writes `msg` to the `stream` in a frame encoded by the `codec`,
+/// using the same wire format as the threaded node, so that both kinds of
+/// nodes can join the same cluster.
+pub async fn send<W: AsyncWrite + Unpin>(
+    msg: &Message,
+    codec: &dyn Codec,
+    stream: &mut W,
+) -> ThreadSafeResult<()> {
+    write_frame(stream, &codec.encode(msg)).await
+}
+
+/// receive reads the next frame from the `stream`, and decodes the message
+/// in it with the `codec`.
+pub async fn receive<R: AsyncRead + Unpin>(
+    codec: &dyn Codec,
+    stream: &mut R,
+) -> ThreadSafeResult<Message> {
+    codec.decode(&read_frame(stream).await?)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, body: &[u8]) -> ThreadSafeResult<()> {
+    let mut frame = Vec::new();
+    message::write_frame(&mut frame, body)?;
+    Ok(stream.write_all(&frame).await?)
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> ThreadSafeResult<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).await?;
+    let mut body = vec![0; message::frame_len(len)?];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// start runs the `node` as tasks on the current tokio runtime, serving
+/// peers through the `listener`, and returns a handle to control it.
+pub async fn start(mut node: Node, listener: TcpListener) -> ThreadSafeResult<NodeHandle> {
+    let leader = node.leader_tx.subscribe();
+    let (inbox, received) = mpsc::unbounded_channel();
+    node.inbox = Some(inbox);
+    let arc_node = Arc::new(Mutex::new(node));
+    let (stop, stopped) = watch::channel(false);
+    let mut tasks = Vec::new();
+
+    // 1. serve peers on the listener
+    tasks.push(tokio::spawn(listen_and_serve(
+        Arc::clone(&arc_node),
+        listener,
+        stopped.clone(),
+    )));
+    tasks.push(tokio::spawn(dispatch_received(
+        Arc::clone(&arc_node),
+        received,
+        stopped.clone(),
+    )));
+
+    // 2. tell peers the node is back and start an election. The tasks
+    // started so far are stopped if the node fails to start
+    rejoin(&arc_node).await;
+    if let Err(e) = run_election(&arc_node).await {
+        let handle = NodeHandle {
+            node: arc_node,
+            leader,
+            stopper: stop,
+            tasks,
+        };
+        handle.kill().await;
+        return Err(e);
+    }
+
+    // 3. send heartbeat if the node is the leader
+    tasks.push(tokio::spawn(heartbeat(
+        Arc::clone(&arc_node),
+        stopped.clone(),
+    )));
+
+    // 4. check if leader is alive
+    tasks.push(tokio::spawn(check_leader(
+        Arc::clone(&arc_node),
+        stopped.clone(),
+    )));
+
+    // 5. connect to dead peers again once they are reachable
+    tasks.push(tokio::spawn(reconnect(Arc::clone(&arc_node), stopped)));
+
+    Ok(NodeHandle {
+        node: arc_node,
+        leader,
+        stopper: stop,
+        tasks,
+    })
+}
+
+/// NodeHandle controls a node started by `start`. The node stops once the
+/// handle is dropped.
+pub struct NodeHandle {
+    node: Arc<Mutex<Node>>,
+    leader: watch::Receiver<Option<NodeId>>,
+    stopper: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<ThreadSafeResult<()>>>,
+}
+
+impl NodeHandle {
+    /// leader returns the leader known by the node, if any.
+    pub fn leader(&self) -> Option<NodeId> {
+        *self.leader.borrow()
+    }
+
+    /// watch_leader returns a receiver that is notified whenever the leader
+    /// known by the node changes.
+    pub fn watch_leader(&self) -> watch::Receiver<Option<NodeId>> {
+        self.leader.clone()
+    }
+
+    /// leader_stream yields the leader known by the node, then each change
+    /// of it.
+    pub fn leader_stream(&self) -> WatchStream<Option<NodeId>> {
+        WatchStream::new(self.leader.clone())
+    }
+
+    /// term returns the latest election term known by the node.
+    pub async fn term(&self) -> u64 {
+        self.node.lock().await.term
+    }
+
+    /// elect starts an election right away, and returns true if the node
+    /// wins it.
+    pub async fn elect(&self) -> ThreadSafeResult<bool> {
+        run_election(&self.node).await
+    }
+
+    /// shutdown stops the node gracefully, a leader resigns first.
+    pub async fn shutdown(self) {
+        {
+            let mut node = self.node.lock().await;
+            if node.is_leader() {
+                resign(&mut node).await;
+            }
+            info!("node({}) is shutting down", node.id);
+        }
+        self.stop().await;
+    }
+
+    /// kill stops the node abruptly, as if its process crashed.
+    pub async fn kill(self) {
+        info!("node({}) is killed", self.node.lock().await.id);
+        self.stop().await;
+    }
+
+    /// stop tells all tasks to exit, closes all connections and waits for
+    /// the tasks to finish.
+    async fn stop(self) {
+        let _ = self.stopper.send(true);
+        for peer in self.node.lock().await.peers.values_mut() {
+            peer.conn = None;
+        }
+        for task in self.tasks {
+            match task.await {
+                Err(e) => error!("task failed: {}", e),
+                Ok(Err(e)) => error!("task failed: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+/// sleep waits for `dur` and returns true if the node is stopped in the
+/// meantime.
+async fn sleep(stopped: &mut watch::Receiver<bool>, dur: Duration) -> bool {
+    if *stopped.borrow() {
+        return true;
+    }
+    tokio::select! {
+        _ = time::sleep(dur) => *stopped.borrow(),
+        // an error means the handle is dropped
+        _ = stopped.changed() => true,
+    }
+}
+
+/// check_leader periodically checks if leader is malfunctioned
+async fn check_leader(
+    arc_node: Arc<Mutex<Node>>,
+    mut stopped: watch::Receiver<bool>,
+) -> ThreadSafeResult<()> {
+    let interval = arc_node.lock().await.timing.leader_check_interval;
+    while !sleep(&mut stopped, interval).await {
+        let elects = {
+            let mut node = arc_node.lock().await;
+            match node.last_leader_heartbeat {
+                None => node.leader.is_none(),
+                Some(last_heartbeat) if last_heartbeat.elapsed() <= interval => false,
+                Some(_) => {
+                    if let Some(leader) = node.leader {
+                        if let Some(peer) = node.peers.get_mut(&leader.id) {
+                            peer.suspect();
+                        }
+                    }
+                    set_leader(&mut node, None);
+                    node.last_leader_heartbeat = None;
+                    true
+                }
+            }
+        };
+        if elects {
+            run_election(&arc_node).await?;
+        }
+    }
+    Ok(())
+}
+
+/// heartbeat sends heartbeat messages to all live peers while the
+/// node is the leader.
+async fn heartbeat(
+    arc_node: Arc<Mutex<Node>>,
+    mut stopped: watch::Receiver<bool>,
+) -> ThreadSafeResult<()> {
+    let interval = arc_node.lock().await.timing.heartbeat_interval;
+    while !sleep(&mut stopped, interval).await {
+        let mut node = arc_node.lock().await;
+        if !node.is_leader() {
+            continue;
+        }
+        let (rank, term, wire) = (node.rank(), node.term, node.wire());
+        for (id, peer) in node.peers.iter_mut() {
+            if peer.is_dead() {
+                continue;
+            }
+            let msg = Message::new(rank, HeartBeat, term);
+            if let Err(e) = send_message(peer, &msg, &wire).await {
+                warn!("fail to send heartbeat to peer({}): {}", id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// set_leader updates the leader known by the node and notifies the
+/// watchers about the change.
+fn set_leader(node: &mut Node, leader: Option<Rank>) {
+    node.leader = leader;
+    let leader = leader.map(|leader| leader.id);
+    node.leader_tx.send_if_modified(|current| {
+        if *current == leader {
+            return false;
+        }
+        *current = leader;
+        true
+    });
+}
+
+/// win_election makes the node the leader of a new term and announces its
+/// victory.
+async fn win_election(node: &mut Node) {
+    node.term += 1;
+    info!("node({}) is the leader of term {}", node.id, node.term);
+    let (rank, term, wire) = (node.rank(), node.term, node.wire());
+    set_leader(node, Some(rank));
+    node.last_leader_heartbeat = None;
+    for (id, peer) in node.peers.iter_mut() {
+        if peer.is_dead() {
+            continue;
+        }
+        let msg = Message::new(rank, Victory, term);
+        if let Err(e) = send_message(peer, &msg, &wire).await {
+            warn!("fail to send Victory to peer({}): {}", id, e);
+        }
+    }
+}
+
+/// resign tells all live peers that the leader steps down.
+async fn resign(node: &mut Node) {
+    info!("node({}) resigns from term {}", node.id, node.term);
+    let (rank, term, wire) = (node.rank(), node.term, node.wire());
+    for (id, peer) in node.peers.iter_mut() {
+        if peer.is_dead() {
+            continue;
+        }
+        let msg = Message::new(rank, Resign, term);
+        if let Err(e) = send_message(peer, &msg, &wire).await {
+            warn!("fail to send Resign to peer({}): {}", id, e);
+        }
+    }
+    set_leader(node, None);
+}
+
+/// rejoin connects to all reachable peers and sends them the `Rejoin`
+/// message, then catches up with the terms they reply. The node is not
+/// locked while waiting for replies.
+async fn rejoin(arc_node: &Arc<Mutex<Node>>) {
+    let (rank, term, timing, wire, addresses) = {
+        let node = arc_node.lock().await;
+        let addresses: Vec<(NodeId, Address)> = node
+            .peers
+            .iter()
+            .map(|(id, peer)| (*id, peer.address.clone()))
+            .collect();
+        (node.rank(), node.term, node.timing, node.wire(), addresses)
+    };
+    for (id, address) in addresses {
+        let (mut conn, version) = match connect(&address, id, rank, &wire, timing).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("fail to connect to peer({}): {}", id, e);
+                continue;
+            }
+        };
+        let request = Message::new_request(rank, Rejoin, term);
+        let res = match wire.send(&request, id, &mut conn.writer).await {
+            Ok(()) => {
+                let mut reader = conn.reader.lock().await;
+                wait_alive(&mut *reader, &request, timing.alive_timeout, &wire).await
+            }
+            Err(e) => Err(e),
+        };
+        let mut node = arc_node.lock().await;
+        // the peer may have left meanwhile
+        let peer = match node.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => continue,
+        };
+        peer.conn = Some(conn);
+        peer.version = Some(version);
+        match res {
+            Ok(ElectResponse::BuillerAlive(reply)) | Ok(ElectResponse::Abstained(reply)) => {
+                info!("peer({}) connected", id);
+                peer.set_liveness(Liveness::Alive);
+                peer.priority = reply.get_sender().priority;
+                node.update_term(reply.get_term());
+            }
+            Ok(ElectResponse::ResponseTimeOut) => {
+                warn!("peer({}) connected, but does not reply to Rejoin", id);
+                peer.set_liveness(Liveness::Suspected);
+            }
+            Err(e) => {
+                warn!("fail to send Rejoin to peer({}): {}", id, e);
+                peer.disconnect();
+            }
+        }
+    }
+}
+
+/// elect sends `Elect` to all live peers with higher rank at once, and
+/// wins if none of them replies `Alive` within the alive timeout. The
+/// replies are awaited concurrently, so that an election takes one alive
+/// timeout at most whatever the number of peers.
+///
+/// The node is locked to send `Elect` and to apply the replies, but not
+/// while waiting for them, so that it keeps serving its peers meanwhile. It
+/// is returned locked along with the result, so that the winner announces
+/// its victory before anything else changes. An election started while
+/// another one is running fails right away, and lets the running one decide.
+async fn elect(arc_node: &Mutex<Node>) -> ThreadSafeResult<(ElectionResult, MutexGuard<'_, Node>)> {
+    let mut waits = {
+        let mut node = arc_node.lock().await;
+        if node.electing {
+            debug!("node({}) is running an election already", node.id);
+            return Ok((ElectionResult::Fail, node));
+        }
+        node.electing = true;
+        let (rank, term, wire) = (node.rank(), node.term, node.wire());
+        let deadline = Instant::now() + node.timing.alive_timeout;
+        let mut waits = JoinSet::new();
+        for (id, peer) in node.peers.iter_mut() {
+            if peer.is_dead() || peer.rank() < rank {
+                continue;
+            }
+            let request = Message::new_request(rank, Elect, term);
+            if let Err(e) = send_message(peer, &request, &wire).await {
+                // the builler is unreachable, and is considered dead
+                warn!("fail to send Elect to peer({}): {}", id, e);
+                continue;
+            }
+            let (id, connection, wire) = (*id, peer.connections, wire.clone());
+            let reader = Arc::clone(&peer.conn.as_ref().unwrap().reader);
+            waits.spawn(async move {
+                let mut reader = reader.lock().await;
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let res = wait_alive(&mut *reader, &request, timeout, &wire).await;
+                (id, connection, res)
+            });
+        }
+        waits
+    };
+
+    // every reply arrives by the deadline, as it bounds the waits
+    let mut replies = Vec::new();
+    while let Some(joined) = waits.join_next().await {
+        replies.push(joined);
+    }
+
+    let mut node = arc_node.lock().await;
+    node.electing = false;
+    let (node_id, mut latest_term) = (node.id, node.term);
+    let mut bullier = None;
+    for joined in replies {
+        let (id, connection, res) = joined?;
+        // the peer reconnected meanwhile, the reply is lost with the
+        // connection it was waited on
+        let peer = match node.peers.get_mut(&id) {
+            Some(peer) if peer.connections == connection => peer,
+            _ => continue,
+        };
+        match res {
+            Ok(ElectResponse::BuillerAlive(reply)) => {
+                peer.set_liveness(Liveness::Alive);
+                peer.priority = reply.get_sender().priority;
+                bullier.get_or_insert(id);
+                latest_term = latest_term.max(reply.get_term());
+            }
+            // the peer is on standby, and lets the node lead
+            Ok(ElectResponse::Abstained(reply)) => {
+                peer.set_liveness(Liveness::Alive);
+                peer.priority = reply.get_sender().priority;
+                latest_term = latest_term.max(reply.get_term());
+            }
+            Ok(ElectResponse::ResponseTimeOut) => peer.suspect(),
+            Err(e) => {
+                warn!("fail to receive Alive from peer({}): {}", id, e);
+                peer.disconnect();
+            }
+        }
+    }
+    node.update_term(latest_term);
+    if let Some(id) = bullier {
+        info!(
+            "node({}) fail to elect: the bullier({}) is alive",
+            node_id, id
+        );
+        return Ok((ElectionResult::Fail, node));
+    }
+    // a peer that won an election meanwhile keeps leading, unless the node
+    // outranks it
+    if let Some(leader) = node.leader {
+        if leader.id != node_id && !node.challenges(leader) {
+            info!(
+                "node({}) fail to elect: peer({}) leads meanwhile",
+                node_id, leader.id
+            );
+            return Ok((ElectionResult::Fail, node));
+        }
+    }
+    info!(
+        "all bullier are dead, node ({}) will be the leader",
+        node.id
+    );
+    Ok((ElectionResult::Win, node))
+}
+
+/// run_election runs an election, see `elect`, and makes the node the
+/// leader if it wins. The node must not be locked by the caller.
+async fn run_election(arc_node: &Mutex<Node>) -> ThreadSafeResult<bool> {
+    match elect(arc_node).await? {
+        (ElectionResult::Win, mut node) => {
+            win_election(&mut node).await;
+            Ok(true)
+        }
+        (ElectionResult::Fail, _) => Ok(false),
+    }
+}
+
+/// reconnect periodically tries to connect to dead peers again, so that a
+/// peer lost to a transient error or a partition takes part in elections
+/// and heartbeats again once it is reachable. The node is not locked while
+/// connecting.
+async fn reconnect(
+    arc_node: Arc<Mutex<Node>>,
+    mut stopped: watch::Receiver<bool>,
+) -> ThreadSafeResult<()> {
+    let interval = arc_node.lock().await.timing.leader_check_interval;
+    while !sleep(&mut stopped, interval).await {
+        let (rank, timing, wire, dead) = {
+            let node = arc_node.lock().await;
+            let dead: Vec<(NodeId, Address)> = node
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.is_dead())
+                .map(|(id, peer)| (*id, peer.address.clone()))
+                .collect();
+            (node.rank(), node.timing, node.wire(), dead)
+        };
+        // a peer that is still unreachable is tried again next time
+        let timing = Timing { retry: 0, ..timing };
+        for (id, address) in dead {
+            if *stopped.borrow() {
+                break;
+            }
+            let conn = match connect(&address, id, rank, &wire, timing).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!("fail to reconnect to peer({}): {}", id, e);
+                    continue;
+                }
+            };
+            let mut node = arc_node.lock().await;
+            // the connection is dropped if the peer rejoined meanwhile
+            if let Some(peer) = node.peers.get_mut(&id).filter(|peer| peer.is_dead()) {
+                info!("peer({}) is reachable again", id);
+                peer.reconnect(Ok(conn));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// send_message sends `msg` to `peer` through the `wire`. A peer that fails
+/// to receive the message is considered dead until the node connects to it
+/// again.
+async fn send_message(peer: &mut Peer, msg: &Message, wire: &Wire) -> ThreadSafeResult<()> {
+    debug!("send message {}", msg);
+    if let Some(conn) = peer.conn.as_mut() {
+        let res = wire.send(msg, peer.id, &mut conn.writer).await;
+        if res.is_err() {
+            peer.disconnect();
+        }
+        return res;
+    }
+    peer.set_liveness(Liveness::Dead);
+    Err(new_box_err!(
+        "try to send message through nonexist connection".to_owned()
+    ))
+}
+
+/// wait_alive waits for the `Alive` reply to the `request` on the `conn`
+/// for `timeout`.
+async fn wait_alive<R: AsyncBufRead + Unpin>(
+    conn: &mut R,
+    request: &Message,
+    timeout: Duration,
+    wire: &Wire,
+) -> ThreadSafeResult<ElectResponse> {
+    match receive_reply(conn, request, timeout, wire).await? {
+        None => Ok(ElectResponse::ResponseTimeOut),
+        Some(rep_msg) => match rep_msg.get_message_type() {
+            MessageType::Alive => Ok(ElectResponse::BuillerAlive(rep_msg)),
+            MessageType::Abstain => Ok(ElectResponse::Abstained(rep_msg)),
+            wrong_type => Err(new_box_err!(format!(
+                "incorrect message type({})",
+                wrong_type
+            ))),
+        },
+    }
+}
+
+/// receive_reply reads messages from the `conn` until the reply to the
+/// `request`, and returns `None` if there is none within `timeout`. Other
+/// messages received meanwhile are dispatched, see `Wire::dispatch`. A
+/// frame cut off by the timeout is an error, as the rest of it would be
+/// taken for the next frame, so the connection should be given up.
+async fn receive_reply<R: AsyncBufRead + Unpin>(
+    conn: &mut R,
+    request: &Message,
+    timeout: Duration,
+    wire: &Wire,
+) -> ThreadSafeResult<Option<Message>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // waiting for the next frame consumes nothing, and may time out
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match time::timeout(timeout, conn.fill_buf()).await {
+            Err(_) => return Ok(None),
+            Ok(buf) => {
+                if buf?.is_empty() {
+                    return Err(new_box_err!("the connection is closed".to_owned()));
+                }
+            }
+        }
+        let frame = time::timeout(timeout, read_frame(conn))
+            .await
+            .map_err(|_| new_box_err!("the frame is cut off by the timeout".to_owned()))??;
+        let msg = wire.open(&frame)?;
+        if msg.replies_to(request) {
+            return Ok(Some(msg));
+        }
+        wire.dispatch(msg);
+    }
+}
+
+/// listen_and_serve accepts connections from peers and handles the
+/// messages sent through them, each connection in its own task.
+async fn listen_and_serve(
+    arc_node: Arc<Mutex<Node>>,
+    listener: TcpListener,
+    mut stopped: watch::Receiver<bool>,
+) -> ThreadSafeResult<()> {
+    // peers are welcomed without locking the node, which may be waiting for
+    // them meanwhile
+    let (id, wire) = {
+        let node = arc_node.lock().await;
+        (node.id, node.wire())
+    };
+    loop {
+        let (conn, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = stopped.changed() => return Ok(()),
+        };
+        info!("accept connection from {}", addr);
+        let node_clone = Arc::clone(&arc_node);
+        let hdl_stopped = stopped.clone();
+        let wire = wire.clone();
+        tokio::spawn(async move {
+            let mut conn = BufReader::new(conn);
+            let res = match welcome(id, &mut conn, &wire).await {
+                Ok(()) => handle_message(node_clone, conn, wire, hdl_stopped).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                warn!("connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// handle_message keeps reading messages from the conn and handling
+/// them accordingly. Messages that fail authentication are dropped.
+async fn handle_message(
+    arc_node: Arc<Mutex<Node>>,
+    mut conn: BufReader<TcpStream>,
+    wire: Wire,
+    mut stopped: watch::Receiver<bool>,
+) -> ThreadSafeResult<()> {
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut conn) => frame?,
+            _ = stopped.changed() => return Ok(()),
+        };
+        let msg = match wire.open(&frame) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("drop message of {} bytes: {}", frame.len(), e);
+                continue;
+            }
+        };
+        dispatch(&arc_node, msg, Some(&mut conn), &wire).await?;
+    }
+}
+
+/// dispatch handles the `msg` received on the `conn`, and replies through
+/// it if needed. Without the `conn`, the message is received while waiting
+/// for a reply, and replies go through the connection to its sender.
+async fn dispatch(
+    arc_node: &Arc<Mutex<Node>>,
+    msg: Message,
+    conn: Option<&mut BufReader<TcpStream>>,
+    wire: &Wire,
+) -> ThreadSafeResult<()> {
+    let mut node = arc_node.lock().await;
+    let (sender_id, sender, term) = (msg.get_sender_id(), msg.get_sender(), msg.get_term());
+    if let Some(peer) = node.peers.get_mut(&sender_id) {
+        peer.priority = sender.priority;
+        if peer.conn.is_some() {
+            peer.set_liveness(Liveness::Alive);
+        }
+    }
+    match msg.get_message_type() {
+        MessageType::Elect => {
+            node.update_term(term);
+            let reply = msg.reply(node.rank(), Alive, node.term);
+            send_reply(&mut node, &reply, msg.get_sender_id(), conn, wire).await?;
+            // continue the election
+            drop(node);
+            run_election(arc_node).await?;
+        }
+
+        MessageType::Victory | MessageType::HeartBeat => {
+            if node.challenges(sender) && term > node.term {
+                return challenge(arc_node, node, sender, term).await;
+            }
+            // ignore the claim unless the sender may lead in its term, e.g.,
+            // a deposed leader still sending heartbeats
+            if node.challenges(sender) || !node.accepts_leader(sender, term) {
+                debug!(
+                    "ignore {} from peer({}) in term {}, the leader is {:?} in term {}",
+                    msg.get_message_type(),
+                    sender_id,
+                    term,
+                    node.leader,
+                    node.term
+                );
+                return Ok(());
+            }
+            trace!("peer({}) leads term {}", sender_id, term);
+            if node.leader.map(|leader| leader.id) != Some(sender_id) {
+                info!("peer({}) is the leader of term {}", sender_id, term);
+            }
+            node.term = term;
+            set_leader(&mut node, Some(sender));
+            node.last_leader_heartbeat = Some(Instant::now());
+        }
+
+        MessageType::Rejoin => {
+            node.update_term(term);
+            let reply = msg.reply(node.rank(), Alive, node.term);
+            send_reply(&mut node, &reply, msg.get_sender_id(), conn, wire).await?;
+            // a joining node is connected once its Join is forwarded
+            let address = match node.peers.get(&sender_id) {
+                Some(peer) => peer.address.clone(),
+                None => return Ok(()),
+            };
+            info!("peer({}) rejoins the cluster", sender_id);
+            let (rank, timing) = (node.rank(), node.timing);
+            // the node is not locked while the peer may be greeting it too
+            drop(node);
+            let new_conn = connect(&address, sender_id, rank, wire, timing).await;
+            if let Some(peer) = arc_node.lock().await.peers.get_mut(&sender_id) {
+                peer.reconnect(new_conn);
+            }
+        }
+
+        MessageType::Join => {
+            let member: Member = msg.get_payload().parse()?;
+            // the node does not know its own address to reply members
+            if sender_id == member.id {
+                return Err(new_box_err!(format!(
+                    "node({}) asks an async node to be its seed",
+                    member.id
+                )));
+            }
+            info!(
+                "node({}) joins the cluster at {}",
+                member.id, member.address
+            );
+            node.update_term(term);
+            let (rank, timing) = (node.rank(), node.timing);
+            drop(node);
+            let new_conn = connect(&member.address, member.id, rank, wire, timing).await;
+            let mut node = arc_node.lock().await;
+            let peer = node
+                .peers
+                .entry(member.id)
+                .or_insert_with(|| Peer::new(member.id, member.address.clone()));
+            peer.address = member.address;
+            peer.reconnect(new_conn);
+        }
+
+        MessageType::Leave => {
+            if let Some(mut peer) = node.peers.remove(&sender_id) {
+                info!("peer({}) leaves the cluster", sender_id);
+                peer.disconnect();
+            }
+            if node.leader.map(|leader| leader.id) == Some(sender_id) {
+                set_leader(&mut node, None);
+                node.last_leader_heartbeat = None;
+                drop(node);
+                run_election(arc_node).await?;
+            }
+        }
+
+        MessageType::Resign => {
+            if msg.get_payload() != message::STANDBY {
+                if let Some(peer) = node.peers.get_mut(&sender_id) {
+                    peer.disconnect();
+                }
+            }
+            if node.leader.map(|leader| leader.id) != Some(sender_id) || term < node.term {
+                return Ok(());
+            }
+            info!("leader({}) resigns, start an election", sender_id);
+            set_leader(&mut node, None);
+            node.last_leader_heartbeat = None;
+            drop(node);
+            run_election(arc_node).await?;
+        }
+
+        wrong_type => {
+            return Err(new_box_err!(format!(
+                "unsupported message type {}",
+                wrong_type
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// challenge starts an election to take over from the lower ranked `sender`
+/// leading the newer `term`, e.g., a peer elected while the node was
+/// partitioned from it. The node catches up with the term first, so that
+/// its victory is not rejected as stale, and two leaders never remain. The
+/// `node` locked by the caller is unlocked to run the election.
+async fn challenge(
+    arc_node: &Mutex<Node>,
+    mut node: MutexGuard<'_, Node>,
+    sender: Rank,
+    term: u64,
+) -> ThreadSafeResult<()> {
+    info!(
+        "node({}) ranked {} challenges peer({}) ranked {} leading term {}",
+        node.id,
+        node.rank(),
+        sender.id,
+        sender,
+        term
+    );
+    node.update_term(term);
+    set_leader(&mut node, None);
+    node.last_leader_heartbeat = None;
+    drop(node);
+    run_election(arc_node).await?;
+    Ok(())
+}
+
+/// dispatch_received handles the messages handed over by `Wire::dispatch`
+/// until the node stops.
+async fn dispatch_received(
+    arc_node: Arc<Mutex<Node>>,
+    mut received: mpsc::UnboundedReceiver<Message>,
+    mut stopped: watch::Receiver<bool>,
+) -> ThreadSafeResult<()> {
+    loop {
+        let msg = tokio::select! {
+            msg = received.recv() => match msg {
+                Some(msg) => msg,
+                None => return Ok(()),
+            },
+            _ = stopped.changed() => return Ok(()),
+        };
+        let wire = arc_node.lock().await.wire();
+        if let Err(e) = dispatch(&arc_node, msg, None, &wire).await {
+            warn!("fail to handle a message received while waiting: {}", e);
+        }
+    }
+}
+
+/// send_reply sends the `reply` to the node `to` through the `conn`, or
+/// through the connection to the node without it.
+async fn send_reply(
+    node: &mut Node,
+    reply: &Message,
+    to: NodeId,
+    conn: Option<&mut BufReader<TcpStream>>,
+    wire: &Wire,
+) -> ThreadSafeResult<()> {
+    match (conn, node.peers.get_mut(&to)) {
+        (Some(conn), _) => wire.send(reply, to, conn).await,
+        (None, Some(peer)) => send_message(peer, reply, wire).await,
+        (None, None) => Err(new_box_err!(format!("peer({}) is unknown", to))),
+    }
+}
+
+/// connect connects to the node `id` at the `address`, retrying on timeouts,
+/// greeted as the node of the `rank`, and returns the connection with the
+/// protocol version negotiated by the peer. Host names are resolved again on
+/// every attempt.
+async fn connect(
+    address: &Address,
+    id: NodeId,
+    rank: Rank,
+    wire: &Wire,
+    timing: Timing,
+) -> ThreadSafeResult<(PeerConn, u32)> {
+    let (reader, mut writer) = dial(address, timing).await?.into_split();
+    let mut reader = BufReader::new(reader);
+    let hello =
+        Message::new_request(rank, MessageType::Hello, 0).with_payload(wire.hello.to_string());
+    wire.send(&hello, id, &mut writer).await?;
+    let reply = receive_reply(&mut reader, &hello, timing.alive_timeout, wire)
+        .await?
+        .ok_or(new_box_err!("the peer does not reply to Hello".to_owned()))?;
+    let version = wire.hello.check_ack(&reply, Some(id))?;
+    let conn = PeerConn {
+        writer,
+        reader: Arc::new(Mutex::new(reader)),
+    };
+    Ok((conn, version))
+}
+
+/// welcome waits for the `Hello` a peer sends first on the `conn` it
+/// connected to the node `id`, and accepts the connection with the protocol
+/// version negotiated, or refuses it with the reason. Peers starting a TLS
+/// handshake are refused, as the async node has no TLS transport.
+async fn welcome(id: NodeId, conn: &mut BufReader<TcpStream>, wire: &Wire) -> ThreadSafeResult<()> {
+    if conn.fill_buf().await?.starts_with(&TLS_HANDSHAKE) {
+        return Err(new_box_err!(
+            "refuse a TLS connection, which the async node does not support".to_owned()
+        ));
+    }
+    let msg = wire.open_hello(&read_frame(conn).await?)?;
+    let version = wire.hello.accept(id, &msg);
+    let ack = match version.as_ref() {
+        Ok(version) => HelloAck::Accepted(*version),
+        Err(e) => HelloAck::Refused(e.to_string()),
+    };
+    let reply = msg
+        .reply(Rank::new(0, id), MessageType::HelloAck, 0)
+        .with_payload(ack.to_string());
+    wire.send(&reply, msg.get_sender_id(), conn).await?;
+    match version {
+        Ok(_) => Ok(()),
+        Err(e) => Err(new_box_err!(format!(
+            "refuse connection from node({}): {}",
+            msg.get_sender_id(),
+            e
+        ))),
+    }
+}
+
+/// hello greets as an async node in the `cluster`. The async node has no
+/// application or request handlers yet, so it speaks no protocol version
+/// with `App` messages or `Forward` requests, and peers send it neither.
+fn hello(cluster: &str) -> Hello {
+    Hello {
+        max_version: APP_MESSAGES_VERSION.min(FORWARD_VERSION) - 1,
+        ..Hello::new(cluster)
+    }
+}
+
+/// dial connects to the `address`, retrying on timeouts.
+async fn dial(address: &Address, timing: Timing) -> ThreadSafeResult<TcpStream> {
+    let mut count = timing.retry;
+    loop {
+        let conn = async {
+            match address {
+                Address::Socket(addr) => TcpStream::connect(*addr).await,
+                Address::Host(host, port) => TcpStream::connect((host.as_str(), *port)).await,
+            }
+        };
+        match time::timeout(timing.conn_timeout, conn).await {
+            Err(_) if count > 0 => count -= 1,
+            Err(e) => return Err(Box::new(e)),
+            Ok(conn) => return Ok(conn?),
+        }
+    }
+}
+
+/// TLS_HANDSHAKE starts the first record of a TLS handshake, i.e., its
+/// content type and the major version.
+const TLS_HANDSHAKE: [u8; 2] = [0x16, 0x03];
+
+/// Wire writes messages in frames, encoded by the codec and sealed by the
+/// auth of the node, and reads them back. Connections start with the hello
+/// of the node.
+#[derive(Debug, Clone)]
+struct Wire {
+    /// the id of the node, which messages read should be sealed for
+    id: NodeId,
+    codec: Arc<dyn Codec>,
+    auth: Arc<Auth>,
+    hello: Arc<Hello>,
+    inbox: Option<mpsc::UnboundedSender<Message>>,
+}
+
+impl Wire {
+    /// send writes the `msg` to the node `to` on the `stream`.
+    async fn send<W: AsyncWrite + Unpin>(
+        &self,
+        msg: &Message,
+        to: NodeId,
+        stream: &mut W,
+    ) -> ThreadSafeResult<()> {
+        write_frame(stream, &self.auth.seal(self.codec.encode(msg), Some(to))).await
+    }
+
+    /// open decodes the message in the `frame`, and fails if the frame is
+    /// not authenticated, or not sent to the node.
+    fn open(&self, frame: &[u8]) -> ThreadSafeResult<Message> {
+        self.auth
+            .open(frame, Some(self.id), |body| self.codec.decode(body))
+    }
+
+    /// open_hello is like `open`, but also accepts a `Hello` sent to any
+    /// node, i.e., by a node joining through the node as a seed.
+    fn open_hello(&self, frame: &[u8]) -> ThreadSafeResult<Message> {
+        let msg = self
+            .open(frame)
+            .or_else(|_| self.auth.open(frame, None, |body| self.codec.decode(body)))?;
+        match msg.get_message_type() {
+            MessageType::Hello => Ok(msg),
+            wrong_type => Err(new_box_err!(format!("expect Hello, got {}", wrong_type))),
+        }
+    }
+
+    /// dispatch hands the `msg` received while waiting for a reply over to
+    /// the dispatcher of the node, so that handling it, e.g., running an
+    /// election, does not hold up the wait.
+    fn dispatch(&self, msg: Message) {
+        match self.inbox.as_ref() {
+            // the dispatcher is gone once the node stops
+            Some(inbox) => {
+                let _ = inbox.send(msg);
+            }
+            None => debug!("drop message {} while waiting for a reply", msg),
+        }
+    }
+}
+
+/// Node is a bully node running on tokio. It speaks the same protocol as the
+/// threaded `bully::Node`, so both can be mixed in a cluster, as long as the
+/// cluster connects over plain TCP: the async node has no TLS transport, and
+/// refuses peers connecting with TLS, e.g., nodes started with `--tls-ca`.
+#[derive(Debug)]
+pub struct Node {
+    id: NodeId,
+    priority: u64,
+    peers: BTreeMap<NodeId, Peer>,
+    leader: Option<Rank>,
+    leader_tx: watch::Sender<Option<NodeId>>,
+    /// the latest election term known by the node
+    term: u64,
+    last_leader_heartbeat: Option<Instant>,
+    timing: Timing,
+    /// an election is waiting for replies, see `elect`
+    electing: bool,
+    /// encodes messages into frames, and decodes them
+    codec: Arc<dyn Codec>,
+    /// seals messages to send, and opens messages received
+    auth: Arc<Auth>,
+    /// greets peers on new connections
+    hello: Arc<Hello>,
+    /// hands messages received while waiting for replies to the dispatcher
+    inbox: Option<mpsc::UnboundedSender<Message>>,
+}
+
+/// PeerConn is a connection the node made to a peer. It is split, so that
+/// an election waits for replies on the reader while the node keeps sending
+/// through the writer.
+#[derive(Debug)]
+struct PeerConn {
+    writer: OwnedWriteHalf,
+    reader: Arc<Mutex<BufReader<OwnedReadHalf>>>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    id: NodeId,
+    /// the priority last heard from the peer
+    priority: u64,
+    address: Address,
+    conn: Option<PeerConn>,
+    /// counts the connections made to the peer, so that a wait on a stale
+    /// connection is told apart, see `elect`
+    connections: u64,
+    liveness: Liveness,
+    /// the protocol version negotiated on the connection, if any
+    version: Option<u32>,
+}
+
+impl Peer {
+    fn new(id: NodeId, address: Address) -> Peer {
+        Peer {
+            id,
+            priority: 0,
+            address,
+            conn: None,
+            connections: 0,
+            liveness: Liveness::Dead,
+            version: None,
+        }
+    }
+
+    fn set_liveness(&mut self, liveness: Liveness) {
+        if self.liveness != liveness {
+            info!("peer({}) is {}, was {}", self.id, liveness, self.liveness);
+            self.liveness = liveness;
+        }
+    }
+
+    /// suspect is called when the peer fails to respond in time. A peer
+    /// that is suspected already is considered dead.
+    fn suspect(&mut self) {
+        match self.liveness {
+            Liveness::Alive => self.set_liveness(Liveness::Suspected),
+            _ => self.set_liveness(Liveness::Dead),
+        }
+    }
+
+    /// reconnect replaces the connection to the peer with the new `conn` and
+    /// its protocol version, and marks the peer as alive, or as dead if the
+    /// node fails to connect.
+    fn reconnect(&mut self, conn: ThreadSafeResult<(PeerConn, u32)>) {
+        match conn {
+            Ok((conn, version)) => {
+                self.conn = Some(conn);
+                self.connections += 1;
+                self.version = Some(version);
+                self.set_liveness(Liveness::Alive);
+            }
+            Err(e) => {
+                warn!("fail to reconnect to peer({}): {}", self.id, e);
+                self.disconnect();
+            }
+        }
+    }
+
+    /// disconnect closes the connection to the peer and marks it as dead.
+    fn disconnect(&mut self) {
+        self.conn = None;
+        self.version = None;
+        self.set_liveness(Liveness::Dead);
+    }
+
+    fn is_dead(&self) -> bool {
+        self.liveness == Liveness::Dead
+    }
+
+    fn rank(&self) -> Rank {
+        Rank::new(self.priority, self.id)
+    }
+}
+
+impl Node {
+    pub fn new(id: NodeId, peer_str: &str) -> ThreadSafeResult<Node> {
+        let peers = parse_peer_addresses(peer_str)?
+            .into_iter()
+            .map(|(id, address)| (id, Peer::new(id, address)))
+            .collect();
+        Ok(Node {
+            id,
+            priority: 0,
+            peers,
+            leader: None,
+            leader_tx: watch::channel(None).0,
+            term: 0,
+            last_leader_heartbeat: None,
+            timing: Timing::default(),
+            electing: false,
+            codec: Arc::new(TextCodec),
+            auth: Arc::default(),
+            hello: Arc::new(hello(DEFAULT_CLUSTER)),
+            inbox: None,
+        })
+    }
+
+    /// set_timing replaces the default timing of the node, and fails if the
+    /// `timing` is invalid.
+    pub fn set_timing(&mut self, timing: Timing) -> ThreadSafeResult<()> {
+        timing.validate()?;
+        self.timing = timing;
+        Ok(())
+    }
+
+    /// set_codec replaces the text codec of messages, all nodes of the
+    /// cluster should use the same codec.
+    pub fn set_codec(&mut self, codec: Arc<dyn Codec>) {
+        self.codec = codec;
+    }
+
+    /// set_cluster names the cluster of the node, peers of other clusters
+    /// are refused. Nodes are in the cluster "default" unless told.
+    pub fn set_cluster(&mut self, cluster: &str) {
+        self.hello = Arc::new(hello(cluster));
+    }
+
+    /// set_priority changes the election priority of the node, which is 0
+    /// by default.
+    pub fn set_priority(&mut self, priority: u64) {
+        self.priority = priority;
+    }
+
+    /// set_auth makes the node authenticate the messages it sends and
+    /// receives, which are not authenticated by default. All nodes of the
+    /// cluster should share the same secret.
+    pub fn set_auth(&mut self, auth: Auth) {
+        self.auth = Arc::new(auth);
+    }
+
+    /// wire returns how the node writes and reads messages.
+    fn wire(&self) -> Wire {
+        Wire {
+            id: self.id,
+            codec: Arc::clone(&self.codec),
+            auth: Arc::clone(&self.auth),
+            hello: Arc::clone(&self.hello),
+            inbox: self.inbox.clone(),
+        }
+    }
+
+    fn rank(&self) -> Rank {
+        Rank::new(self.priority, self.id)
+    }
+
+    fn is_leader(&self) -> bool {
+        self.leader.map(|leader| leader.id) == Some(self.id)
+    }
+
+    /// challenges tells if the node outranks the `leader`, and should lead
+    /// instead.
+    fn challenges(&self, leader: Rank) -> bool {
+        self.rank() > leader
+    }
+
+    /// update_term adopts `term` if it is newer than the current term.
+    fn update_term(&mut self, term: u64) {
+        if term > self.term {
+            debug!(
+                "node({}) moves from term {} to {}",
+                self.id, self.term, term
+            );
+            self.term = term;
+        }
+    }
+
+    /// accepts_leader tells if a leadership claimed by `sender` in `term` is
+    /// not older than the one known by the node.
+    fn accepts_leader(&self, sender: Rank, term: u64) -> bool {
+        (term, sender) >= (self.term, self.leader.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        connect, dispatch, listen_and_serve, receive, receive_reply, run_election, send, welcome,
+        Node,
+    };
+    use crate::bully::bully::Liveness;
+    use crate::bully::message::{BinaryCodec, Message, MessageType::*, Rank, TextCodec};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{mpsc, watch, Mutex};
+
+    #[tokio::test]
+    async fn send_then_receive() {
+        let (leader, candidate) = (Rank::new(5, 3), Rank::new(0, 1));
+        let mut buf = Vec::new();
+        send(&Message::new(leader, Victory, 7), &TextCodec, &mut buf)
+            .await
+            .unwrap();
+        send(&Message::new(candidate, Elect, 6), &TextCodec, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, b"\0\0\0\x093:3:7:5:0\0\0\0\x091:1:6:0:0");
+        send(&Message::new(candidate, Elect, 6), &BinaryCodec, &mut buf)
+            .await
+            .unwrap();
+        let mut rd = BufReader::new(&buf[..]);
+        assert_eq!(
+            receive(&TextCodec, &mut rd).await.unwrap(),
+            Message::new(leader, Victory, 7)
+        );
+        assert_eq!(
+            receive(&TextCodec, &mut rd).await.unwrap(),
+            Message::new(candidate, Elect, 6)
+        );
+        assert_eq!(
+            receive(&BinaryCodec, &mut rd).await.unwrap(),
+            Message::new(candidate, Elect, 6)
+        );
+        assert!(receive(&TextCodec, &mut rd).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn give_up_frames_cut_off_by_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut conn = BufReader::new(listener.accept().await.unwrap().0);
+        let wire = Node::new(1, "2=127.0.0.1:7002").unwrap().wire();
+        let elect = Message::new(Rank::new(0, 1), Elect, 3);
+        let timeout = Duration::from_millis(100);
+        // no reply at all is a timeout
+        assert!(receive_reply(&mut conn, &elect, timeout, &wire)
+            .await
+            .unwrap()
+            .is_none());
+        // a reply cut off in the middle is an error
+        peer.write_all(b"\0\0\0\x091:1").await.unwrap();
+        assert!(receive_reply(&mut conn, &elect, timeout, &wire)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn dispatch_unrelated_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut conn = BufReader::new(listener.accept().await.unwrap().0);
+        let mut node = Node::new(1, "2=127.0.0.1:7002").unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        node.inbox = Some(tx);
+        let wire = node.wire();
+        // the peer sends a heartbeat before replying Alive to Elect
+        let (one, two) = (Rank::new(0, 1), Rank::new(0, 2));
+        let elect = Message::new_request(one, Elect, 3);
+        send(&Message::new(two, HeartBeat, 3), &TextCodec, &mut peer)
+            .await
+            .unwrap();
+        send(&elect.reply(two, Alive, 3), &TextCodec, &mut peer)
+            .await
+            .unwrap();
+        let timeout = Duration::from_secs(1);
+        let reply = receive_reply(&mut conn, &elect, timeout, &wire).await;
+        assert_eq!(reply.unwrap().unwrap().get_message_type(), Alive);
+        assert_eq!(rx.recv().await.unwrap().get_message_type(), HeartBeat);
+    }
+
+    #[tokio::test]
+    async fn elect_at_once() {
+        // node(3) greets peers, but never replies to Elect
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        tokio::spawn(async move {
+            let wire = Node::new(3, "1=127.0.0.1:7001").unwrap().wire();
+            let mut conns = Vec::new();
+            loop {
+                let mut conn = BufReader::new(silent.accept().await.unwrap().0);
+                welcome(3, &mut conn, &wire).await.unwrap();
+                conns.push(conn);
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let two = Node::new(2, &format!("1=127.0.0.1:7001,3={}", silent_addr)).unwrap();
+        let one = Node::new(1, &format!("2={}", addr)).unwrap();
+        let (one, two) = (Arc::new(Mutex::new(one)), Arc::new(Mutex::new(two)));
+        let (_stop, stopped) = watch::channel(false);
+        tokio::spawn(listen_and_serve(Arc::clone(&two), listener, stopped));
+        for (node, id) in [(&two, 3), (&one, 2)] {
+            let mut node = node.lock().await;
+            let (rank, wire, timing) = (node.rank(), node.wire(), node.timing);
+            let peer = node.peers.get_mut(&id).unwrap();
+            peer.reconnect(connect(&peer.address, id, rank, &wire, timing).await);
+            assert_eq!(peer.liveness, Liveness::Alive);
+        }
+
+        // node(2) keeps serving node(1) while it waits for node(3), so
+        // node(1) hears from node(2) in time and loses
+        let (won_one, won_two) = tokio::join!(run_election(&one), run_election(&two));
+        assert!(!won_one.unwrap());
+        won_two.unwrap();
+        assert!(two.lock().await.is_leader());
+        assert!(!one.lock().await.is_leader());
+    }
+
+    #[tokio::test]
+    async fn challenge_lower_ranked_leaders() {
+        let node = Arc::new(Mutex::new(Node::new(2, "1=127.0.0.1:7001").unwrap()));
+        let wire = node.lock().await.wire();
+        // node(1) won while partitioned from node(2), which takes over
+        let victory = Message::new(Rank::new(0, 1), Victory, 5);
+        dispatch(&node, victory, None, &wire).await.unwrap();
+        assert!(node.lock().await.is_leader());
+        assert_eq!(node.lock().await.term, 6);
+        // the deposed leader is ignored until it hears from node(2)
+        let heartbeat = Message::new(Rank::new(0, 1), HeartBeat, 6);
+        dispatch(&node, heartbeat, None, &wire).await.unwrap();
+        assert!(node.lock().await.is_leader());
+    }
+}
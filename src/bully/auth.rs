@@ -0,0 +1,252 @@
+use crate::bully::message::{Message, NodeId};
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// REPLAY_WINDOW bounds how far a message may fall behind the latest one
+/// received from its sender, older messages are dropped as replays. It
+/// covers messages of a sender reordered across connections.
+const REPLAY_WINDOW: Duration = Duration::from_secs(30);
+
+/// COUNTER_SIZE and TAG_SIZE are the sizes of the counter and the tag
+/// appended to the body of a frame.
+const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 32;
+
+/// Auth authenticates messages with an HMAC-SHA256 over a secret shared by
+/// all nodes of the cluster. The body of every frame is followed by a
+/// counter in big endian, and the tag over both. The counter is the time
+/// the sender sent the message in nanoseconds, and grows strictly for each
+/// message of the sender, so that a node restarting with a sane clock keeps
+/// counting upwards. A message whose counter was already received from its
+/// sender, or falls behind the latest one by more than the `REPLAY_WINDOW`,
+/// is a replay. The tag covers the id of the recipient too, which is not
+/// sent, so that a message captured on its way to one node is refused by
+/// the others.
+///
+/// Any node holding the secret can sign for another node, peers are told
+/// apart by their certificates with `TlsTransport`.
+///
+/// The default `Auth` neither signs nor verifies messages.
+#[derive(Default)]
+pub struct Auth {
+    key: Option<Vec<u8>>,
+    /// the counter of the last message sent
+    counter: AtomicU64,
+    /// counters received recently, by senders
+    received: Mutex<HashMap<NodeId, Received>>,
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never leak the secret into logs
+        write!(f, "Auth {{ enabled: {} }}", self.key.is_some())
+    }
+}
+
+/// Received holds the counters of a sender within the replay window.
+#[derive(Debug, Default)]
+struct Received {
+    latest: u64,
+    counters: BTreeSet<u64>,
+}
+
+impl Auth {
+    /// new authenticates messages with the `secret`, which should not be
+    /// empty.
+    pub fn new(secret: &[u8]) -> ThreadSafeResult<Auth> {
+        if secret.is_empty() {
+            return Err(new_box_err!("the secret is empty".to_owned()));
+        }
+        Ok(Auth {
+            key: Some(secret.to_vec()),
+            ..Auth::default()
+        })
+    }
+
+    /// load reads the secret from the file at `path`, ignoring surrounding
+    /// whitespaces.
+    pub fn load(path: &str) -> ThreadSafeResult<Auth> {
+        let secret = fs::read_to_string(path)
+            .map_err(|e| new_box_err!(format!("fail to read secret {}: {}", path, e)))?;
+        Auth::new(secret.trim().as_bytes())
+    }
+
+    /// seal appends the counter and tag to the `body` of a frame sent to
+    /// the `recipient` if messages are authenticated. A frame sealed for no
+    /// recipient is opened by any node, e.g., the `Hello` to a seed whose id
+    /// is unknown yet.
+    pub fn seal(&self, mut body: Vec<u8>, recipient: Option<NodeId>) -> Vec<u8> {
+        let key = match self.key.as_ref() {
+            Some(key) => key,
+            None => return body,
+        };
+        body.extend_from_slice(&self.next_counter().to_be_bytes());
+        let tag = new_mac(key, &body, recipient).finalize().into_bytes();
+        body.extend_from_slice(&tag);
+        body
+    }
+
+    /// open decodes the message from the `frame` with `decode`, and fails if
+    /// messages are authenticated but the frame is not signed with the
+    /// secret for the `recipient`, or is a replay.
+    pub fn open<F>(
+        &self,
+        frame: &[u8],
+        recipient: Option<NodeId>,
+        decode: F,
+    ) -> ThreadSafeResult<Message>
+    where
+        F: FnOnce(&[u8]) -> ThreadSafeResult<Message>,
+    {
+        let key = match self.key.as_ref() {
+            Some(key) => key,
+            None => return decode(frame),
+        };
+        if frame.len() < COUNTER_SIZE + TAG_SIZE {
+            return Err(new_box_err!("unauthenticated message".to_owned()));
+        }
+        let (signed, tag) = frame.split_at(frame.len() - TAG_SIZE);
+        // compared in constant time
+        new_mac(key, signed, recipient)
+            .verify_slice(tag)
+            .map_err(|_| new_box_err!("invalid tag".to_owned()))?;
+        let (body, counter) = signed.split_at(signed.len() - COUNTER_SIZE);
+        let msg = decode(body)?;
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+        self.check_replay(msg.get_sender_id(), counter)?;
+        Ok(msg)
+    }
+
+    /// next_counter returns the counter of the next message to send.
+    fn next_counter(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let prev = self
+            .counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        now.max(prev + 1)
+    }
+
+    /// check_replay records the `counter` of a message from the `sender`,
+    /// and fails if the message is a replay.
+    fn check_replay(&self, sender: NodeId, counter: u64) -> ThreadSafeResult<()> {
+        let window = REPLAY_WINDOW.as_nanos() as u64;
+        let mut received = self.received.lock().unwrap();
+        let received = received.entry(sender).or_default();
+        if counter < received.latest.saturating_sub(window) || !received.counters.insert(counter) {
+            return Err(new_box_err!(format!(
+                "replayed message from node({}) with counter {}",
+                sender, counter
+            )));
+        }
+        received.latest = received.latest.max(counter);
+        let oldest = received.latest.saturating_sub(window);
+        received.counters = received.counters.split_off(&oldest);
+        Ok(())
+    }
+}
+
+/// new_mac returns the MAC over the `signed` part of a frame and its
+/// `recipient`, which is tagged so that no recipient differs from all ids.
+fn new_mac(key: &[u8], signed: &[u8], recipient: Option<NodeId>) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(signed);
+    match recipient {
+        Some(id) => {
+            mac.update(&[1]);
+            mac.update(&id.to_be_bytes());
+        }
+        None => mac.update(&[0]),
+    }
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Auth, REPLAY_WINDOW};
+    use crate::bully::message::{Codec, Message, MessageType::*, Rank, TextCodec};
+
+    #[test]
+    fn seal_then_open() {
+        let (alice, bob) = (Auth::new(b"secret").unwrap(), Auth::new(b"secret").unwrap());
+        // bob is node 2
+        let seal = |auth: &Auth, msg: Message| auth.seal(TextCodec.encode(&msg), Some(2));
+        let open =
+            |auth: &Auth, frame: &[u8]| auth.open(frame, Some(2), |body| TextCodec.decode(body));
+        let victory = Message::new(Rank::new(0, 3), Victory, 7);
+        let frame = seal(&alice, Message::new(Rank::new(0, 3), Victory, 7));
+        assert!(frame.starts_with(b"3:3:7:0:0"));
+        assert_eq!(open(&bob, &frame).unwrap(), victory);
+        // replayed
+        assert!(open(&bob, &frame).is_err());
+        // messages of a sender may arrive out of order
+        let (first, second) = (
+            seal(&alice, Message::new(Rank::new(0, 3), HeartBeat, 7)),
+            seal(&alice, Message::new(Rank::new(0, 3), HeartBeat, 7)),
+        );
+        assert!(open(&bob, &second).is_ok());
+        assert!(open(&bob, &first).is_ok());
+
+        // spoofed
+        let eve = Auth::new(b"guess").unwrap();
+        let frame = seal(&eve, Message::new(Rank::new(0, 9), Victory, 8));
+        assert!(open(&bob, &frame).is_err());
+        assert!(open(&bob, b"9:3:8:0:0").is_err());
+        let mut tampered = seal(&alice, Message::new(Rank::new(0, 3), Victory, 8));
+        tampered[0] = b'9';
+        assert!(open(&bob, &tampered).is_err());
+
+        // without a secret, messages are neither signed nor verified
+        let plain = Auth::default();
+        assert_eq!(
+            seal(&plain, Message::new(Rank::new(0, 3), Victory, 7)),
+            b"3:3:7:0:0"
+        );
+        assert_eq!(open(&plain, b"3:3:7:0:0").unwrap(), victory);
+        assert!(Auth::new(b"").is_err());
+    }
+
+    #[test]
+    fn refuse_messages_to_others() {
+        let (alice, carol) = (Auth::new(b"secret").unwrap(), Auth::new(b"secret").unwrap());
+        let open =
+            |frame: &[u8], recipient| carol.open(frame, recipient, |body| TextCodec.decode(body));
+        // a message to node 2 is replayed to node 3
+        let victory = TextCodec.encode(&Message::new(Rank::new(0, 1), Victory, 7));
+        let frame = alice.seal(victory.clone(), Some(2));
+        assert!(open(&frame, Some(3)).is_err());
+        assert!(open(&frame, None).is_err());
+        assert!(open(&frame, Some(2)).is_ok());
+        // a message to no node in particular is opened as such by any node
+        let frame = alice.seal(victory, None);
+        assert!(open(&frame, Some(3)).is_err());
+        assert!(open(&frame, None).is_ok());
+    }
+
+    #[test]
+    fn reject_stale_counters() {
+        let auth = Auth::new(b"secret").unwrap();
+        let window = REPLAY_WINDOW.as_nanos() as u64;
+        auth.check_replay(1, 10 * window).unwrap();
+        auth.check_replay(1, 9 * window + 1).unwrap();
+        assert!(auth.check_replay(1, 9 * window - 1).is_err());
+        // counters are tracked by senders
+        auth.check_replay(2, 1).unwrap();
+        auth.check_replay(1, 11 * window).unwrap();
+        assert!(auth.check_replay(1, 10 * window).is_err());
+    }
+}
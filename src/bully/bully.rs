@@ -1,19 +1,41 @@
-use crate::bully::consts::*;
+use crate::bully::address::Address;
+use crate::bully::admin::AdminServer;
+use crate::bully::app::{
+    AppHandler, AppHandlers, AppMessage, ForwardAck, RequestHandler, RequestHandlers,
+};
+use crate::bully::auth::Auth;
+use crate::bully::config::ClusterConfig;
+use crate::bully::handshake::{
+    Hello, HelloAck, APP_MESSAGES_VERSION, DEFAULT_CLUSTER, FORWARD_VERSION,
+};
+use crate::bully::membership::{Member, Members};
 use crate::bully::message::{
-    self, ElectResponse, Message,
+    self, Codec, ElectResponse, Message,
     MessageType::{self, *},
+    NodeId, Rank, TextCodec, CODECS,
 };
+use crate::bully::metrics::Metrics;
+use crate::bully::observer::{LeadershipEvent, Observer, Observers};
+use crate::bully::score::{LoadAverage, Score};
+use crate::bully::timing::{Timing, TimingOverrides};
+use crate::bully::tls::TlsTransport;
+use crate::bully::transport::{Conn, Listener, TcpTransport, Transport};
 use crate::error::{LeaderElectError, ThreadSafeResult};
+use chrono::{DateTime, Utc};
 use clap::{AppSettings, Clap};
 use derive_more::Display;
-use log::{debug, error, info, trace};
-use std::collections::{BTreeMap, HashMap};
-use std::io::{self, BufRead, BufReader, ErrorKind, Write};
-use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, ErrorKind, Read};
+use std::mem;
 use std::process;
-use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::SystemTime;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Run a node for leader election using the bully algorithm.
 #[derive(Clap)]
@@ -21,124 +43,1164 @@ use std::time::SystemTime;
 #[clap(setting = AppSettings::ColoredHelp)]
 pub struct Opts {
     /// ID of the current candidate
-    #[clap(short, long)]
-    id: u8,
-    /// Peers' id, addresses pair e.g., --peers="1=0.0.0.0:1234,2=0.0.0.0:5678"
-    #[clap(short, long)]
-    peers: String,
-    /// Address that can be visited by peers
-    #[clap(short, long, default_value = "127.0.0.1:5678")]
-    advertise_address: String,
+    #[clap(short, long, env = "BULLY_ID")]
+    id: NodeId,
+    /// Election priority of the node, the node with the highest priority
+    /// leads, and ties are broken by ids. Defaults to the priority in the
+    /// cluster file, or 0
+    #[clap(long, env = "BULLY_PRIORITY")]
+    priority: Option<u64>,
+    /// Advertise a dynamic score as the priority instead, `load` scores the
+    /// node by its idle CPUs
+    #[clap(long, env = "BULLY_SCORE", possible_values = &["load"], conflicts_with = "priority")]
+    score: Option<String>,
+    /// How much a node should outrank the leader by to take over from it,
+    /// so that small changes of scores do not cause elections
+    #[clap(long, env = "BULLY_HYSTERESIS", default_value = "0")]
+    hysteresis: u64,
+    /// Peers' id, addresses pair e.g., --peers="1=0.0.0.0:1234,2=[::1]:5678,3=node-3:5678"
+    #[clap(
+        short,
+        long,
+        env = "BULLY_PEERS",
+        required_unless_present_any = &["config", "seeds"]
+    )]
+    peers: Option<String>,
+    /// Addresses of seed nodes to join the cluster through, e.g.,
+    /// --seeds="10.0.0.1:5678,node-2:5678". A seed starts a new cluster if
+    /// no other seed is reachable. Nodes joined through seeds leave the
+    /// cluster on SIGTERM or SIGINT
+    #[clap(short, long, env = "BULLY_SEEDS", conflicts_with_all = &["peers", "config"])]
+    seeds: Option<String>,
+    /// Name of the cluster, nodes refuse peers of other clusters. Defaults
+    /// to the name in the cluster file, or "default"
+    #[clap(long, env = "BULLY_CLUSTER")]
+    cluster: Option<String>,
+    /// Cluster file in TOML listing the id and address of all nodes
+    #[clap(short, long, env = "BULLY_CONFIG", conflicts_with = "peers")]
+    config: Option<String>,
+    /// Address that can be visited by peers, either ip:port or host:port,
+    /// defaults to the address of the node in the cluster file, or
+    /// 127.0.0.1:5678
+    #[clap(short, long, env = "BULLY_ADVERTISE_ADDRESS")]
+    advertise_address: Option<String>,
+    /// CA certificate in PEM to verify peers with, enables mutual TLS
+    /// between nodes along with --tls-cert and --tls-key
+    #[clap(long, env = "BULLY_TLS_CA", requires_all = &["tls-cert", "tls-key"])]
+    tls_ca: Option<String>,
+    /// Certificate chain of the node in PEM, which should be issued by the
+    /// CA for the DNS name node-{id}, e.g., node-3
+    #[clap(long, env = "BULLY_TLS_CERT", requires = "tls-ca")]
+    tls_cert: Option<String>,
+    /// Private key of the node in PEM
+    #[clap(long, env = "BULLY_TLS_KEY", requires = "tls-ca")]
+    tls_key: Option<String>,
+    /// File holding a secret shared by all nodes, with which messages are
+    /// authenticated, and replayed messages are dropped
+    #[clap(long, env = "BULLY_SECRET_FILE")]
+    secret_file: Option<String>,
+    /// Encoding of messages, all nodes of the cluster should use the same
+    /// one
+    #[clap(long, env = "BULLY_CODEC", default_value = "text", possible_values = CODECS)]
+    codec: String,
+    /// Address to serve the admin HTTP API on, e.g., 127.0.0.1:8080,
+    /// disabled by default
+    #[clap(long, env = "BULLY_ADMIN_ADDRESS")]
+    admin_address: Option<String>,
     /// A level of verbosity, and can be used multiple times
-    #[clap(short, long, default_value = "info")]
+    #[clap(short, long, default_value = "info", env = "BULLY_LOG_LEVEL")]
     pub log_level: String,
+    #[clap(flatten)]
+    timing: TimingOverrides,
 }
 
+const DEFAULT_ADVERTISE_ADDRESS: &str = "127.0.0.1:5678";
+
+/// FORWARD_RETRY_INTERVAL is how long a forwarded request waits before it is
+/// retried, e.g., while the cluster elects a new leader.
+const FORWARD_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 pub fn run(opts: &Opts) -> ThreadSafeResult<()> {
     // 1. initialize the node object
-    let arc_rw_node = Arc::new(RwLock::new(Node::new(
-        opts.id,
-        &opts.peers,
-        &opts.advertise_address,
-    )?));
+    let node = new_node(opts)?;
     debug!("node({}) initialized", opts.id);
+
+    // 2. listen on the advertise address, the listener is bound before
+    // rejoining so that peers can connect back right away
+    let listener = node.transport.listen(&node.advertise_address)?;
+
+    // 3. shut down gracefully on SIGTERM or SIGINT, nodes joined through
+    // seeds leave the cluster as well
+    let leave = !node.seeds.is_empty();
+    let handle = Arc::new(start(node, listener)?);
+    let sig_handle = Arc::clone(&handle);
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        if let Some(sig) = signals.forever().next() {
+            info!("receive signal {}, shutting down", sig);
+            if leave {
+                sig_handle.leave();
+            } else {
+                sig_handle.shutdown();
+            }
+        }
+    });
+
+    // 4. serve the admin API if asked to
+    let admin = match opts.admin_address.as_ref() {
+        Some(address) => match AdminServer::serve(Arc::clone(&handle), address) {
+            Ok(admin) => Some(admin),
+            Err(e) => {
+                handle.kill();
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    // 5. run the node until all handlers finish
+    handle.join();
+    if let Some(admin) = admin {
+        admin.shutdown();
+    }
+    Ok(())
+}
+
+/// new_node initializes the node from the `opts`, flags and environment
+/// variables take precedence over the cluster file.
+fn new_node(opts: &Opts) -> ThreadSafeResult<Node> {
+    // the advertise address is resolved first, as it should differ from the
+    // addresses of the peers
+    let advertise_address = opts
+        .advertise_address
+        .as_deref()
+        .map(str::parse::<Address>)
+        .transpose()?;
+    let or_default = |address: Option<Address>| match address {
+        Some(address) => Ok(address),
+        None => DEFAULT_ADVERTISE_ADDRESS.parse::<Address>(),
+    };
+    let mut node = match (
+        opts.config.as_ref(),
+        opts.peers.as_ref(),
+        opts.seeds.as_ref(),
+    ) {
+        (Some(path), _, _) => ClusterConfig::load(path)?.node(opts.id, advertise_address)?,
+        (None, Some(peers), _) => Node::with_peers(
+            opts.id,
+            parse_peer_addresses(peers)?,
+            or_default(advertise_address)?,
+        )?,
+        (None, None, Some(seeds)) => {
+            let seeds = seeds
+                .split(',')
+                .map(|seed| seed.parse())
+                .collect::<ThreadSafeResult<Vec<Address>>>()?;
+            Node::with_seeds(opts.id, seeds, or_default(advertise_address)?)
+        }
+        (None, None, None) => {
+            return Err(new_box_err!(
+                "either peers, config or seeds is needed".to_owned()
+            ))
+        }
+    };
+    if let Some(priority) = opts.priority {
+        node.set_priority(priority);
+    }
+    if opts.score.is_some() {
+        node.set_score(LoadAverage);
+    }
+    node.set_hysteresis(opts.hysteresis);
+    node.set_timing(opts.timing.apply(node.timing))?;
+    if let (Some(ca), Some(cert), Some(key)) = (
+        opts.tls_ca.as_ref(),
+        opts.tls_cert.as_ref(),
+        opts.tls_key.as_ref(),
+    ) {
+        node.set_transport(TlsTransport::new(ca, cert, key)?);
+    }
+    if let Some(path) = opts.secret_file.as_ref() {
+        node.set_auth(Auth::load(path)?);
+    }
+    if let Some(cluster) = opts.cluster.as_ref() {
+        node.set_cluster(cluster);
+    }
+    node.set_codec(message::new_codec(&opts.codec)?);
+    Ok(node)
+}
+
+/// start runs the `node` in background threads, serving peers through the
+/// `listener`, and returns a handle to control it.
+pub fn start(mut node: Node, listener: Box<dyn Listener>) -> ThreadSafeResult<NodeHandle> {
     let mut handlers = HashMap::new();
 
-    // 2. listen on the advertise address
+    // 0. notify observers from a dedicated thread, so that they never run
+    // with the node locked
+    let (tx, rx) = mpsc::channel();
+    let observers = mem::take(&mut node.observers);
+    node.events = Some(tx);
+    handlers.insert(
+        "observer handler",
+        thread::spawn(move || {
+            for event in rx {
+                observers.notify(event);
+            }
+            Ok(())
+        }),
+    );
+
+    // messages received while waiting for replies are handled by a
+    // dedicated thread, see `Wire::dispatch`
+    let (inbox, received) = mpsc::channel();
+    node.inbox = Some(inbox);
+
+    // the first election already prefers the fittest node
+    let score = node.score.take();
+    if let Some(score) = score.as_ref() {
+        node.priority = score.score();
+    }
+    let metrics = node.metrics.clone();
+    let arc_rw_node = Arc::new(RwLock::new(node));
+    let stopper = Arc::new(Stopper::default());
+    // the dispatcher exits once the node and its waiting threads are gone,
+    // which may outlive the handlers
+    let dp_clone = Arc::clone(&arc_rw_node);
+    let dp_stopper = Arc::clone(&stopper);
+    thread::spawn(move || dispatch_received(dp_clone, received, dp_stopper));
+
+    // 1. serve peers on the listener
     let ls_clone = Arc::clone(&arc_rw_node);
+    let ls_stopper = Arc::clone(&stopper);
     handlers.insert(
         "message handler",
-        thread::spawn(move || listen_and_serve(ls_clone)),
+        thread::spawn(move || listen_and_serve(ls_clone, listener, ls_stopper)),
     );
 
-    // 3. connect to peers
-    {
-        let mut node = arc_rw_node.write().unwrap();
-        for (id, peer) in &mut node.peers.iter_mut() {
-            (*peer).conn = Some(connect(peer.address)?);
-            info!("peer({}) connected", id);
-        }
+    // 2. learn the members from the seeds if any, connect to peers, tell
+    // them the node is back, and start an election as a recovered node does
+    // in the bully algorithm. The threads started so far are stopped if the
+    // node fails to start
+    let res = join(&arc_rw_node).and_then(|()| {
+        rejoin(&arc_rw_node);
+        run_election(&arc_rw_node)
+    });
+    if let Err(e) = res {
+        let handle = NodeHandle {
+            metrics,
+            node: arc_rw_node,
+            stopper,
+            handlers: Mutex::new(handlers),
+        };
+        handle.kill();
+        return Err(e);
     }
 
-    // 4. send heartbeat if the node is the leader
+    // 3. send heartbeat if the node is the leader
     let hb_clone = Arc::clone(&arc_rw_node);
-    handlers.insert("hearbeat handler", thread::spawn(|| heartbeat(hb_clone)));
+    let hb_stopper = Arc::clone(&stopper);
+    handlers.insert(
+        "hearbeat handler",
+        thread::spawn(|| heartbeat(hb_clone, hb_stopper)),
+    );
 
-    // 5. check if leader is alive
+    // 4. check if leader is alive
     let cl_clone = Arc::clone(&arc_rw_node);
+    let cl_stopper = Arc::clone(&stopper);
     handlers.insert(
         "leader_checker handler",
-        thread::spawn(|| check_leader(cl_clone)),
+        thread::spawn(|| check_leader(cl_clone, cl_stopper)),
     );
 
-    // 6. wait for all handlers to finish
-    for (name, hdl) in handlers {
-        if let Err(e) = hdl.join() {
-            error!("{} failed: {:?}", name, e);
-            process::exit(1);
+    // 5. connect to dead peers again once they are reachable
+    let rc_clone = Arc::clone(&arc_rw_node);
+    let rc_stopper = Arc::clone(&stopper);
+    handlers.insert(
+        "reconnect handler",
+        thread::spawn(|| reconnect(rc_clone, rc_stopper)),
+    );
+
+    // 6. advertise the latest score of the node as its priority
+    if let Some(score) = score {
+        let sc_clone = Arc::clone(&arc_rw_node);
+        let sc_stopper = Arc::clone(&stopper);
+        handlers.insert(
+            "score handler",
+            thread::spawn(|| update_priority(sc_clone, score, sc_stopper)),
+        );
+    }
+
+    Ok(NodeHandle {
+        metrics,
+        node: arc_rw_node,
+        stopper,
+        handlers: Mutex::new(handlers),
+    })
+}
+
+/// NodeHandle controls a node started by `start`.
+pub struct NodeHandle {
+    node: Arc<RwLock<Node>>,
+    /// shared with the node, and read without locking it
+    metrics: Metrics,
+    stopper: Arc<Stopper>,
+    handlers: Mutex<HashMap<&'static str, JoinHandle<ThreadSafeResult<()>>>>,
+}
+
+impl NodeHandle {
+    pub fn id(&self) -> NodeId {
+        self.node.read().unwrap().id
+    }
+
+    /// leader returns the leader known by the node, if any.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.node.read().unwrap().leader.map(|leader| leader.id)
+    }
+
+    /// term returns the latest election term known by the node.
+    pub fn term(&self) -> u64 {
+        self.node.read().unwrap().term
+    }
+
+    /// members returns the ids of the node and its peers in order.
+    pub fn members(&self) -> Vec<NodeId> {
+        let node = self.node.read().unwrap();
+        let mut members: Vec<NodeId> = node.peers.keys().copied().collect();
+        members.push(node.id);
+        members.sort_unstable();
+        members
+    }
+
+    /// broadcast sends the application message of the `kind` to all live
+    /// peers, and fails unless the node is the leader. Peers that cannot
+    /// receive it are skipped.
+    pub fn broadcast(&self, kind: &str, body: &str) -> ThreadSafeResult<()> {
+        let app = AppMessage::new(kind, body)?;
+        let mut node = self.node.write().unwrap();
+        if !node.is_leader() {
+            return Err(new_box_err!(format!("node({}) is not the leader", node.id)));
         }
+        let (rank, term, wire) = (node.rank(), node.term, node.wire());
+        let msg = Message::new(rank, App, term).with_payload(app.to_string());
+        for (id, peer) in node.peers.iter_mut() {
+            if peer.is_dead() {
+                continue;
+            }
+            if !peer.speaks(APP_MESSAGES_VERSION) {
+                warn!("peer({}) does not receive application messages", id);
+                continue;
+            }
+            if let Err(e) = send_message(peer, &msg, &wire) {
+                warn!("fail to send {} to peer({}): {}", app.kind, id, e);
+            }
+        }
+        Ok(())
     }
 
-    Ok(())
+    /// send_to sends the application message of the `kind` to the peer `id`.
+    pub fn send_to(&self, id: NodeId, kind: &str, body: &str) -> ThreadSafeResult<()> {
+        let app = AppMessage::new(kind, body)?;
+        let mut node = self.node.write().unwrap();
+        let (rank, term, wire) = (node.rank(), node.term, node.wire());
+        let peer = node
+            .peers
+            .get_mut(&id)
+            .ok_or(new_box_err!(format!("node({}) is not a peer", id)))?;
+        if !peer.speaks(APP_MESSAGES_VERSION) {
+            return Err(new_box_err!(format!(
+                "peer({}) does not receive application messages",
+                id
+            )));
+        }
+        send_message(
+            peer,
+            &Message::new(rank, App, term).with_payload(app.to_string()),
+            &wire,
+        )
+    }
+
+    /// forward sends the request of the `kind` to the leader, or handles it
+    /// if the node leads, and returns the body of the reply. It waits for a
+    /// leader, and retries when the leadership changes, until the `timeout`.
+    /// A leader may fail after handling a request, and the retry is handled
+    /// again by the next leader, so handlers should be idempotent.
+    pub fn forward(&self, kind: &str, body: &str, timeout: Duration) -> ThreadSafeResult<String> {
+        let request = AppMessage::new(kind, body)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let reason = match self.forward_once(&request, deadline) {
+                Ok(ForwardAck::Handled(body)) => return Ok(body),
+                Ok(ForwardAck::Failed(reason)) => {
+                    return Err(new_box_err!(format!(
+                        "the leader fails request {}: {}",
+                        request.kind, reason
+                    )))
+                }
+                Ok(ForwardAck::NotLeader) => "the leadership changes".to_owned(),
+                Err(e) => e.to_string(),
+            };
+            debug!("retry request {}: {}", request.kind, reason);
+            if Instant::now() + FORWARD_RETRY_INTERVAL >= deadline {
+                return Err(new_box_err!(format!(
+                    "no leader handles request {} in {:?}: {}",
+                    request.kind, timeout, reason
+                )));
+            }
+            if self.stopper.sleep(FORWARD_RETRY_INTERVAL) {
+                return Err(new_box_err!("the node is stopped".to_owned()));
+            }
+        }
+    }
+
+    /// forward_once sends the `request` to the leader known by the node over
+    /// a connection of its own, so that it does not wait behind elections.
+    fn forward_once(
+        &self,
+        request: &AppMessage,
+        deadline: Instant,
+    ) -> ThreadSafeResult<ForwardAck> {
+        let (leader, transport, address, wire, rank, term, metrics, timing) = {
+            let node = self.node.read().unwrap();
+            let leader = node
+                .leader
+                .ok_or(new_box_err!("no leader is elected".to_owned()))?
+                .id;
+            if leader == node.id {
+                let handlers = Arc::clone(&node.request_handlers);
+                let id = node.id;
+                drop(node);
+                return Ok(handlers.handle(id, request));
+            }
+            let address = node
+                .peers
+                .get(&leader)
+                .ok_or(new_box_err!(format!("leader({}) is not a peer", leader)))?
+                .address
+                .clone();
+            (
+                leader,
+                Arc::clone(&node.transport),
+                address,
+                node.wire(),
+                node.rank(),
+                node.term,
+                node.metrics.clone(),
+                node.timing,
+            )
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(new_box_err!("the request times out".to_owned()));
+        }
+        // the leader is retried by the caller, within the deadline
+        let timing = Timing {
+            retry: 0,
+            conn_timeout: timing.conn_timeout.min(remaining),
+            ..timing
+        };
+        let (mut conn, version) = connect_peer(
+            transport.as_ref(),
+            leader,
+            &address,
+            &wire,
+            rank,
+            &metrics,
+            timing,
+        )?;
+        if version < FORWARD_VERSION {
+            return Ok(ForwardAck::Failed(format!(
+                "leader({}) speaks protocol version {}",
+                leader, version
+            )));
+        }
+        let msg = Message::new_request(rank, Forward, term).with_payload(request.to_string());
+        send_message_through_conn(&msg, leader, conn.as_mut(), &wire)?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receive_reply(conn.as_mut(), &msg, remaining, &wire)? {
+            Some(reply) if reply.get_message_type() == MessageType::ForwardAck => {
+                reply.get_payload().parse()
+            }
+            Some(reply) => Err(new_box_err!(format!(
+                "leader({}) replies {} to Forward",
+                leader,
+                reply.get_message_type()
+            ))),
+            None => Err(new_box_err!(format!("leader({}) does not reply", leader))),
+        }
+    }
+
+    /// fencing_token returns the token of the node's leadership, or `None`
+    /// if the node is not the leader. Pass it along with writes to shared
+    /// storage, so that writes from a deposed leader can be rejected.
+    pub fn fencing_token(&self) -> Option<FencingToken> {
+        let node = self.node.read().unwrap();
+        match node.leader {
+            Some(leader) if leader.id == node.id => Some(FencingToken {
+                term: node.term,
+                leader,
+            }),
+            _ => None,
+        }
+    }
+
+    /// status returns a snapshot of the state of the node and its peers.
+    pub fn status(&self) -> NodeStatus {
+        let node = self.node.read().unwrap();
+        NodeStatus {
+            id: node.id,
+            priority: node.priority,
+            leader: node.leader.map(|leader| leader.id),
+            term: node.term,
+            last_leader_heartbeat: node
+                .last_leader_heartbeat
+                .map(|time| DateTime::<Utc>::from(time).to_rfc3339()),
+            standby: node.standby,
+            peers: node
+                .peers
+                .values()
+                .map(|peer| PeerStatus {
+                    id: peer.id,
+                    address: peer.address.to_string(),
+                    priority: peer.priority,
+                    liveness: peer.liveness,
+                    connected: peer.conn.is_some(),
+                    protocol_version: peer.version,
+                })
+                .collect(),
+        }
+    }
+
+    /// metrics returns the metrics of the node in the Prometheus text format.
+    pub fn metrics(&self) -> ThreadSafeResult<String> {
+        self.metrics.encode()
+    }
+
+    /// is_running tells if the node is neither stopped nor stopping.
+    pub fn is_running(&self) -> bool {
+        !self.stopper.is_stopped()
+    }
+
+    /// elect starts an election right away, and tells if the node wins it.
+    /// A node on standby runs for leadership again. Returns false if the
+    /// node is running an election already, which decides instead.
+    pub fn elect(&self) -> ThreadSafeResult<bool> {
+        {
+            let mut node = self.node.write().unwrap();
+            node.standby = false;
+            info!("node({}) is told to start an election", node.id);
+        }
+        run_election(&self.node)
+    }
+
+    /// resign makes the leader step down and stand by, i.e., it lets its
+    /// peers lead until it is told to `elect`, or no peer wins an election.
+    /// Returns false if the node is not the leader.
+    pub fn resign(&self) -> bool {
+        let mut node = self.node.write().unwrap();
+        if !node.is_leader() {
+            return false;
+        }
+        node.standby = true;
+        resign(&mut node);
+        node.last_leader_heartbeat = None;
+        true
+    }
+
+    /// join waits for all handlers of the node to finish. The process exits
+    /// if any of them panics.
+    pub fn join(&self) {
+        let mut handlers = self.handlers.lock().unwrap();
+        for (name, hdl) in handlers.drain() {
+            match hdl.join() {
+                Err(e) => {
+                    error!("{} failed: {:?}", name, e);
+                    process::exit(1);
+                }
+                Ok(Err(e)) => error!("{} failed: {}", name, e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+
+    /// shutdown stops the node gracefully and waits for its handlers to
+    /// finish. A leader resigns first, so that peers elect a new leader
+    /// right away instead of waiting for the leader check to time out.
+    pub fn shutdown(&self) {
+        {
+            let mut node = self.node.write().unwrap();
+            if node.is_leader() {
+                resign(&mut node);
+            }
+            info!("node({}) is shutting down", node.id);
+        }
+        self.stop();
+        self.join();
+    }
+
+    /// leave removes the node from the cluster for good: a leader resigns
+    /// first, then all peers forget about the node, and the node stops.
+    pub fn leave(&self) {
+        {
+            let mut node = self.node.write().unwrap();
+            if node.is_leader() {
+                resign(&mut node);
+            }
+            info!("node({}) is leaving the cluster", node.id);
+            let (rank, term, wire) = (node.rank(), node.term, node.wire());
+            for (id, peer) in node.peers.iter_mut() {
+                if peer.is_dead() {
+                    continue;
+                }
+                if let Err(e) = send_message(peer, &Message::new(rank, Leave, term), &wire) {
+                    warn!("fail to send Leave to peer({}): {}", id, e);
+                }
+            }
+        }
+        self.stop();
+        self.join();
+    }
+
+    /// kill stops the node abruptly, as if its process crashed: all
+    /// connections are closed and peers are not notified.
+    pub fn kill(&self) {
+        info!("node({}) is killed", self.id());
+        self.stop();
+        self.join();
+    }
+
+    /// stop tells all handlers to exit and closes all connections.
+    fn stop(&self) {
+        self.stopper.stop();
+        let mut node = self.node.write().unwrap();
+        for peer in node.peers.values_mut() {
+            if let Some(conn) = peer.conn.take() {
+                let _ = conn.shutdown();
+            }
+        }
+        for conn in node.inbound.values() {
+            let _ = conn.shutdown();
+        }
+        // let the observer handler exit once pending events are delivered
+        node.events = None;
+        node.inbox = None;
+        // wake up the listener blocked on accepting connections
+        let _ = node
+            .transport
+            .connect(&node.advertise_address, node.timing.conn_timeout);
+    }
+}
+
+/// FencingToken identifies a leadership. Tokens of later leaderships compare
+/// greater, so a storage can reject writes carrying a token older than the
+/// newest one it has seen. Two nodes winning concurrently in the same term
+/// are told apart by their ranks, as the higher rank wins in the bully
+/// algorithm.
+#[derive(Display, Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+#[display(fmt = "{}.{}", term, leader)]
+pub struct FencingToken {
+    pub term: u64,
+    pub leader: Rank,
+}
+
+/// NodeStatus is a snapshot of the state of a node, see `NodeHandle::status`.
+#[derive(Serialize, Debug)]
+pub struct NodeStatus {
+    pub id: NodeId,
+    pub priority: u64,
+    pub leader: Option<NodeId>,
+    pub term: u64,
+    /// when the last heartbeat of the leader was received, in RFC 3339
+    pub last_leader_heartbeat: Option<String>,
+    pub standby: bool,
+    pub peers: Vec<PeerStatus>,
+}
+
+/// PeerStatus is the state of a peer as seen by the node.
+#[derive(Serialize, Debug)]
+pub struct PeerStatus {
+    pub id: NodeId,
+    pub address: String,
+    pub priority: u64,
+    pub liveness: Liveness,
+    /// whether the node holds a connection to the peer
+    pub connected: bool,
+    /// the protocol version negotiated on the connection to the peer
+    pub protocol_version: Option<u32>,
+}
+
+/// Stopper tells the background threads of a node to exit.
+#[derive(Default)]
+struct Stopper {
+    stopped: Mutex<bool>,
+    cvar: Condvar,
+}
+
+impl Stopper {
+    fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.cvar.notify_all();
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
+
+    /// sleep blocks for `dur` and returns true if the node is stopped in
+    /// the meantime.
+    fn sleep(&self, dur: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .cvar
+            .wait_timeout_while(stopped, dur, |stopped| !*stopped)
+            .unwrap();
+        *stopped
+    }
 }
 
 /// check_leader periodically checks if leader is malfunctioned
-fn check_leader(locked_node: Arc<RwLock<Node>>) -> ThreadSafeResult<()> {
-    loop {
-        thread::sleep(LEADER_CHECK_INTERVAL);
-        let mut node = locked_node.write().unwrap();
-        let current_time = SystemTime::now();
-        match node.last_leader_heartbeat {
-            None => continue,
-            Some(last_heartbeat) => {
-                if current_time.duration_since(last_heartbeat)? > LEADER_CHECK_INTERVAL {
-                    // the leader is melfunctioned, try to elect
-                    node.leader = None;
-                    node.last_leader_heartbeat = None;
-                    if let ElectionResult::Win = elect(&mut node)? {
-                        // won the election, announce self as the leader
-                        node.leader = Some(node.id);
-                        announce_victory(&mut node)?;
+fn check_leader(locked_node: Arc<RwLock<Node>>, stopper: Arc<Stopper>) -> ThreadSafeResult<()> {
+    let interval = locked_node.read().unwrap().timing.leader_check_interval;
+    while !stopper.sleep(interval) {
+        let elects = {
+            let mut node = locked_node.write().unwrap();
+            if stopper.is_stopped() {
+                break;
+            }
+            let current_time = SystemTime::now();
+            match node.last_leader_heartbeat {
+                // the leader is unknown, e.g., the bullier that replied
+                // `Alive` never announced its victory, start a new election
+                None if node.leader.is_none() => true,
+                // the node leads, a peer leading a newer term is challenged
+                // as soon as its Victory or heartbeat arrives, see
+                // `challenge`
+                None => false,
+                Some(last_heartbeat) => {
+                    let leader = node.leader.unwrap_or_default();
+                    if node.challenges(leader) {
+                        // the node is fitter to lead than the leader
+                        info!(
+                            "node({}) ranked {} outranks the leader({}) ranked {}",
+                            node.id,
+                            node.rank(),
+                            leader.id,
+                            leader
+                        );
+                        set_leader(&mut node, None);
+                        node.last_leader_heartbeat = None;
+                        true
+                    } else if current_time.duration_since(last_heartbeat)? > interval {
+                        // the leader is melfunctioned, try to elect
+                        node.metrics.missed_heartbeats.inc();
+                        if let Some(leader) = node.leader {
+                            if let Some(peer) = node.peers.get_mut(&leader.id) {
+                                peer.suspect();
+                            }
+                        }
+                        set_leader(&mut node, None);
+                        node.last_leader_heartbeat = None;
+                        // a node on standby lets its peers elect a new
+                        // leader, and only runs for it if none wins by the
+                        // next check
+                        !node.standby
+                    } else {
+                        false
                     }
                 }
             }
+        };
+        if elects {
+            run_election(&locked_node)?;
         }
     }
+    Ok(())
+}
+
+/// update_priority polls the `score` and advertises it as the priority of
+/// the node.
+fn update_priority(
+    locked_node: Arc<RwLock<Node>>,
+    score: Box<dyn Score>,
+    stopper: Arc<Stopper>,
+) -> ThreadSafeResult<()> {
+    let interval = locked_node.read().unwrap().timing.heartbeat_interval;
+    while !stopper.sleep(interval) {
+        // the score may take a while, do not lock the node meanwhile
+        let priority = score.score();
+        let mut node = locked_node.write().unwrap();
+        if node.priority != priority {
+            debug!(
+                "node({}) changes its priority from {} to {}",
+                node.id, node.priority, priority
+            );
+            node.priority = priority;
+        }
+    }
+    Ok(())
+}
+
+/// set_leader updates the leader known by the node and notifies observers
+/// about the change.
+fn set_leader(node: &mut Node, leader: Option<Rank>) {
+    let previous = node.leader.map(|leader| leader.id);
+    node.leader = leader;
+    let leader = leader.map(|leader| leader.id);
+    if previous == leader {
+        return;
+    }
+    node.metrics.leader_changes.inc();
+    node.metrics.set_leader(leader);
+    if previous == Some(node.id) {
+        node.notify(LeadershipEvent::LostLeadership);
+    }
+    if let Some(id) = leader {
+        node.notify(LeadershipEvent::LeaderChanged(id));
+        if id == node.id {
+            node.notify(LeadershipEvent::BecameLeader);
+        }
+    }
+}
+
+/// win_election makes the node the leader of a new term and announces its
+/// victory.
+fn win_election(node: &mut Node) -> ThreadSafeResult<()> {
+    node.term += 1;
+    node.standby = false;
+    info!("node({}) is the leader of term {}", node.id, node.term);
+    let rank = node.rank();
+    set_leader(node, Some(rank));
+    node.last_leader_heartbeat = None;
+    announce_victory(node)
+}
+
+/// resign tells all live peers that the leader steps down, and whether it
+/// stands by or is leaving.
+fn resign(node: &mut Node) {
+    info!("node({}) resigns from term {}", node.id, node.term);
+    let (rank, term, wire) = (node.rank(), node.term, node.wire());
+    let payload = match node.standby {
+        true => message::STANDBY.to_owned(),
+        false => String::new(),
+    };
+    for (id, peer) in node.peers.iter_mut() {
+        if peer.is_dead() {
+            continue;
+        }
+        let msg = Message::new(rank, Resign, term).with_payload(payload.clone());
+        if let Err(e) = send_message(peer, &msg, &wire) {
+            warn!("fail to send Resign to peer({}): {}", id, e);
+        }
+    }
+    set_leader(node, None);
 }
 
 /// ElectionResult is the result of an election.
 #[derive(Debug, Display)]
-enum ElectionResult {
+pub(crate) enum ElectionResult {
     #[display(fmt = "Win")]
     Win,
     #[display(fmt = "")]
     Fail,
 }
 
-/// announce_victory broadcasts `Victory` message to all peers with smaller id.
+/// join asks the seeds of the node for the members of the cluster one by
+/// one, and adds them as peers. The seed replies right away, then tells the
+/// other members about the node. A node that is one of the seeds starts a
+/// new cluster if no other seed is reachable.
+fn join(arc_rw_node: &Arc<RwLock<Node>>) -> ThreadSafeResult<()> {
+    let (rank, term, timing, transport, wire, metrics, seeds, member) = {
+        let node = arc_rw_node.read().unwrap();
+        let member = Member {
+            id: node.id,
+            address: node.advertise_address.clone(),
+        };
+        let transport = Arc::clone(&node.transport);
+        (
+            node.rank(),
+            node.term,
+            node.timing,
+            transport,
+            node.wire(),
+            node.metrics.clone(),
+            node.seeds.clone(),
+            member,
+        )
+    };
+    if seeds.is_empty() {
+        return Ok(());
+    }
+    for seed in seeds.iter().filter(|seed| **seed != member.address) {
+        let conn = connect(transport.as_ref(), seed, &metrics, timing).and_then(|mut conn| {
+            greet(conn.as_mut(), None, rank, &wire, timing.alive_timeout)
+                .map(|(_, seed_id)| (conn, seed_id))
+        });
+        let (mut conn, seed_id) = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("fail to connect to seed {}: {}", seed, e);
+                continue;
+            }
+        };
+        let msg = Message::new_request(rank, Join, term).with_payload(member.to_string());
+        let reply = send_message_through_conn(&msg, seed_id, conn.as_mut(), &wire)
+            .and_then(|_| receive_reply(conn.as_mut(), &msg, timing.alive_timeout, &wire));
+        // peers are connected again once they are known
+        let _ = conn.shutdown();
+        let reply = match reply {
+            Ok(Some(reply)) if verify_peer(conn.as_ref(), reply.get_sender_id()).is_err() => {
+                warn!(
+                    "seed {} is not node({}), as it replies",
+                    seed,
+                    reply.get_sender_id()
+                );
+                continue;
+            }
+            Ok(Some(reply)) if reply.get_message_type() == Members => reply,
+            Ok(Some(reply)) => {
+                warn!("seed {} replies {} to Join", seed, reply.get_message_type());
+                continue;
+            }
+            Ok(None) => {
+                warn!("seed {} does not reply to Join", seed);
+                continue;
+            }
+            Err(e) => {
+                warn!("fail to join through seed {}: {}", seed, e);
+                continue;
+            }
+        };
+        let members: Members = reply.get_payload().parse()?;
+        let mut node = arc_rw_node.write().unwrap();
+        info!("node({}) joins the cluster through seed {}", node.id, seed);
+        node.update_term(reply.get_term());
+        for (id, address) in members.nodes {
+            if id != node.id {
+                node.peers
+                    .entry(id)
+                    .or_insert_with(|| Peer::new(id, address));
+            }
+        }
+        if let Some(leader) = members.leader {
+            info!("peer({}) is the leader of term {}", leader.id, node.term);
+            set_leader(&mut node, Some(leader));
+            node.last_leader_heartbeat = Some(SystemTime::now());
+        }
+        return Ok(());
+    }
+    if seeds.contains(&member.address) {
+        info!(
+            "no other seed is reachable, node({}) starts a new cluster",
+            member.id
+        );
+        return Ok(());
+    }
+    Err(new_box_err!(
+        "fail to join the cluster through any seed".to_owned()
+    ))
+}
+
+/// rejoin connects to all reachable peers and sends them the `Rejoin`
+/// message, so that they replace their stale connections to the node. The
+/// peers reply `Alive` with their terms, from which the node catches up.
+/// Peers that are not reachable will connect to the node once they rejoin.
+/// The node is not locked while waiting for replies, as peers rejoining at
+/// the same time need to handle each other's `Rejoin`.
+fn rejoin(arc_rw_node: &Arc<RwLock<Node>>) {
+    let (rank, term, timing, transport, wire, metrics, addresses) = {
+        let node = arc_rw_node.read().unwrap();
+        let addresses: Vec<(NodeId, Address)> = node
+            .peers
+            .iter()
+            .map(|(id, peer)| (*id, peer.address.clone()))
+            .collect();
+        let transport = Arc::clone(&node.transport);
+        let metrics = node.metrics.clone();
+        (
+            node.rank(),
+            node.term,
+            node.timing,
+            transport,
+            node.wire(),
+            metrics,
+            addresses,
+        )
+    };
+    for (id, address) in addresses {
+        let conn = connect_peer(
+            transport.as_ref(),
+            id,
+            &address,
+            &wire,
+            rank,
+            &metrics,
+            timing,
+        );
+        let (mut conn, version) = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("fail to connect to peer({}): {}", id, e);
+                continue;
+            }
+        };
+        let msg = Message::new_request(rank, Rejoin, term);
+        let res = send_message_through_conn(&msg, id, conn.as_mut(), &wire)
+            .and_then(|_| wait_alive(conn.as_mut(), &msg, timing.alive_timeout, &wire));
+        let mut node = arc_rw_node.write().unwrap();
+        // the peer may have left meanwhile
+        let peer = match node.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => continue,
+        };
+        if let Some(stale) = peer.conn.replace(conn) {
+            let _ = stale.shutdown();
+        }
+        peer.connections += 1;
+        peer.version = Some(version);
+        match res {
+            Ok(ElectResponse::BuillerAlive(reply)) | Ok(ElectResponse::Abstained(reply)) => {
+                info!("peer({}) connected", id);
+                peer.set_liveness(Liveness::Alive);
+                peer.priority = reply.get_sender().priority;
+                node.update_term(reply.get_term());
+            }
+            Ok(ElectResponse::ResponseTimeOut) => {
+                warn!("peer({}) connected, but does not reply to Rejoin", id);
+                peer.set_liveness(Liveness::Suspected);
+            }
+            Err(e) => {
+                warn!("fail to send Rejoin to peer({}): {}", id, e);
+                peer.disconnect();
+            }
+        }
+    }
+}
+
+/// announce_victory broadcasts `Victory` message to all live peers. Peers
+/// with higher rank are told too, as their scores may have changed since
+/// they were heard from.
 fn announce_victory(node: &mut Node) -> ThreadSafeResult<()> {
-    for (_, peer) in node.peers.range_mut(..node.id) {
-        send_message(node.id, peer, MessageType::Victory)?
+    let (rank, term, wire) = (node.rank(), node.term, node.wire());
+    for (id, peer) in node.peers.iter_mut() {
+        if peer.is_dead() {
+            continue;
+        }
+        if let Err(e) = send_message(peer, &Message::new(rank, Victory, term), &wire) {
+            warn!("fail to send Victory to peer({}): {}", id, e);
+        }
     }
     Ok(())
 }
 
-/// elect tries to initiate an election.
-fn elect(node: &mut Node) -> ThreadSafeResult<ElectionResult> {
-    for (_, peer) in node.peers.range_mut(node.id + 1..) {
-        // send Elect message to peers with larger id
-        // TODO send elect to all peers concurrently?
-        match send_elect_message(node.id, peer)? {
-            ElectResponse::BuillerAlive => {
-                // the builler is alive, abort the election.
-                info!(
-                    "node({}) fail to elect: the bullier({}) is alive",
-                    node.id, peer.id
-                );
-                return Ok(ElectionResult::Fail);
+/// elect tries to initiate an election. `Elect` is sent to all live peers
+/// with higher rank at once, and the election is lost if any of them replies
+/// `Alive`, or won if none does within the alive timeout. Dead peers are
+/// skipped, and peers on standby reply `Abstain` instead.
+///
+/// The node is locked to send `Elect` and to apply the replies, but not
+/// while waiting for them, so that it keeps serving its peers meanwhile. It
+/// is returned locked along with the result, so that the winner announces
+/// its victory before anything else changes. An election started while
+/// another one is running fails right away, and lets the running one decide.
+fn elect(
+    locked_node: &RwLock<Node>,
+) -> ThreadSafeResult<(ElectionResult, RwLockWriteGuard<'_, Node>)> {
+    let (start, rx, waits) = {
+        let mut node = locked_node.write().unwrap();
+        if node.electing {
+            debug!("node({}) is running an election already", node.id);
+            return Ok((ElectionResult::Fail, node));
+        }
+        node.electing = true;
+        node.metrics.elections_started.inc();
+        let (rank, term, wire) = (node.rank(), node.term, node.wire());
+        let start = Instant::now();
+        let deadline = start + node.timing.alive_timeout;
+        let (tx, rx) = mpsc::channel();
+        let mut waits = Vec::new();
+        for (id, peer) in node.peers.iter_mut() {
+            if peer.is_dead() || peer.rank() < rank {
+                continue;
+            }
+            // send Elect message to peers with higher rank
+            let request = Message::new_request(rank, Elect, term);
+            let conn = send_message(peer, &request, &wire)
+                .and_then(|_| Ok(peer.conn.as_ref().unwrap().try_clone()?));
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // the builler is unreachable, treat it as dead
+                    warn!("fail to send Elect to peer({}): {}", id, e);
+                    peer.disconnect();
+                    continue;
+                }
+            };
+            let (id, connection, tx) = (*id, peer.connections, tx.clone());
+            let wire = wire.clone();
+            waits.push(thread::spawn(move || {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let res = wait_alive(conn.as_mut(), &request, timeout, &wire);
+                let _ = tx.send((id, connection, res));
+            }));
+        }
+        (start, rx, waits)
+    };
+
+    // every reply arrives by the deadline, as it bounds the waits
+    let replies: Vec<_> = rx.iter().collect();
+    for wait in waits {
+        let _ = wait.join();
+    }
+
+    let mut node = locked_node.write().unwrap();
+    node.electing = false;
+    let (node_id, mut latest_term) = (node.id, node.term);
+    let mut bullier = None;
+    for (id, connection, res) in replies {
+        // the peer reconnected meanwhile, the reply is lost with the
+        // connection it was waited on
+        let peer = match node.peers.get_mut(&id) {
+            Some(peer) if peer.connections == connection => peer,
+            _ => continue,
+        };
+        match res {
+            Ok(ElectResponse::BuillerAlive(reply)) => {
+                peer.set_liveness(Liveness::Alive);
+                peer.priority = reply.get_sender().priority;
+                bullier.get_or_insert(id);
+                latest_term = latest_term.max(reply.get_term());
+            }
+            Ok(ElectResponse::Abstained(reply)) => {
+                peer.set_liveness(Liveness::Alive);
+                peer.priority = reply.get_sender().priority;
+                debug!("peer({}) is on standby, and abstains", id);
+                node.update_term(reply.get_term());
+            }
+            Ok(ElectResponse::ResponseTimeOut) => peer.suspect(),
+            Err(e) => {
+                warn!("fail to receive Alive from peer({}): {}", id, e);
+                peer.disconnect();
             }
-            // send elect message to the next builler
-            ElectResponse::ResponseTimeOut => continue,
+        }
+    }
+    node.update_term(latest_term);
+    if let Some(id) = bullier {
+        // the builler is alive, abort the election.
+        info!(
+            "node({}) fail to elect: the bullier({}) is alive",
+            node_id, id
+        );
+        node.metrics.observe_election(false, start);
+        return Ok((ElectionResult::Fail, node));
+    }
+    // a peer that won an election meanwhile keeps leading, unless the node
+    // outranks it
+    if let Some(leader) = node.leader {
+        if leader.id != node_id && !node.challenges(leader) {
+            info!(
+                "node({}) fail to elect: peer({}) leads meanwhile",
+                node_id, leader.id
+            );
+            node.metrics.observe_election(false, start);
+            return Ok((ElectionResult::Fail, node));
         }
     }
     info!(
@@ -146,177 +1208,813 @@ fn elect(node: &mut Node) -> ThreadSafeResult<ElectionResult> {
         node.id
     );
     // if not receive Alive, announce self as the leader
-    Ok(ElectionResult::Win)
+    node.metrics.observe_election(true, start);
+    Ok((ElectionResult::Win, node))
+}
+
+/// run_election runs an election, see `elect`, and makes the node the
+/// leader if it wins. The node must not be locked by the caller.
+fn run_election(locked_node: &RwLock<Node>) -> ThreadSafeResult<bool> {
+    match elect(locked_node)? {
+        (ElectionResult::Win, mut node) => {
+            win_election(&mut node)?;
+            Ok(true)
+        }
+        (ElectionResult::Fail, _) => Ok(false),
+    }
 }
 
 /// heartbeat checks if the current node is the leader, if yes, it sends
-/// heartbeat message to peers with smaller id.
-fn heartbeat(locked_node: Arc<RwLock<Node>>) -> ThreadSafeResult<()> {
-    loop {
-        thread::sleep(HEARTBEAT_INTERVAL);
+/// heartbeat message to all live peers.
+fn heartbeat(locked_node: Arc<RwLock<Node>>, stopper: Arc<Stopper>) -> ThreadSafeResult<()> {
+    let interval = locked_node.read().unwrap().timing.heartbeat_interval;
+    while !stopper.sleep(interval) {
         let mut node = locked_node.write().unwrap();
-        let node_id = node.id;
-        if let Some(leader) = node.leader.as_ref() {
-            if *leader != node_id {
+        if stopper.is_stopped() {
+            break;
+        }
+        if !node.is_leader() {
+            continue;
+        }
+        // the current node is the leader, send heartbeat to live peers.
+        let (rank, term, wire) = (node.rank(), node.term, node.wire());
+        let mut sent = 0;
+        for (id, peer) in node.peers.iter_mut() {
+            if peer.is_dead() {
                 continue;
             }
-            // the current node is the leader, send heartbeat to peers with
-            // smaller id number.
-            for (_, peer) in node.peers.range_mut(..node_id) {
-                send_message(node_id, peer, MessageType::HeartBeat)?;
+            match send_message(peer, &Message::new(rank, HeartBeat, term), &wire) {
+                Ok(()) => sent += 1,
+                Err(e) => warn!("fail to send heartbeat to peer({}): {}", id, e),
             }
         }
+        node.metrics.heartbeats_sent.inc_by(sent);
     }
+    Ok(())
 }
 
-/// send_message sends message with given `message_type` from `sender_id`
-/// to `peer`.
-fn send_message(sender_id: u8, peer: &mut Peer, message_type: MessageType) -> ThreadSafeResult<()> {
-    let msg = Message::new(sender_id, message_type);
+/// reconnect periodically tries to connect to dead peers again, so that a
+/// peer lost to a transient error or a partition takes part in elections
+/// and heartbeats again once it is reachable. The node is not locked while
+/// connecting.
+fn reconnect(locked_node: Arc<RwLock<Node>>, stopper: Arc<Stopper>) -> ThreadSafeResult<()> {
+    let interval = locked_node.read().unwrap().timing.leader_check_interval;
+    while !stopper.sleep(interval) {
+        let (rank, timing, transport, wire, metrics, dead) = {
+            let node = locked_node.read().unwrap();
+            let dead: Vec<(NodeId, Address)> = node
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.is_dead())
+                .map(|(id, peer)| (*id, peer.address.clone()))
+                .collect();
+            let transport = Arc::clone(&node.transport);
+            let metrics = node.metrics.clone();
+            (
+                node.rank(),
+                node.timing,
+                transport,
+                node.wire(),
+                metrics,
+                dead,
+            )
+        };
+        // a peer that is still unreachable is tried again next time
+        let timing = Timing { retry: 0, ..timing };
+        for (id, address) in dead {
+            if stopper.is_stopped() {
+                break;
+            }
+            let conn = connect_peer(
+                transport.as_ref(),
+                id,
+                &address,
+                &wire,
+                rank,
+                &metrics,
+                timing,
+            );
+            let (conn, version) = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!("fail to reconnect to peer({}): {}", id, e);
+                    continue;
+                }
+            };
+            let mut node = locked_node.write().unwrap();
+            match node.peers.get_mut(&id) {
+                Some(peer) if peer.is_dead() => {
+                    info!("peer({}) is reachable again", id);
+                    peer.reconnect(Ok((conn, version)));
+                }
+                // the peer rejoined or left meanwhile
+                _ => {
+                    let _ = conn.shutdown();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// send_message sends `msg` to `peer` through the `wire`. A peer that
+/// fails to receive the message is considered dead until the node connects
+/// to it again.
+fn send_message(peer: &mut Peer, msg: &Message, wire: &Wire) -> ThreadSafeResult<()> {
     debug!("send message {}", msg);
     if let Some(conn) = peer.conn.as_mut() {
-        return send_message_through_conn(msg, conn);
+        let res = send_message_through_conn(msg, peer.id, conn.as_mut(), wire);
+        if res.is_err() {
+            peer.disconnect();
+        }
+        return res;
     }
+    peer.set_liveness(Liveness::Dead);
     Err(new_box_err!(
         "try to send message through nonexist connection".to_owned()
     ))
 }
 
-fn send_message_through_conn(msg: Message, conn: &mut TcpStream) -> ThreadSafeResult<()> {
-    Ok(conn.write_all(message::message_to_str(msg).as_bytes())?)
+/// send_message_through_conn sends `msg` to the node `to` on the `conn`
+/// through the `wire`.
+fn send_message_through_conn(
+    msg: &Message,
+    to: NodeId,
+    conn: &mut dyn Conn,
+    wire: &Wire,
+) -> ThreadSafeResult<()> {
+    wire.send(msg, Some(to), conn)
 }
 
-/// send_elect_message sends `Elect` message to the given peer and waits for
-/// reply from the peer. If a reply is received, the ElectResponse::BuillerAlive
-/// will be returned. If no replies received within a designated time period,
-/// the ElectResponse::ResponseTimeOut will be returned.
-fn send_elect_message(sender_id: u8, peer: &mut Peer) -> ThreadSafeResult<ElectResponse> {
-    send_message(sender_id, peer, MessageType::Elect)?;
-    if let Some(mut conn) = peer.conn.as_mut() {
-        conn.set_read_timeout(Some(ALIVE_TIMEOUT))?;
-        let mut buf_rd = BufReader::new(&mut conn);
-        let mut response = String::new();
-        match buf_rd.read_line(&mut response) {
-            Err(e) if e.kind() == ErrorKind::TimedOut => {
-                conn.set_read_timeout(None)?;
-                return Ok(ElectResponse::ResponseTimeOut);
-            }
-            Err(e) => {
-                conn.set_read_timeout(None)?;
-                return Err(Box::new(e));
+/// wait_alive waits for the `Alive` reply to the `request` on the `conn`
+/// for `timeout`.
+fn wait_alive(
+    conn: &mut dyn Conn,
+    request: &Message,
+    timeout: Duration,
+    wire: &Wire,
+) -> ThreadSafeResult<ElectResponse> {
+    match receive_reply(conn, request, timeout, wire)? {
+        None => Ok(ElectResponse::ResponseTimeOut),
+        Some(rep_msg) => match rep_msg.get_message_type() {
+            MessageType::Alive => {
+                // receive acknowledge
+                Ok(ElectResponse::BuillerAlive(rep_msg))
             }
-            Ok(num_bytes) => {
-                if num_bytes == 0 {
-                    return Err(new_box_err!(
-                        "read zero bytes from the connection".to_owned()
-                    ));
-                }
-                let rep_msg = message::str_to_message(&response)?;
-                match rep_msg.get_message_type() {
-                    MessageType::Alive => {
-                        // receive acknowledge
-                        return Ok(ElectResponse::BuillerAlive);
-                    }
-                    wrong_type @ _ => {
-                        return Err(new_box_err!(format!(
-                            "incorrect message type({})",
-                            wrong_type
-                        )));
-                    }
-                }
+            MessageType::Abstain => Ok(ElectResponse::Abstained(rep_msg)),
+            wrong_type => Err(new_box_err!(format!(
+                "incorrect message type({})",
+                wrong_type
+            ))),
+        },
+    }
+}
+
+/// receive_reply waits for the reply to the `request` on the `conn` for
+/// `timeout`, and returns `None` if there is none in time. Other messages
+/// received meanwhile are dispatched, see `Wire::dispatch`. A message that
+/// the `wire` fails to open is an error, and so is a frame cut off by the
+/// timeout, as the rest of it would be taken for the next frame, so the
+/// connection should be given up.
+fn receive_reply(
+    conn: &mut dyn Conn,
+    request: &Message,
+    timeout: Duration,
+    wire: &Wire,
+) -> ThreadSafeResult<Option<Message>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // a zero timeout is rejected, and means that time is up anyway
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Ok(None);
+        }
+        conn.set_read_timeout(Some(timeout))?;
+        // wait for the first byte of the next frame, then read the rest
+        let mut first = [0; 1];
+        let frame = conn.read(&mut first).and_then(|n| match n {
+            0 => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the connection is closed",
+            )),
+            _ => Ok(()),
+        });
+        if let Err(e) = frame {
+            conn.set_read_timeout(None)?;
+            return match is_timeout(&e) {
+                true => Ok(None),
+                false => Err(Box::new(e)),
+            };
+        }
+        let frame = message::read_frame((&first[..]).chain(&mut *conn));
+        conn.set_read_timeout(None)?;
+        let msg = match frame {
+            Err(e) if is_timeout(&e) => {
+                return Err(new_box_err!(
+                    "the frame is cut off by the timeout".to_owned()
+                ))
             }
+            Err(e) => return Err(Box::new(e)),
+            Ok(frame) => wire.open(&frame)?,
+        };
+        if msg.replies_to(request) {
+            return Ok(Some(msg));
         }
+        wire.dispatch(msg, conn)?;
     }
-    Err(new_box_err!(
-        "try to send message through the nonexist connection".to_owned()
-    ))
 }
 
-/// receive_message listens on `address` and passes received messages to
-/// the channel
-fn listen_and_serve(arc_rw_node: Arc<RwLock<Node>>) -> ThreadSafeResult<()> {
-    let listener: TcpListener;
-    {
-        let adr = &arc_rw_node.read().unwrap().advertise_address;
-        listener = TcpListener::bind(adr)?;
-    }
+/// is_timeout tells if a read fails as it times out, whose kind depends on
+/// the platform.
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock
+}
+
+/// listen_and_serve accepts connections from peers and handles the
+/// messages sent through them.
+fn listen_and_serve(
+    arc_rw_node: Arc<RwLock<Node>>,
+    listener: Box<dyn Listener>,
+    stopper: Arc<Stopper>,
+) -> ThreadSafeResult<()> {
+    // peers are welcomed without locking the node, which may be waiting for
+    // them meanwhile
+    let (id, wire) = {
+        let node = arc_rw_node.read().unwrap();
+        (node.id, node.wire())
+    };
     loop {
         let (mut conn, addr) = listener.accept()?;
-        let node_clone = arc_rw_node.clone();
+        if stopper.is_stopped() {
+            return Ok(());
+        }
         info!("accept connection from {}", addr);
-        thread::spawn(move || handle_message(node_clone, &mut conn));
+        let node_clone = arc_rw_node.clone();
+        let hdl_stopper = Arc::clone(&stopper);
+        let wire = wire.clone();
+        thread::spawn(move || {
+            let res = conn
+                .handshake()
+                .map_err(Into::into)
+                .and_then(|_| welcome(id, conn.as_mut(), &wire))
+                .and_then(|_| {
+                    // keep a clone of the connection, so that it can be closed
+                    // when the node is killed
+                    let clone = conn.try_clone()?;
+                    node_clone
+                        .write()
+                        .unwrap()
+                        .inbound
+                        .insert(addr.clone(), clone);
+                    handle_message(Arc::clone(&node_clone), conn.as_mut(), &wire, hdl_stopper)
+                });
+            if let Err(e) = res {
+                warn!("connection from {} closed: {}", addr, e);
+            }
+            node_clone.write().unwrap().inbound.remove(&addr);
+        });
     }
 }
 
 /// handle_message keeps reading messages from the conn and handling
-/// them accordingly.
-fn handle_message(arc_rw_node: Arc<RwLock<Node>>, conn: &mut TcpStream) -> ThreadSafeResult<()> {
-    let mut buf_rd = BufReader::new(conn);
+/// them accordingly. Messages that fail authentication are dropped.
+fn handle_message(
+    arc_rw_node: Arc<RwLock<Node>>,
+    conn: &mut dyn Conn,
+    wire: &Wire,
+    stopper: Arc<Stopper>,
+) -> ThreadSafeResult<()> {
+    let peer_id = conn.peer_id();
     loop {
-        let msg = message::receive_message(&mut buf_rd)?;
-        match msg.get_message_type() {
-            MessageType::Elect => {
-                // reply alive
+        let frame = message::read_frame(&mut *conn)?;
+        if stopper.is_stopped() {
+            return Ok(());
+        }
+        let msg = match wire.open(&frame) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("drop message of {} bytes: {}", frame.len(), e);
+                let node = arc_rw_node.read().unwrap();
+                node.metrics.rejected_messages.inc();
+                continue;
+            }
+        };
+        if let Some(peer_id) = peer_id {
+            if msg.get_sender_id() != peer_id {
+                return Err(new_box_err!(format!(
+                    "refuse message from node({}) over the connection of node({})",
+                    msg.get_sender_id(),
+                    peer_id
+                )));
+            }
+        }
+        dispatch(&arc_rw_node, msg, conn, wire)?;
+    }
+}
+
+/// dispatch handles the `msg` received on the `conn`, and replies through it
+/// if needed.
+fn dispatch(
+    arc_rw_node: &Arc<RwLock<Node>>,
+    msg: Message,
+    conn: &mut dyn Conn,
+    wire: &Wire,
+) -> ThreadSafeResult<()> {
+    heard_from(&mut arc_rw_node.write().unwrap(), msg.get_sender());
+    let term = msg.get_term();
+    match msg.get_message_type() {
+        MessageType::Elect => {
+            {
+                // reply alive, a candidate from an older term catches up
+                // with the term carried by the reply
                 let mut node = arc_rw_node.write().unwrap();
-                send_message_through_conn(Message::new(node.id, Alive), buf_rd.get_mut())?;
-                // continue the election
-                if let ElectionResult::Win = elect(&mut node)? {
-                    // won the election, announce self as the leader
-                    node.leader = Some(node.id);
-                    announce_victory(&mut node)?;
+                node.update_term(term);
+                if node.standby {
+                    // let the candidate lead
+                    let reply = msg.reply(node.rank(), Abstain, node.term);
+                    send_message_through_conn(&reply, msg.get_sender_id(), conn, wire)?;
+                    return Ok(());
                 }
-                // else do nothing
+                let reply = msg.reply(node.rank(), Alive, node.term);
+                send_message_through_conn(&reply, msg.get_sender_id(), conn, wire)?;
             }
+            // continue the election
+            run_election(arc_rw_node)?;
+        }
 
-            MessageType::Victory => {
-                let mut node = arc_rw_node.write().unwrap();
-                let sender_id = msg.get_sender_id();
-                if sender_id < node.id {
-                    return Err(
-                        new_box_err!(format!(
-                                "Victory message sent from peer with id({}) smaller than the current node({})", 
-                                sender_id, node.id)));
+        MessageType::Victory => {
+            let mut node = arc_rw_node.write().unwrap();
+            let (sender_id, sender) = (msg.get_sender_id(), msg.get_sender());
+            if node.challenges(sender) {
+                if term > node.term {
+                    return challenge(arc_rw_node, node, sender, term);
                 }
-                info!("peer({}) is the leader", sender_id);
-                node.leader = Some(sender_id);
-                node.last_leader_heartbeat = Some(SystemTime::now());
+                // the node takes over once it checks the leader
+                warn!(
+                    "reject Victory from peer({}) ranked {}, lower than the current node({}) ranked {}",
+                    sender_id,
+                    sender,
+                    node.id,
+                    node.rank()
+                );
+                return Ok(());
+            }
+            if !node.accepts_leader(sender, term) {
+                warn!(
+                    "reject stale Victory from peer({}) in term {}, the current term is {}",
+                    sender_id, term, node.term
+                );
+                return Ok(());
             }
+            info!("peer({}) is the leader of term {}", sender_id, term);
+            node.term = term;
+            set_leader(&mut node, Some(sender));
+            node.last_leader_heartbeat = Some(SystemTime::now());
+        }
 
-            MessageType::HeartBeat => {
+        MessageType::HeartBeat => {
+            let mut node = arc_rw_node.write().unwrap();
+            node.metrics.heartbeats_received.inc();
+            let (sender_id, sender) = (msg.get_sender_id(), msg.get_sender());
+            if node.challenges(sender) && term > node.term {
+                return challenge(arc_rw_node, node, sender, term);
+            }
+            // ignore the heartbeat unless the sender may lead in its term,
+            // e.g., a deposed leader still sending heartbeats
+            if node.challenges(sender) || !node.accepts_leader(sender, term) {
+                debug!(
+                    "ignore heartbeat from peer({}) in term {}, the leader is {:?} in term {}",
+                    sender_id, term, node.leader, node.term
+                );
+                return Ok(());
+            }
+            trace!("receive heartbeat from leader({})", sender_id);
+            if node.leader.map(|leader| leader.id) != Some(sender_id) {
+                info!("peer({}) is the leader of term {}", sender_id, term);
+            }
+            node.term = term;
+            set_leader(&mut node, Some(sender));
+            node.last_leader_heartbeat = Some(SystemTime::now())
+        }
+        MessageType::Rejoin => {
+            let sender_id = msg.get_sender_id();
+            let (transport, rank, metrics, timing, address) = {
                 let mut node = arc_rw_node.write().unwrap();
-                let sender_id = msg.get_sender_id();
-                match node.leader {
-                    Some(id) if id == sender_id => {
-                        trace!("receive heartbeat from leader({})", id);
-                        node.last_leader_heartbeat = Some(SystemTime::now())
+                // a restarted peer starts from term 0, so the term of
+                // Rejoin is never stale, reply the current term to let
+                // it catch up
+                node.update_term(term);
+                let reply = msg.reply(node.rank(), Alive, node.term);
+                send_message_through_conn(&reply, msg.get_sender_id(), conn, wire)?;
+                let address = match node.peers.get(&sender_id) {
+                    Some(peer) => peer.address.clone(),
+                    None => {
+                        // a joining node may rejoin before its Join is
+                        // forwarded by the seed, connect to it once it is
+                        debug!("receive Rejoin from unknown peer({})", sender_id);
+                        return Ok(());
+                    }
+                };
+                let transport = Arc::clone(&node.transport);
+                let metrics = node.metrics.clone();
+                (transport, node.rank(), metrics, node.timing, address)
+            };
+            info!("peer({}) rejoins the cluster", sender_id);
+            // replace the stale connection with a new one, the node is
+            // not locked while the peer may be greeting it too
+            let new_conn = connect_peer(
+                transport.as_ref(),
+                sender_id,
+                &address,
+                wire,
+                rank,
+                &metrics,
+                timing,
+            );
+            let mut node = arc_rw_node.write().unwrap();
+            if let Some(peer) = node.peers.get_mut(&sender_id) {
+                peer.reconnect(new_conn);
+            }
+        }
+
+        MessageType::Join => {
+            let member: Member = msg.get_payload().parse()?;
+            let (transport, rank, metrics, timing) = {
+                let node = arc_rw_node.read().unwrap();
+                if member.id == node.id {
+                    return Err(new_box_err!(format!(
+                        "node({}) at {} joins with the id of the current node",
+                        member.id, member.address
+                    )));
+                }
+                let transport = Arc::clone(&node.transport);
+                let metrics = node.metrics.clone();
+                (transport, node.rank(), metrics, node.timing)
+            };
+            info!(
+                "node({}) joins the cluster at {}",
+                member.id, member.address
+            );
+            let new_conn = connect_peer(
+                transport.as_ref(),
+                member.id,
+                &member.address,
+                wire,
+                rank,
+                &metrics,
+                timing,
+            );
+            let mut node = arc_rw_node.write().unwrap();
+            node.update_term(term);
+            let (id, address) = (member.id, member.address.clone());
+            let peer = node
+                .peers
+                .entry(id)
+                .or_insert_with(|| Peer::new(id, address.clone()));
+            peer.address = address;
+            peer.reconnect(new_conn);
+            // the node is the seed asked by the joining node, reply the
+            // members, then tell the other members about the new one
+            if msg.get_sender_id() == member.id {
+                let (rank, term) = (node.rank(), node.term);
+                let reply = msg
+                    .reply(rank, Members, term)
+                    .with_payload(node.members().to_string());
+                send_message_through_conn(&reply, msg.get_sender_id(), conn, wire)?;
+                for (id, peer) in node.peers.iter_mut() {
+                    if *id == member.id || peer.is_dead() {
+                        continue;
+                    }
+                    let join = Message::new(rank, Join, term).with_payload(member.to_string());
+                    if let Err(e) = send_message(peer, &join, wire) {
+                        warn!("fail to send Join to peer({}): {}", id, e);
                     }
-                    // ignore the heartbeat if the sender is not the leader
-                    Some(id) => debug!(
-                        "receive heartbeat from peer({}), which is not the leader({})",
-                        sender_id, id
-                    ),
-                    // ignore the heartbeat if the leader is not set
-                    None => debug!(
-                        "receive heartbeat from peer({}), but the leader is not set",
-                        sender_id
-                    ),
                 }
             }
-            wrong_type @ _ => {
-                return Err(new_box_err!(format!(
-                    "unsupported message type {}",
-                    wrong_type
-                )));
+        }
+
+        MessageType::Leave => {
+            let mut node = arc_rw_node.write().unwrap();
+            let sender_id = msg.get_sender_id();
+            if let Some(mut peer) = node.peers.remove(&sender_id) {
+                info!("peer({}) leaves the cluster", sender_id);
+                peer.disconnect();
+            }
+            // a leader resigns before leaving, this is in case the
+            // Resign is lost
+            if node.leader.map(|leader| leader.id) == Some(sender_id) && !node.standby {
+                set_leader(&mut node, None);
+                node.last_leader_heartbeat = None;
+                drop(node);
+                run_election(arc_rw_node)?;
             }
         }
+
+        MessageType::Resign => {
+            let mut node = arc_rw_node.write().unwrap();
+            let sender_id = msg.get_sender_id();
+            // the peer is leaving unless it stands by, skip it until it
+            // rejoins
+            if msg.get_payload() != message::STANDBY {
+                if let Some(peer) = node.peers.get_mut(&sender_id) {
+                    peer.disconnect();
+                }
+            }
+            if node.leader.map(|leader| leader.id) != Some(sender_id) || term < node.term {
+                debug!(
+                    "ignore Resign from peer({}) in term {}, the leader is {:?} in term {}",
+                    sender_id, term, node.leader, node.term
+                );
+                return Ok(());
+            }
+            info!("leader({}) resigns, start an election", sender_id);
+            set_leader(&mut node, None);
+            node.last_leader_heartbeat = None;
+            if node.standby {
+                return Ok(());
+            }
+            drop(node);
+            run_election(arc_rw_node)?;
+        }
+
+        MessageType::App => {
+            let app: AppMessage = match msg.get_payload().parse() {
+                Ok(app) => app,
+                Err(e) => {
+                    warn!(
+                        "drop application message from node({}): {}",
+                        msg.get_sender_id(),
+                        e
+                    );
+                    return Ok(());
+                }
+            };
+            let handlers = Arc::clone(&arc_rw_node.read().unwrap().app_handlers);
+            handlers.receive(msg.get_sender_id(), &app);
+        }
+
+        MessageType::Forward => {
+            let (rank, term, handlers) = {
+                let node = arc_rw_node.read().unwrap();
+                let handlers = if node.is_leader() {
+                    Some(Arc::clone(&node.request_handlers))
+                } else {
+                    None
+                };
+                (node.rank(), node.term, handlers)
+            };
+            // the handler may take a while, do not lock the node meanwhile
+            let ack = match (handlers, msg.get_payload().parse::<AppMessage>()) {
+                (None, _) => ForwardAck::NotLeader,
+                (Some(_), Err(e)) => ForwardAck::Failed(e.to_string()),
+                (Some(handlers), Ok(request)) => handlers.handle(msg.get_sender_id(), &request),
+            };
+            let reply = msg
+                .reply(rank, MessageType::ForwardAck, term)
+                .with_payload(ack.to_string());
+            send_message_through_conn(&reply, msg.get_sender_id(), conn, wire)?;
+        }
+
+        // the request timed out, or was sent on another connection
+        MessageType::Alive
+        | MessageType::Abstain
+        | MessageType::Members
+        | MessageType::ForwardAck => {
+            debug!(
+                "ignore {} from peer({}) to request {}",
+                msg.get_message_type(),
+                msg.get_sender_id(),
+                msg.get_request_id()
+            );
+        }
+
+        wrong_type => {
+            return Err(new_box_err!(format!(
+                "unsupported message type {}",
+                wrong_type
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// challenge starts an election to take over from the lower ranked `sender`
+/// leading the newer `term`, e.g., a peer elected while the node was
+/// partitioned from it. The node catches up with the term first, so that
+/// its victory is not rejected as stale, and two leaders never remain. The
+/// `node` locked by the caller is unlocked to run the election.
+fn challenge(
+    locked_node: &RwLock<Node>,
+    mut node: RwLockWriteGuard<'_, Node>,
+    sender: Rank,
+    term: u64,
+) -> ThreadSafeResult<()> {
+    info!(
+        "node({}) ranked {} challenges peer({}) ranked {} leading term {}",
+        node.id,
+        node.rank(),
+        sender.id,
+        sender,
+        term
+    );
+    node.update_term(term);
+    set_leader(&mut node, None);
+    node.last_leader_heartbeat = None;
+    drop(node);
+    run_election(locked_node)?;
+    Ok(())
+}
+
+/// dispatch_received handles the messages handed over by `Wire::dispatch`
+/// until the node stops.
+fn dispatch_received(
+    arc_rw_node: Arc<RwLock<Node>>,
+    received: Receiver<Received>,
+    stopper: Arc<Stopper>,
+) {
+    for (msg, mut conn) in received {
+        if stopper.is_stopped() {
+            return;
+        }
+        if let Err(e) = verify_peer(conn.as_ref(), msg.get_sender_id()) {
+            warn!("drop message {}: {}", msg, e);
+            continue;
+        }
+        let wire = arc_rw_node.read().unwrap().wire();
+        if let Err(e) = dispatch(&arc_rw_node, msg, conn.as_mut(), &wire) {
+            warn!("fail to handle a message received while waiting: {}", e);
+        }
+    }
+}
+
+/// heard_from marks the `sender` as alive after receiving a message from it,
+/// and updates its priority. A dead peer without a connection stays dead
+/// until the node connects to it again, as there is no way to reply to it.
+fn heard_from(node: &mut Node, sender: Rank) {
+    if let Some(peer) = node.peers.get_mut(&sender.id) {
+        peer.priority = sender.priority;
+        if peer.conn.is_some() {
+            peer.set_liveness(Liveness::Alive);
+        }
     }
 }
 
-/// connect connects to the `address` and return a TcpStream on success.
-fn connect(address: SocketAddrV4) -> ThreadSafeResult<TcpStream> {
-    let mut count = RETRY;
+/// greet sends `Hello` as the node of the `rank` on a new `conn`, and
+/// returns the protocol version negotiated by the peer with the id of the
+/// peer. It fails if the peer does not accept the connection within
+/// `timeout`, or is not the node `id` if given.
+fn greet(
+    conn: &mut dyn Conn,
+    id: Option<NodeId>,
+    rank: Rank,
+    wire: &Wire,
+    timeout: Duration,
+) -> ThreadSafeResult<(u32, NodeId)> {
+    let hello =
+        Message::new_request(rank, MessageType::Hello, 0).with_payload(wire.hello.to_string());
+    wire.send(&hello, id, conn)?;
+    let reply = receive_reply(conn, &hello, timeout, wire)?
+        .ok_or(new_box_err!("the peer does not reply to Hello".to_owned()))?;
+    let version = wire.hello.check_ack(&reply, id)?;
+    Ok((version, reply.get_sender_id()))
+}
+
+/// connect_peer connects to the node `id` at the `address`, greeted as the
+/// node of the `rank`, and returns the connection with the protocol version
+/// negotiated.
+fn connect_peer(
+    transport: &dyn Transport,
+    id: NodeId,
+    address: &Address,
+    wire: &Wire,
+    rank: Rank,
+    metrics: &Metrics,
+    timing: Timing,
+) -> ThreadSafeResult<(Box<dyn Conn>, u32)> {
+    let mut conn = connect(transport, address, metrics, timing)?;
+    verify_peer(conn.as_ref(), id)?;
+    let (version, _) = greet(conn.as_mut(), Some(id), rank, wire, timing.alive_timeout)?;
+    Ok((conn, version))
+}
+
+/// welcome waits for the `Hello` a peer sends first on the `conn` it
+/// connected to the node `id`, and accepts the connection with the protocol
+/// version negotiated, or refuses it with the reason. `HelloAck` carries no
+/// priority, which would need the node locked.
+fn welcome(id: NodeId, conn: &mut dyn Conn, wire: &Wire) -> ThreadSafeResult<()> {
+    let hello = wire.open_hello(&message::read_frame(&mut *conn)?)?;
+    let sender_id = hello.get_sender_id();
+    let version = verify_peer(conn, sender_id).and_then(|_| wire.hello.accept(id, &hello));
+    let ack = match version.as_ref() {
+        Ok(version) => HelloAck::Accepted(*version),
+        Err(e) => HelloAck::Refused(e.to_string()),
+    };
+    let reply = hello
+        .reply(Rank::new(0, id), MessageType::HelloAck, 0)
+        .with_payload(ack.to_string());
+    send_message_through_conn(&reply, sender_id, conn, wire)?;
+    match version {
+        Ok(version) => {
+            debug!(
+                "node({}) connects with protocol version {}",
+                sender_id, version
+            );
+            Ok(())
+        }
+        Err(e) => Err(new_box_err!(format!(
+            "refuse connection from node({}): {}",
+            sender_id, e
+        ))),
+    }
+}
+
+/// verify_peer fails if the `conn` is not to the node `id`, as far as the
+/// transport can tell, e.g., from the certificate of the peer.
+fn verify_peer(conn: &dyn Conn, id: NodeId) -> ThreadSafeResult<()> {
+    match conn.peer_id() {
+        Some(peer_id) if peer_id != id => Err(new_box_err!(format!(
+            "expect node({}), but the peer is node({})",
+            id, peer_id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Wire writes messages in frames, encoded by the codec and sealed by the
+/// auth of the node, and reads them back. Connections start with the hello
+/// of the node.
+#[derive(Debug, Clone)]
+struct Wire {
+    /// the id of the node, which messages read should be sealed for
+    id: NodeId,
+    codec: Arc<dyn Codec>,
+    auth: Arc<Auth>,
+    hello: Arc<Hello>,
+    inbox: Option<Sender<Received>>,
+}
+
+/// Received is a message with the connection it is received on, so that
+/// it can be replied.
+type Received = (Message, Box<dyn Conn>);
+
+impl Wire {
+    /// send writes the `msg` to the node `to` on the `conn`, or to any node
+    /// if its id is unknown.
+    fn send(&self, msg: &Message, to: Option<NodeId>, conn: &mut dyn Conn) -> ThreadSafeResult<()> {
+        let frame = self.auth.seal(self.codec.encode(msg), to);
+        Ok(message::write_frame(conn, &frame)?)
+    }
+
+    /// open decodes the message in the `frame`, and fails if the frame is
+    /// not authenticated, or not sent to the node.
+    fn open(&self, frame: &[u8]) -> ThreadSafeResult<Message> {
+        self.auth
+            .open(frame, Some(self.id), |body| self.codec.decode(body))
+    }
+
+    /// open_hello is like `open`, but also accepts a `Hello` sent to any
+    /// node, i.e., by a node joining through the node as a seed.
+    fn open_hello(&self, frame: &[u8]) -> ThreadSafeResult<Message> {
+        let msg = self
+            .open(frame)
+            .or_else(|_| self.auth.open(frame, None, |body| self.codec.decode(body)))?;
+        match msg.get_message_type() {
+            MessageType::Hello => Ok(msg),
+            wrong_type => Err(new_box_err!(format!("expect Hello, got {}", wrong_type))),
+        }
+    }
+
+    /// dispatch hands the `msg` received on the `conn` while waiting for a
+    /// reply over to the dispatcher of the node, which handles it once the
+    /// node is unlocked, as the waiting thread may hold the lock.
+    fn dispatch(&self, msg: Message, conn: &dyn Conn) -> ThreadSafeResult<()> {
+        match self.inbox.as_ref() {
+            Some(inbox) => {
+                // the dispatcher is gone once the node stops
+                let _ = inbox.send((msg, conn.try_clone()?));
+            }
+            None => debug!("drop message {} while waiting for a reply", msg),
+        }
+        Ok(())
+    }
+}
+
+/// connect connects to the `address` through the `transport` and return the
+/// connection on success. Host names are resolved again on every attempt,
+/// and every failed attempt is counted by the `metrics`.
+fn connect(
+    transport: &dyn Transport,
+    address: &Address,
+    metrics: &Metrics,
+    timing: Timing,
+) -> ThreadSafeResult<Box<dyn Conn>> {
+    let mut count = timing.retry;
     loop {
-        match TcpStream::connect_timeout(&(address.into()), INIT_CONN_TIMEOUT) {
+        let res = transport.connect(address, timing.conn_timeout);
+        if res.is_err() {
+            metrics.connect_failures.inc();
+        }
+        match res {
             Err(e) if io::ErrorKind::TimedOut == e.kind() && count > 0 => {
                 count -= 1;
                 continue;
@@ -333,53 +2031,631 @@ fn connect(address: SocketAddrV4) -> ThreadSafeResult<TcpStream> {
 
 #[derive(Debug)]
 pub struct Node {
-    id: u8,
-    advertise_address: SocketAddrV4,
-    peers: BTreeMap<u8, Peer>,
-    leader: Option<u8>,
+    id: NodeId,
+    priority: u64,
+    advertise_address: Address,
+    peers: BTreeMap<NodeId, Peer>,
+    leader: Option<Rank>,
+    /// the latest election term known by the node
+    term: u64,
     last_leader_heartbeat: Option<SystemTime>,
+    inbound: HashMap<String, Box<dyn Conn>>,
+    observers: Observers,
+    events: Option<Sender<LeadershipEvent>>,
+    /// handle application messages, shared with the threads reading them
+    app_handlers: Arc<AppHandlers>,
+    /// handle requests forwarded to the node while it leads
+    request_handlers: Arc<RequestHandlers>,
+    /// messages received while waiting for replies, see `Wire::dispatch`
+    inbox: Option<Sender<Received>>,
+    transport: Arc<dyn Transport>,
+    /// encodes messages into frames, and decodes them
+    codec: Arc<dyn Codec>,
+    /// seals messages to send, and opens messages received
+    auth: Arc<Auth>,
+    /// greets peers on new connections
+    hello: Arc<Hello>,
+    timing: Timing,
+    /// an election is waiting for replies, see `elect`
+    electing: bool,
+    /// polled for the priority of the node, if any
+    score: Option<Box<dyn Score>>,
+    /// nodes asked for the members of the cluster when the node starts
+    seeds: Vec<Address>,
+    /// how much the node should outrank the leader by to take over from it
+    hysteresis: u64,
+    /// the node resigned, and lets its peers lead until it wins an election
+    standby: bool,
+    metrics: Metrics,
 }
 
 #[derive(Debug)]
 pub struct Peer {
-    id: u8,
-    address: SocketAddrV4,
-    conn: Option<TcpStream>,
+    id: NodeId,
+    /// the priority last heard from the peer
+    priority: u64,
+    address: Address,
+    conn: Option<Box<dyn Conn>>,
+    /// counts the connections made to the peer, so that a wait on a stale
+    /// connection is told apart, see `elect`
+    connections: u64,
+    liveness: Liveness,
+    /// the protocol version negotiated on the connection, if any
+    version: Option<u32>,
+}
+
+/// Liveness is the state of a peer as seen by the current node.
+#[derive(Debug, Display, Serialize, PartialEq, Copy, Clone)]
+pub enum Liveness {
+    #[display(fmt = "Alive")]
+    Alive,
+    /// the peer missed a heartbeat or an `Alive` reply
+    #[display(fmt = "Suspected")]
+    Suspected,
+    /// the peer is skipped by elections and broadcasts until the node
+    /// connects to it again
+    #[display(fmt = "Dead")]
+    Dead,
+}
+
+impl Peer {
+    /// new creates the peer at the `address`, which is unreachable until
+    /// connected.
+    fn new(id: NodeId, address: Address) -> Peer {
+        Peer {
+            id,
+            priority: 0,
+            address,
+            conn: None,
+            connections: 0,
+            liveness: Liveness::Dead,
+            version: None,
+        }
+    }
+
+    fn set_liveness(&mut self, liveness: Liveness) {
+        if self.liveness != liveness {
+            info!("peer({}) is {}, was {}", self.id, liveness, self.liveness);
+            self.liveness = liveness;
+        }
+    }
+
+    /// suspect is called when the peer fails to respond in time. A peer
+    /// that is suspected already is considered dead.
+    fn suspect(&mut self) {
+        match self.liveness {
+            Liveness::Alive => self.set_liveness(Liveness::Suspected),
+            _ => self.set_liveness(Liveness::Dead),
+        }
+    }
+
+    /// reconnect replaces the connection to the peer with the new `conn` and
+    /// its protocol version, and marks the peer as alive, or as dead if the
+    /// node fails to connect.
+    fn reconnect(&mut self, conn: ThreadSafeResult<(Box<dyn Conn>, u32)>) {
+        match conn {
+            Ok((conn, version)) => {
+                if let Some(stale) = self.conn.replace(conn) {
+                    let _ = stale.shutdown();
+                }
+                self.connections += 1;
+                self.version = Some(version);
+                self.set_liveness(Liveness::Alive);
+            }
+            Err(e) => {
+                warn!("fail to reconnect to peer({}): {}", self.id, e);
+                self.disconnect();
+            }
+        }
+    }
+
+    /// disconnect closes the connection to the peer and marks it as dead.
+    fn disconnect(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let _ = conn.shutdown();
+        }
+        self.version = None;
+        self.set_liveness(Liveness::Dead);
+    }
+
+    fn is_dead(&self) -> bool {
+        self.liveness == Liveness::Dead
+    }
+
+    /// speaks tells if the protocol version negotiated with the peer is at
+    /// least `version`.
+    fn speaks(&self, version: u32) -> bool {
+        self.version.is_some_and(|v| v >= version)
+    }
+
+    fn rank(&self) -> Rank {
+        Rank::new(self.priority, self.id)
+    }
 }
 
 impl Node {
-    pub fn new(id: u8, peer_str: &str, advertise_address: &str) -> ThreadSafeResult<Node> {
-        Ok(Node {
+    pub fn new(id: NodeId, peer_str: &str, advertise_address: &str) -> ThreadSafeResult<Node> {
+        Node::with_peers(
             id,
-            advertise_address: advertise_address.parse()?,
-            peers: parse_peer_opt(peer_str.to_owned())?,
+            parse_peer_addresses(peer_str)?,
+            advertise_address.parse()?,
+        )
+    }
+
+    /// with_peers creates the node `id` listening on `advertise_address`,
+    /// whose peers are given as id, address pairs. The node itself should not
+    /// be one of its peers, and all addresses should be distinct.
+    pub fn with_peers(
+        id: NodeId,
+        peers: BTreeMap<NodeId, Address>,
+        advertise_address: Address,
+    ) -> ThreadSafeResult<Node> {
+        if peers.is_empty() {
+            return Err(new_box_err!(format!("node({}) has no peers", id)));
+        }
+        if peers.contains_key(&id) {
+            return Err(new_box_err!(format!(
+                "peers should not contain the node's own id({})",
+                id
+            )));
+        }
+        let mut addresses = HashSet::new();
+        addresses.insert(&advertise_address);
+        for (peer_id, address) in peers.iter() {
+            if !addresses.insert(address) {
+                return Err(new_box_err!(format!(
+                    "the address({}) of peer({}) is used by another node",
+                    address, peer_id
+                )));
+            }
+        }
+        let mut node = Node::with_seeds(id, Vec::new(), advertise_address);
+        node.peers = new_peers(peers);
+        Ok(node)
+    }
+
+    /// with_seeds creates the node `id` listening on `advertise_address`,
+    /// which joins the cluster through the `seeds` once started.
+    pub fn with_seeds(id: NodeId, seeds: Vec<Address>, advertise_address: Address) -> Node {
+        Node {
+            id,
+            priority: 0,
+            advertise_address,
+            peers: BTreeMap::new(),
             leader: None,
+            term: 0,
             last_leader_heartbeat: None,
-        })
+            inbound: HashMap::new(),
+            observers: Observers::default(),
+            app_handlers: Arc::default(),
+            request_handlers: Arc::default(),
+            events: None,
+            inbox: None,
+            transport: Arc::new(TcpTransport),
+            codec: Arc::new(TextCodec),
+            auth: Arc::default(),
+            hello: Arc::new(Hello::new(DEFAULT_CLUSTER)),
+            timing: Timing::default(),
+            electing: false,
+            score: None,
+            hysteresis: 0,
+            seeds,
+            standby: false,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// add_observer registers an observer to be notified of leadership
+    /// changes once the node is started.
+    pub fn add_observer<O: Observer + 'static>(&mut self, observer: O) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// on_message registers the handler of the application messages of the
+    /// `kind`, which should not be empty nor contain colons, before the node
+    /// starts. Messages of kinds without handlers are dropped.
+    pub fn on_message<H: AppHandler + 'static>(
+        &mut self,
+        kind: &str,
+        handler: H,
+    ) -> ThreadSafeResult<()> {
+        // only the node holds the handlers until it starts
+        let handlers = Arc::get_mut(&mut self.app_handlers)
+            .ok_or(new_box_err!("the node is already started".to_owned()))?;
+        handlers.insert(kind, Box::new(handler))
+    }
+
+    /// on_request registers the handler of the requests of the `kind`
+    /// forwarded to the node while it leads, before the node starts.
+    /// Requests of kinds without handlers fail.
+    pub fn on_request<H: RequestHandler + 'static>(
+        &mut self,
+        kind: &str,
+        handler: H,
+    ) -> ThreadSafeResult<()> {
+        let handlers = Arc::get_mut(&mut self.request_handlers)
+            .ok_or(new_box_err!("the node is already started".to_owned()))?;
+        handlers.insert(kind, Box::new(handler))
+    }
+
+    /// set_transport replaces the TCP transport used to connect to peers,
+    /// e.g., with an in-memory one in tests.
+    pub fn set_transport<T: Transport + 'static>(&mut self, transport: T) {
+        self.transport = Arc::new(transport);
+    }
+
+    /// set_auth makes the node authenticate the messages it sends and
+    /// receives, which are not authenticated by default. All nodes of the
+    /// cluster should share the same secret.
+    pub fn set_auth(&mut self, auth: Auth) {
+        self.auth = Arc::new(auth);
+    }
+
+    /// set_codec replaces the text codec of messages, all nodes of the
+    /// cluster should use the same codec.
+    pub fn set_codec(&mut self, codec: Arc<dyn Codec>) {
+        self.codec = codec;
+    }
+
+    /// set_cluster names the cluster of the node, peers of other clusters
+    /// are refused. Nodes are in the cluster "default" unless told.
+    pub fn set_cluster(&mut self, cluster: &str) {
+        self.hello = Arc::new(Hello::new(cluster));
+    }
+
+    /// wire returns how the node writes and reads messages.
+    fn wire(&self) -> Wire {
+        Wire {
+            id: self.id,
+            codec: Arc::clone(&self.codec),
+            auth: Arc::clone(&self.auth),
+            hello: Arc::clone(&self.hello),
+            inbox: self.inbox.clone(),
+        }
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    /// set_priority changes the election priority of the node, which is 0
+    /// by default. Peers learn it from the messages of the node.
+    pub fn set_priority(&mut self, priority: u64) {
+        self.priority = priority;
+    }
+
+    /// set_score makes the node advertise the `score` as its priority, which
+    /// is polled every heartbeat interval once the node starts.
+    pub fn set_score<S: Score + 'static>(&mut self, score: S) {
+        self.score = Some(Box::new(score));
+    }
+
+    /// set_hysteresis sets how much the node should outrank the leader by to
+    /// take over from it, 0 by default. A leader that is only slightly
+    /// outranked keeps leading, so that small changes of scores do not
+    /// cause elections.
+    pub fn set_hysteresis(&mut self, hysteresis: u64) {
+        self.hysteresis = hysteresis;
+    }
+
+    fn rank(&self) -> Rank {
+        Rank::new(self.priority, self.id)
+    }
+
+    /// challenges tells if the node outranks the `leader` by more than the
+    /// hysteresis, and should lead instead. A node on standby never does.
+    fn challenges(&self, leader: Rank) -> bool {
+        !self.standby && Rank::new(self.priority.saturating_sub(self.hysteresis), self.id) > leader
+    }
+
+    fn is_leader(&self) -> bool {
+        self.leader.map(|leader| leader.id) == Some(self.id)
+    }
+
+    /// members returns the node and its peers, and the leader known by the
+    /// node.
+    fn members(&self) -> Members {
+        let mut nodes: BTreeMap<NodeId, Address> = self
+            .peers
+            .iter()
+            .map(|(id, peer)| (*id, peer.address.clone()))
+            .collect();
+        nodes.insert(self.id, self.advertise_address.clone());
+        Members {
+            leader: self.leader,
+            nodes,
+        }
+    }
+
+    /// set_timing replaces the default timing of the node, and fails if the
+    /// `timing` is invalid.
+    pub fn set_timing(&mut self, timing: Timing) -> ThreadSafeResult<()> {
+        timing.validate()?;
+        self.timing = timing;
+        Ok(())
+    }
+
+    /// update_term adopts `term` if it is newer than the current term.
+    fn update_term(&mut self, term: u64) {
+        if term > self.term {
+            debug!(
+                "node({}) moves from term {} to {}",
+                self.id, self.term, term
+            );
+            self.term = term;
+        }
+    }
+
+    /// accepts_leader tells if a leadership claimed by `sender` in `term` is
+    /// not older than the one known by the node. Leaderships are ordered by
+    /// term first and leader rank second, like fencing tokens.
+    fn accepts_leader(&self, sender: Rank, term: u64) -> bool {
+        (term, sender) >= (self.term, self.leader.unwrap_or_default())
+    }
+
+    fn notify(&self, event: LeadershipEvent) {
+        debug!("node({}) notifies {}", self.id, event);
+        if let Some(events) = self.events.as_ref() {
+            let _ = events.send(event);
+        }
     }
 }
 
-/// parse_peer_opt parses the value of the command line options `peers`
-fn parse_peer_opt(peer_str: String) -> ThreadSafeResult<BTreeMap<u8, Peer>> {
-    let mut peers = BTreeMap::new();
+/// new_peers creates the peers at the given addresses, which are unreachable
+/// until connected.
+fn new_peers(addresses: BTreeMap<NodeId, Address>) -> BTreeMap<NodeId, Peer> {
+    addresses
+        .into_iter()
+        .map(|(id, address)| (id, Peer::new(id, address)))
+        .collect()
+}
+
+/// parse_peer_addresses parses peers' id, address pairs, e.g.,
+/// "1=0.0.0.0:1234,2=[::1]:5678,3=node-3:5678".
+pub(crate) fn parse_peer_addresses(peer_str: &str) -> ThreadSafeResult<BTreeMap<NodeId, Address>> {
+    let mut addresses = BTreeMap::new();
     for pair in peer_str.split(',') {
-        let mut id_addr_pair = pair.split("=");
+        let mut id_addr_pair = pair.split('=');
         let id = id_addr_pair
             .next()
-            .ok_or(new_box_err!(peer_str.clone()))?
-            .parse::<u8>()?;
+            .ok_or(new_box_err!(peer_str.to_owned()))?
+            .parse::<NodeId>()?;
         let address = id_addr_pair
             .next()
-            .ok_or(new_box_err!(peer_str.clone()))?
-            .parse::<SocketAddrV4>()?;
-        peers.insert(
-            id,
-            Peer {
-                id,
-                address,
-                conn: None,
-            },
+            .ok_or(new_box_err!(peer_str.to_owned()))?
+            .parse::<Address>()?;
+        if addresses.insert(id, address).is_some() {
+            return Err(new_box_err!(format!("duplicate peer id({})", id)));
+        }
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        connect, elect, new_node, new_peers, parse_peer_addresses, receive_reply, set_leader,
+        ElectionResult, LeadershipEvent::*, Liveness, Node, Opts, Rank,
+    };
+    use crate::bully::message::{receive_message, send_message, Message, MessageType, TextCodec};
+    use clap::Clap;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    #[test]
+    fn suspect_then_dead() {
+        let mut peers = new_peers(parse_peer_addresses("1=127.0.0.1:7001").unwrap());
+        let peer = peers.get_mut(&1).unwrap();
+        assert!(peer.is_dead());
+        peer.set_liveness(Liveness::Alive);
+        peer.suspect();
+        assert_eq!(peer.liveness, Liveness::Suspected);
+        peer.suspect();
+        assert!(peer.is_dead());
+    }
+
+    #[test]
+    fn reject_invalid_peers() {
+        assert!(Node::new(1, "2=127.0.0.1:7002", "127.0.0.1:7001").is_ok());
+        assert!(Node::new(1, "1=127.0.0.1:7002", "127.0.0.1:7001").is_err());
+        assert!(Node::new(1, "2=127.0.0.1:7002,2=127.0.0.1:7003", "127.0.0.1:7001").is_err());
+        assert!(Node::new(1, "2=127.0.0.1:7002,3=127.0.0.1:7002", "127.0.0.1:7001").is_err());
+        assert!(Node::new(1, "2=127.0.0.1:7001", "127.0.0.1:7001").is_err());
+    }
+
+    #[test]
+    fn reject_stale_leader() {
+        let mut node = Node::new(1, "2=127.0.0.1:7002,3=127.0.0.1:7003", "127.0.0.1:7001").unwrap();
+        let (two, three) = (Rank::new(0, 2), Rank::new(0, 3));
+        node.term = 4;
+        node.leader = Some(two);
+        assert!(node.accepts_leader(two, 4));
+        assert!(node.accepts_leader(three, 4));
+        assert!(node.accepts_leader(two, 5));
+        assert!(!node.accepts_leader(three, 3));
+        node.leader = Some(three);
+        assert!(!node.accepts_leader(two, 4));
+        // a higher priority outranks a larger id
+        assert!(node.accepts_leader(Rank::new(1, 2), 4));
+        node.update_term(2);
+        assert_eq!(node.term, 4);
+    }
+
+    #[test]
+    fn leadership_events() {
+        let mut node = Node::new(2, "1=127.0.0.1:7001,3=127.0.0.1:7003", "127.0.0.1:7002").unwrap();
+        let (tx, rx) = mpsc::channel();
+        node.events = Some(tx);
+        set_leader(&mut node, Some(Rank::new(0, 2)));
+        set_leader(&mut node, Some(Rank::new(0, 2)));
+        set_leader(&mut node, Some(Rank::new(0, 3)));
+        // a leader changing its priority is not a new leader
+        set_leader(&mut node, Some(Rank::new(1, 3)));
+        set_leader(&mut node, None);
+        set_leader(&mut node, Some(Rank::new(0, 1)));
+        node.events = None;
+        let events: Vec<_> = rx.iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                LeaderChanged(2),
+                BecameLeader,
+                LostLeadership,
+                LeaderChanged(3),
+                LeaderChanged(1)
+            ]
         );
     }
-    Ok(peers)
+
+    #[test]
+    fn advertise_address_of_opts() {
+        let opts = Opts::parse_from([
+            "bully",
+            "--id=2",
+            "--peers=1=127.0.0.1:5678,3=127.0.0.1:5680",
+            "--advertise-address=127.0.0.1:5679",
+        ]);
+        let node = new_node(&opts).unwrap();
+        assert_eq!(node.advertise_address.to_string(), "127.0.0.1:5679");
+        let opts = Opts::parse_from(["bully", "--id=2", "--seeds=127.0.0.1:5678"]);
+        let node = new_node(&opts).unwrap();
+        assert_eq!(node.advertise_address.to_string(), "127.0.0.1:5678");
+        let opts = Opts::parse_from([
+            "bully",
+            "--id=2",
+            "--peers=1=127.0.0.1:5678",
+            "--advertise-address=127.0.0.1:5678",
+        ]);
+        assert!(new_node(&opts).is_err());
+    }
+
+    #[test]
+    fn elect_waits_for_bulliers_at_once() {
+        // bulliers that accept connections but never reply
+        let listeners: Vec<TcpListener> = (0..3)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let peer_str = listeners
+            .iter()
+            .enumerate()
+            .map(|(i, listener)| format!("{}={}", i + 2, listener.local_addr().unwrap()))
+            .collect::<Vec<String>>()
+            .join(",");
+        let mut node = Node::new(1, &peer_str, "127.0.0.1:7001").unwrap();
+        let transport = Arc::clone(&node.transport);
+        for peer in node.peers.values_mut() {
+            let conn = connect(
+                transport.as_ref(),
+                &peer.address,
+                &node.metrics,
+                node.timing,
+            );
+            peer.conn = Some(conn.unwrap());
+            peer.set_liveness(Liveness::Alive);
+        }
+        let (start, alive_timeout) = (Instant::now(), node.timing.alive_timeout);
+        let locked_node = RwLock::new(node);
+        let (result, node) = elect(&locked_node).unwrap();
+        assert!(matches!(result, ElectionResult::Win));
+        assert!(start.elapsed() < 2 * alive_timeout);
+        assert!(node
+            .peers
+            .values()
+            .all(|peer| peer.liveness == Liveness::Suspected));
+    }
+
+    #[test]
+    fn elect_without_locking_the_node() {
+        // a bullier that accepts connections but never replies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_str = format!("2={}", listener.local_addr().unwrap());
+        let mut node = Node::new(1, &peer_str, "127.0.0.1:7001").unwrap();
+        let transport = Arc::clone(&node.transport);
+        let peer = node.peers.get_mut(&2).unwrap();
+        let conn = connect(
+            transport.as_ref(),
+            &peer.address,
+            &node.metrics,
+            node.timing,
+        );
+        peer.conn = Some(conn.unwrap());
+        peer.set_liveness(Liveness::Alive);
+        let alive_timeout = node.timing.alive_timeout;
+        let locked_node = Arc::new(RwLock::new(node));
+        let clone = Arc::clone(&locked_node);
+        let election =
+            thread::spawn(move || matches!(elect(&clone).unwrap().0, ElectionResult::Win));
+        while !locked_node.read().unwrap().electing {
+            thread::yield_now();
+        }
+        // the node is not locked while waiting for the bullier
+        let start = Instant::now();
+        drop(locked_node.write().unwrap());
+        assert!(start.elapsed() < alive_timeout / 2);
+        // the running election decides
+        assert!(matches!(
+            elect(&locked_node).unwrap().0,
+            ElectionResult::Fail
+        ));
+        assert!(election.join().unwrap());
+        assert!(!locked_node.read().unwrap().electing);
+    }
+
+    #[test]
+    fn dispatch_unrelated_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_str = format!("2={}", listener.local_addr().unwrap());
+        let mut node = Node::new(1, &peer_str, "127.0.0.1:7001").unwrap();
+        let (tx, rx) = mpsc::channel();
+        node.inbox = Some(tx);
+        // the peer sends a heartbeat before replying Alive to Elect
+        let peer = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let elect = receive_message(&TextCodec, &mut conn).unwrap();
+            let two = Rank::new(0, 2);
+            let heartbeat = Message::new(two, MessageType::HeartBeat, 3);
+            send_message(&heartbeat, &TextCodec, &mut conn).unwrap();
+            let alive = elect.reply(two, MessageType::Alive, 3);
+            send_message(&alive, &TextCodec, &mut conn).unwrap();
+            conn
+        });
+        let transport = Arc::clone(&node.transport);
+        let peer_node = node.peers.get_mut(&2).unwrap();
+        let conn = connect(
+            transport.as_ref(),
+            &peer_node.address,
+            &node.metrics,
+            node.timing,
+        );
+        peer_node.conn = Some(conn.unwrap());
+        peer_node.set_liveness(Liveness::Alive);
+        let locked_node = RwLock::new(node);
+        let (result, node) = elect(&locked_node).unwrap();
+        assert!(matches!(result, ElectionResult::Fail));
+        assert_eq!(node.term, 3);
+        drop(node);
+        let (msg, _) = rx.recv().unwrap();
+        assert_eq!(msg.get_message_type(), MessageType::HeartBeat);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn give_up_frames_cut_off_by_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        let node = Node::new(1, "2=127.0.0.1:7002", "127.0.0.1:7001").unwrap();
+        let wire = node.wire();
+        let elect = Message::new(Rank::new(0, 1), MessageType::Elect, 3);
+        let timeout = Duration::from_millis(100);
+        // no reply at all is a timeout
+        assert!(receive_reply(&mut conn, &elect, timeout, &wire)
+            .unwrap()
+            .is_none());
+        // a reply cut off in the middle is an error
+        peer.write_all(b"\0\0\0\x091:1").unwrap();
+        assert!(receive_reply(&mut conn, &elect, timeout, &wire).is_err());
+    }
 }
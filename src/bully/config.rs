@@ -0,0 +1,182 @@
+use crate::bully::address::Address;
+use crate::bully::bully::Node;
+use crate::bully::message::NodeId;
+use crate::bully::timing::{Timing, TimingOverrides};
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::str::FromStr;
+
+/// ClusterConfig lists all nodes of a cluster, so that every node can be
+/// started from the same file, e.g.,
+///
+/// ```toml
+/// # nodes refuse peers of other clusters, defaults to "default"
+/// name = "prod"
+///
+/// # timing of all nodes, in milliseconds
+/// [timing]
+/// heartbeat_interval_ms = 1000
+/// leader_check_interval_ms = 1500
+///
+/// [[nodes]]
+/// id = 1
+/// address = "10.0.0.1:5678"
+/// # leads while alive, though its id is smaller, defaults to 0
+/// priority = 10
+///
+/// [[nodes]]
+/// id = 2
+/// address = "node-2.example.com:5678"
+/// # timing of the node only
+/// timing = { alive_timeout_ms = 2000 }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// name of the cluster, see `Hello`
+    pub name: Option<String>,
+    #[serde(default)]
+    pub timing: TimingOverrides,
+    pub nodes: Vec<NodeConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub id: NodeId,
+    pub address: Address,
+    /// election priority of the node, see `Rank`
+    #[serde(default)]
+    pub priority: u64,
+    /// overrides the timing of the cluster
+    #[serde(default)]
+    pub timing: TimingOverrides,
+}
+
+impl FromStr for ClusterConfig {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: ClusterConfig = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl ClusterConfig {
+    /// load reads and validates the cluster file at `path`.
+    pub fn load(path: &str) -> ThreadSafeResult<ClusterConfig> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| new_box_err!(format!("fail to read {}: {}", path, e)))?;
+        match content.parse() {
+            Ok(config) => Ok(config),
+            Err(e) => Err(new_box_err!(format!(
+                "invalid cluster file {}: {}",
+                path, e
+            ))),
+        }
+    }
+
+    /// validate checks that ids and addresses are unique, and that every
+    /// node has at least one peer.
+    pub fn validate(&self) -> ThreadSafeResult<()> {
+        let mut ids = HashSet::new();
+        let mut addresses = HashSet::new();
+        for node in self.nodes.iter() {
+            if !ids.insert(node.id) {
+                return Err(new_box_err!(format!("duplicate node id({})", node.id)));
+            }
+            if !addresses.insert(&node.address) {
+                return Err(new_box_err!(format!(
+                    "duplicate address({}) of node({})",
+                    node.address, node.id
+                )));
+            }
+        }
+        if self.nodes.len() < 2 {
+            return Err(new_box_err!(
+                "a cluster needs at least two nodes, or nodes have no peers".to_owned()
+            ));
+        }
+        Ok(())
+    }
+
+    /// node creates the node `id` of the cluster, whose peers are all the
+    /// other nodes. The node listens on its address in the file unless the
+    /// `advertise_address` is given.
+    pub fn node(&self, id: NodeId, advertise_address: Option<Address>) -> ThreadSafeResult<Node> {
+        let config = self
+            .nodes
+            .iter()
+            .find(|node| node.id == id)
+            .ok_or(new_box_err!(format!("node({}) is not in the cluster", id)))?;
+        let peers = self
+            .nodes
+            .iter()
+            .filter(|node| node.id != id)
+            .map(|node| (node.id, node.address.clone()))
+            .collect();
+        let address = advertise_address.unwrap_or_else(|| config.address.clone());
+        let mut node = Node::with_peers(id, peers, address)?;
+        if let Some(name) = self.name.as_ref() {
+            node.set_cluster(name);
+        }
+        node.set_priority(config.priority);
+        node.set_timing(config.timing.apply(self.timing.apply(Timing::default())))?;
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClusterConfig;
+    use std::time::Duration;
+
+    const CLUSTER: &str = r#"
+        name = "test"
+
+        [timing]
+        alive_timeout_ms = 500
+
+        [[nodes]]
+        id = 1
+        address = "127.0.0.1:7001"
+
+        [[nodes]]
+        id = 2
+        address = "127.0.0.1:7002"
+        timing = { alive_timeout_ms = 800 }
+    "#;
+
+    #[test]
+    fn parse_and_validate() {
+        let config: ClusterConfig = CLUSTER.parse().unwrap();
+        assert_eq!(config.nodes.len(), 2);
+        assert_eq!(config.name.as_deref(), Some("test"));
+        assert_eq!(config.timing.alive_timeout_ms, Some(500));
+        assert_eq!(
+            config.node(1, None).unwrap().timing().alive_timeout,
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            config.node(2, None).unwrap().timing().alive_timeout,
+            Duration::from_millis(800)
+        );
+        assert!(config.node(3, None).is_err());
+
+        let duplicate_id = CLUSTER.replace("id = 2", "id = 1");
+        assert!(duplicate_id.parse::<ClusterConfig>().is_err());
+        let duplicate_address = CLUSTER.replace("7002", "7001");
+        assert!(duplicate_address.parse::<ClusterConfig>().is_err());
+        let single = r#"
+            [[nodes]]
+            id = 1
+            address = "127.0.0.1:7001"
+        "#;
+        assert!(single.parse::<ClusterConfig>().is_err());
+        let unknown_field = CLUSTER.replace("[timing]", "[timing]\nretries = 3");
+        assert!(unknown_field.parse::<ClusterConfig>().is_err());
+    }
+}
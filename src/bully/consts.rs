@@ -1,3 +1,4 @@
+//! Default timing of nodes, see `Timing` to change it.
 use std::time::Duration;
 
 pub const RETRY: u8 = 10;
@@ -0,0 +1,244 @@
+use crate::bully::message::{Message, MessageType, NodeId};
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use std::fmt;
+use std::str::FromStr;
+
+/// PROTOCOL_VERSION is the latest version of the protocol spoken by nodes
+/// of this build, and MIN_PROTOCOL_VERSION the oldest one they still speak.
+/// The oldest is raised only once no node of the cluster runs a build that
+/// needs it, or the build cannot decode it any more.
+///
+/// 2: messages carry request ids
+/// 3: application messages, see `APP_MESSAGES_VERSION`
+/// 4: requests forwarded to the leader, see `FORWARD_VERSION`
+pub const PROTOCOL_VERSION: u32 = 4;
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// APP_MESSAGES_VERSION is the first version of the protocol with `App`
+/// messages, which are not sent to peers speaking an older one.
+pub const APP_MESSAGES_VERSION: u32 = 3;
+
+/// FORWARD_VERSION is the first version of the protocol with `Forward`
+/// requests, which are not forwarded to leaders speaking an older one.
+pub const FORWARD_VERSION: u32 = 4;
+
+/// DEFAULT_CLUSTER names the cluster of nodes not told their cluster.
+pub const DEFAULT_CLUSTER: &str = "default";
+
+/// Hello is carried by the `Hello` a node sends first on every connection it
+/// makes: the range of protocol versions it speaks and the name of its
+/// cluster, e.g., "1-2:prod".
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub cluster: String,
+}
+
+impl Hello {
+    /// new greets as a node of this build in the `cluster`.
+    pub fn new(cluster: &str) -> Hello {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            cluster: cluster.to_owned(),
+        }
+    }
+
+    /// negotiate returns the highest protocol version spoken by both the node
+    /// and the `peer`, and fails if they are in different clusters, or share
+    /// no version.
+    pub fn negotiate(&self, peer: &Hello) -> ThreadSafeResult<u32> {
+        if self.cluster != peer.cluster {
+            return Err(new_box_err!(format!(
+                "the peer is in cluster {:?}, not {:?}",
+                peer.cluster, self.cluster
+            )));
+        }
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            return Err(new_box_err!(format!(
+                "the peer speaks protocol versions {}-{}, but not {}-{}",
+                peer.min_version, peer.max_version, self.min_version, self.max_version
+            )));
+        }
+        Ok(version)
+    }
+
+    /// accept negotiates the protocol version with the `msg` a peer sends
+    /// first on a connection to the node `id`, which should be `Hello`.
+    pub fn accept(&self, id: NodeId, msg: &Message) -> ThreadSafeResult<u32> {
+        if msg.get_message_type() != MessageType::Hello {
+            return Err(new_box_err!(format!(
+                "expect Hello, got {}",
+                msg.get_message_type()
+            )));
+        }
+        if msg.get_sender_id() == id {
+            return Err(new_box_err!(format!("the peer has the id of node({})", id)));
+        }
+        self.negotiate(&msg.get_payload().parse()?)
+    }
+
+    /// check_ack returns the protocol version in the `reply` to `Hello`, and
+    /// fails if the peer refuses the connection, or is not the node `id` if
+    /// given.
+    pub fn check_ack(&self, reply: &Message, id: Option<NodeId>) -> ThreadSafeResult<u32> {
+        let sender_id = reply.get_sender_id();
+        if reply.get_message_type() != MessageType::HelloAck {
+            return Err(new_box_err!(format!(
+                "the peer replies {} to Hello",
+                reply.get_message_type()
+            )));
+        }
+        match id {
+            Some(id) if sender_id != id => {
+                return Err(new_box_err!(format!(
+                    "expect node({}), but the peer is node({})",
+                    id, sender_id
+                )))
+            }
+            _ => {}
+        }
+        match reply.get_payload().parse()? {
+            HelloAck::Accepted(version)
+                if (self.min_version..=self.max_version).contains(&version) =>
+            {
+                Ok(version)
+            }
+            HelloAck::Accepted(version) => Err(new_box_err!(format!(
+                "node({}) negotiates the unknown protocol version {}",
+                sender_id, version
+            ))),
+            HelloAck::Refused(reason) => Err(new_box_err!(format!(
+                "node({}) refuses the connection: {}",
+                sender_id, reason
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Hello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}:{}",
+            self.min_version, self.max_version, self.cluster
+        )
+    }
+}
+
+impl FromStr for Hello {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> ThreadSafeResult<Hello> {
+        // the name of the cluster may contain colons
+        let (versions, cluster) = s
+            .split_once(':')
+            .ok_or(new_box_err!(format!("invalid hello({})", s)))?;
+        let (min_version, max_version) = versions
+            .split_once('-')
+            .ok_or(new_box_err!(format!("invalid versions({})", versions)))?;
+        Ok(Hello {
+            min_version: min_version.parse()?,
+            max_version: max_version.parse()?,
+            cluster: cluster.to_owned(),
+        })
+    }
+}
+
+/// HelloAck is carried by the `HelloAck` replied to `Hello`: the protocol
+/// version negotiated, e.g., "1", or why the connection is refused, e.g.,
+/// "refused: the peer is in cluster "dev", not "prod"". The connection is
+/// closed once refused.
+#[derive(Debug, PartialEq)]
+pub enum HelloAck {
+    Accepted(u32),
+    Refused(String),
+}
+
+const REFUSED: &str = "refused: ";
+
+impl fmt::Display for HelloAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HelloAck::Accepted(version) => write!(f, "{}", version),
+            HelloAck::Refused(reason) => write!(f, "{}{}", REFUSED, reason),
+        }
+    }
+}
+
+impl FromStr for HelloAck {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> ThreadSafeResult<HelloAck> {
+        match s.strip_prefix(REFUSED) {
+            Some(reason) => Ok(HelloAck::Refused(reason.to_owned())),
+            None => Ok(HelloAck::Accepted(s.parse()?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hello, HelloAck, PROTOCOL_VERSION};
+    use crate::bully::message::{Message, MessageType, Rank};
+
+    #[test]
+    fn negotiate() {
+        let hello: Hello = "1-3:prod:eu".parse().unwrap();
+        assert_eq!(hello.cluster, "prod:eu");
+        assert_eq!(hello.to_string(), "1-3:prod:eu");
+        assert!("1:prod".parse::<Hello>().is_err());
+        assert!("prod".parse::<Hello>().is_err());
+
+        // rolling upgrades speak the highest version both sides know
+        assert_eq!(hello.negotiate(&"2-4:prod:eu".parse().unwrap()).unwrap(), 3);
+        assert_eq!(hello.negotiate(&"1-2:prod:eu".parse().unwrap()).unwrap(), 2);
+        assert!(hello.negotiate(&"4-5:prod:eu".parse().unwrap()).is_err());
+        assert!(hello.negotiate(&"1-3:dev".parse().unwrap()).is_err());
+
+        let greet = |id, cluster: &str| {
+            Message::new(Rank::new(0, id), MessageType::Hello, 0)
+                .with_payload(Hello::new(cluster).to_string())
+        };
+        let hello = Hello::new("prod");
+        assert_eq!(
+            hello.accept(1, &greet(2, "prod")).unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert!(hello.accept(1, &greet(1, "prod")).is_err());
+        assert!(hello.accept(1, &greet(2, "dev")).is_err());
+        let elect = Message::new(Rank::new(0, 2), MessageType::Elect, 0);
+        assert!(hello.accept(1, &elect).is_err());
+
+        let ack = |id, ack: HelloAck| {
+            Message::new(Rank::new(0, id), MessageType::HelloAck, 0).with_payload(ack.to_string())
+        };
+        assert_eq!(
+            hello
+                .check_ack(&ack(2, HelloAck::Accepted(PROTOCOL_VERSION)), Some(2))
+                .unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert_eq!(
+            hello
+                .check_ack(&ack(2, HelloAck::Accepted(PROTOCOL_VERSION)), None)
+                .unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert!(hello
+            .check_ack(&ack(3, HelloAck::Accepted(PROTOCOL_VERSION)), Some(2))
+            .is_err());
+        assert!(hello
+            .check_ack(&ack(2, HelloAck::Accepted(9)), Some(2))
+            .is_err());
+        let refused = HelloAck::Refused("no common version".to_owned());
+        assert!(hello.check_ack(&ack(2, refused), Some(2)).is_err());
+
+        let refused = HelloAck::Refused("no common version".to_owned());
+        assert_eq!(refused.to_string().parse::<HelloAck>().unwrap(), refused);
+        assert_eq!("2".parse::<HelloAck>().unwrap(), HelloAck::Accepted(2));
+        assert!("".parse::<HelloAck>().is_err());
+    }
+}
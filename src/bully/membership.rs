@@ -0,0 +1,94 @@
+use crate::bully::address::Address;
+use crate::bully::bully::parse_peer_addresses;
+use crate::bully::message::{NodeId, Rank};
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use derive_more::Display;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Member is a node joining the cluster, carried by `Join`, e.g.,
+/// "4=10.0.0.4:5678".
+#[derive(Display, Debug, PartialEq, Clone)]
+#[display(fmt = "{}={}", id, address)]
+pub struct Member {
+    pub id: NodeId,
+    pub address: Address,
+}
+
+impl FromStr for Member {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> ThreadSafeResult<Member> {
+        let (id, address) = s
+            .split_once('=')
+            .ok_or(new_box_err!(format!("invalid member({})", s)))?;
+        Ok(Member {
+            id: id.parse()?,
+            address: address.parse()?,
+        })
+    }
+}
+
+/// Members is the membership of a cluster replied by a seed, including the
+/// seed itself and the leader it knows, e.g.,
+/// "0.3;1=10.0.0.1:5678,3=10.0.0.3:5678", or ";1=10.0.0.1:5678" if the
+/// leader is unknown.
+#[derive(Debug, PartialEq)]
+pub struct Members {
+    pub leader: Option<Rank>,
+    pub nodes: BTreeMap<NodeId, Address>,
+}
+
+impl fmt::Display for Members {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(leader) = self.leader {
+            write!(f, "{}", leader)?;
+        }
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|(id, address)| format!("{}={}", id, address))
+            .collect();
+        write!(f, ";{}", nodes.join(","))
+    }
+}
+
+impl FromStr for Members {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> ThreadSafeResult<Members> {
+        let (leader, nodes) = s
+            .split_once(';')
+            .ok_or(new_box_err!(format!("invalid members({})", s)))?;
+        Ok(Members {
+            leader: match leader {
+                "" => None,
+                leader => Some(leader.parse()?),
+            },
+            nodes: parse_peer_addresses(nodes)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Member, Members};
+    use crate::bully::message::Rank;
+
+    #[test]
+    fn from_str() {
+        let member: Member = "4=[::1]:7004".parse().unwrap();
+        assert_eq!(member.id, 4);
+        assert_eq!(member.to_string(), "4=[::1]:7004");
+        assert!("4".parse::<Member>().is_err());
+
+        let members: Members = "0.3;1=10.0.0.1:7000,3=node-3:7000".parse().unwrap();
+        assert_eq!(members.leader, Some(Rank::new(0, 3)));
+        assert_eq!(members.nodes.len(), 2);
+        assert_eq!(members.to_string(), "0.3;1=10.0.0.1:7000,3=node-3:7000");
+        let members: Members = ";1=10.0.0.1:7000".parse().unwrap();
+        assert_eq!(members.leader, None);
+        assert!("1=10.0.0.1:7000".parse::<Members>().is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::bully::address::Address;
+use crate::bully::transport::{Conn, Listener, Transport};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// MemoryNetwork connects nodes running in the same process through
+/// in-memory pipes. Links between nodes can be cut and restored at will,
+/// which makes failures easy to reproduce in tests.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+#[derive(Debug, Default)]
+struct NetworkState {
+    listeners: HashMap<Address, (u64, Sender<(MemoryConn, String)>)>,
+    cut_links: HashSet<(Address, Address)>,
+    conns: Vec<(Address, Address, MemoryConn)>,
+    next_id: u64,
+}
+
+/// link identifies the link between two addresses regardless of direction.
+/// Addresses are compared as is, host names are never resolved.
+fn link(a: &Address, b: &Address) -> (Address, Address) {
+    if a <= b {
+        (a.clone(), b.clone())
+    } else {
+        (b.clone(), a.clone())
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// transport returns the transport of the node at `address`.
+    pub fn transport(&self, address: Address) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            address,
+        }
+    }
+
+    /// partition cuts the link between `a` and `b`: the connections between
+    /// them are closed and new ones time out.
+    pub fn partition(&self, a: &Address, b: &Address) {
+        let mut state = self.state.lock().unwrap();
+        state.cut_links.insert(link(a, b));
+        state.conns.retain(|(from, to, conn)| {
+            if link(from, to) == link(a, b) {
+                conn.close();
+                return false;
+            }
+            true
+        });
+    }
+
+    /// heal restores the link between `a` and `b`.
+    pub fn heal(&self, a: &Address, b: &Address) {
+        self.state.lock().unwrap().cut_links.remove(&link(a, b));
+    }
+}
+
+/// MemoryTransport is the transport of a node on a MemoryNetwork.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    address: Address,
+}
+
+impl Transport for MemoryTransport {
+    fn connect(&self, address: &Address, _: Duration) -> io::Result<Box<dyn Conn>> {
+        let mut state = self.network.state.lock().unwrap();
+        if state.cut_links.contains(&link(&self.address, address)) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "the link is cut"));
+        }
+        let refused = || io::Error::new(ErrorKind::ConnectionRefused, "no listener");
+        let (_, sender) = state.listeners.get(address).ok_or_else(refused)?;
+        let (client, server) = MemoryConn::pair();
+        let desc = format!("{}#{}", self.address, state.next_id);
+        sender.send((server, desc)).map_err(|_| refused())?;
+        state.next_id += 1;
+        state.conns.retain(|(_, _, conn)| !conn.is_closed());
+        let link = (self.address.clone(), address.clone(), client.clone());
+        state.conns.push(link);
+        Ok(Box::new(client))
+    }
+
+    fn listen(&self, address: &Address) -> io::Result<Box<dyn Listener>> {
+        let mut state = self.network.state.lock().unwrap();
+        if state.listeners.contains_key(address) {
+            return Err(io::Error::new(ErrorKind::AddrInUse, address.to_string()));
+        }
+        let (tx, rx) = mpsc::channel();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.listeners.insert(address.clone(), (id, tx));
+        Ok(Box::new(MemoryListener {
+            network: self.network.clone(),
+            address: address.clone(),
+            id,
+            rx,
+        }))
+    }
+}
+
+/// MemoryListener stops accepting connections once dropped, like a closed
+/// TCP listener.
+struct MemoryListener {
+    network: MemoryNetwork,
+    address: Address,
+    id: u64,
+    rx: Receiver<(MemoryConn, String)>,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> io::Result<(Box<dyn Conn>, String)> {
+        let (conn, desc) = self
+            .rx
+            .recv()
+            .map_err(|_| io::Error::new(ErrorKind::NotConnected, "the listener is closed"))?;
+        Ok((Box::new(conn), desc))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        let mut state = self.network.state.lock().unwrap();
+        if let Some((id, _)) = state.listeners.get(&self.address) {
+            if *id == self.id {
+                state.listeners.remove(&self.address);
+            }
+        }
+    }
+}
+
+/// MemoryConn is one end of an in-memory connection.
+#[derive(Debug, Clone)]
+pub struct MemoryConn {
+    rd: Arc<Pipe>,
+    wr: Arc<Pipe>,
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl MemoryConn {
+    fn pair() -> (MemoryConn, MemoryConn) {
+        let (a_to_b, b_to_a) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let a = MemoryConn {
+            rd: Arc::clone(&b_to_a),
+            wr: Arc::clone(&a_to_b),
+            read_timeout: Arc::default(),
+        };
+        let b = MemoryConn {
+            rd: a_to_b,
+            wr: b_to_a,
+            read_timeout: Arc::default(),
+        };
+        (a, b)
+    }
+
+    fn close(&self) {
+        self.rd.close();
+        self.wr.close();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.rd.is_closed() || self.wr.is_closed()
+    }
+}
+
+impl Read for MemoryConn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        self.rd.read(buf, timeout)
+    }
+}
+
+impl Write for MemoryConn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wr.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Conn for MemoryConn {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.close();
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// Pipe carries bytes in one direction of a MemoryConn.
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    cvar: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    /// read blocks until some bytes are available, the pipe is closed, or
+    /// the `timeout` expires. Bytes written before closing are still read.
+    fn read(&self, out: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let deadline = timeout.map(|dur| Instant::now() + dur);
+        let mut state = self.state.lock().unwrap();
+        while state.buf.is_empty() && !state.closed {
+            state = match deadline {
+                None => self.cvar.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(ErrorKind::WouldBlock, "read timed out"));
+                    }
+                    self.cvar.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+        let num_bytes = out.len().min(state.buf.len());
+        for (dst, src) in out.iter_mut().zip(state.buf.drain(..num_bytes)) {
+            *dst = src;
+        }
+        Ok(num_bytes)
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "the pipe is closed"));
+        }
+        state.buf.extend(data);
+        self.cvar.notify_all();
+        Ok(data.len())
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.cvar.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryNetwork;
+    use crate::bully::address::Address;
+    use crate::bully::transport::Transport;
+    use std::io::{ErrorKind, Read, Write};
+    use std::time::Duration;
+
+    #[test]
+    fn connect_and_partition() {
+        let (a, b): (Address, Address) = ("node-1:1".parse().unwrap(), "node-2:1".parse().unwrap());
+        let network = MemoryNetwork::new();
+        let (ta, tb) = (network.transport(a.clone()), network.transport(b.clone()));
+        let timeout = Duration::from_millis(10);
+        assert_eq!(
+            ta.connect(&b, timeout).unwrap_err().kind(),
+            ErrorKind::ConnectionRefused
+        );
+
+        let listener = tb.listen(&b).unwrap();
+        let mut client = ta.connect(&b, timeout).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"1:0:1\n").unwrap();
+        let mut buf = [0; 6];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"1:0:1\n");
+        server.set_read_timeout(Some(timeout)).unwrap();
+        assert_eq!(
+            server.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        network.partition(&a, &b);
+        assert_eq!(server.read(&mut buf).unwrap(), 0);
+        assert!(client.write_all(b"1:0:1\n").is_err());
+        assert_eq!(
+            ta.connect(&b, timeout).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+        network.heal(&a, &b);
+        assert!(ta.connect(&b, timeout).is_ok());
+
+        drop(listener);
+        assert_eq!(
+            ta.connect(&b, timeout).unwrap_err().kind(),
+            ErrorKind::ConnectionRefused
+        );
+    }
+}
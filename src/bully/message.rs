@@ -1,7 +1,41 @@
 use crate::error::{LeaderElectError, ThreadSafeResult};
 use derive_more::Display;
-use std::io::{BufRead, Write};
-use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use std::convert::{TryFrom, TryInto};
+use std::fmt::Debug;
+use std::io::{self, ErrorKind, Read, Write};
+use std::str::{self, FromStr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// NodeId identifies a node in a cluster.
+pub type NodeId = u64;
+
+/// Rank orders nodes in elections, a node bullies the ones with lower ranks.
+/// Nodes are ranked by their priorities first, ties are broken by their ids.
+#[derive(Display, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Copy, Clone)]
+#[display(fmt = "{}.{}", priority, id)]
+pub struct Rank {
+    pub priority: u64,
+    pub id: NodeId,
+}
+
+impl Rank {
+    pub fn new(priority: u64, id: NodeId) -> Rank {
+        Rank { priority, id }
+    }
+}
+
+impl FromStr for Rank {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (priority, id) = s
+            .split_once('.')
+            .ok_or(new_box_err!(format!("invalid rank({})", s)))?;
+        Ok(Rank::new(priority.parse()?, id.parse()?))
+    }
+}
 
 #[derive(Display, Debug, PartialEq, Copy, Clone)]
 pub enum MessageType {
@@ -13,109 +47,540 @@ pub enum MessageType {
     Alive,
     #[display(fmt = "Victory")]
     Victory,
+    #[display(fmt = "Rejoin")]
+    Rejoin,
+    /// the leader steps down, and leaves unless the payload is `STANDBY`
+    #[display(fmt = "Resign")]
+    Resign,
+    /// a node asks to join the cluster, or a seed tells members about it
+    #[display(fmt = "Join")]
+    Join,
+    /// a node leaves the cluster for good
+    #[display(fmt = "Leave")]
+    Leave,
+    /// a seed replies the members of the cluster to `Join`
+    #[display(fmt = "Members")]
+    Members,
+    /// a node on standby replies it to `Elect`, it is alive, but lets the
+    /// candidate lead
+    #[display(fmt = "Abstain")]
+    Abstain,
+    /// a node greets the peer it connects to, see `Hello`
+    #[display(fmt = "Hello")]
+    Hello,
+    /// the peer accepts or refuses the connection, see `HelloAck`
+    #[display(fmt = "HelloAck")]
+    HelloAck,
+    /// a message of the application, see `AppMessage`
+    #[display(fmt = "App")]
+    App,
+    /// a node forwards a request of the application to the leader, see
+    /// `NodeHandle::forward`
+    #[display(fmt = "Forward")]
+    Forward,
+    /// the leader replies to `Forward`, see `ForwardAck`
+    #[display(fmt = "ForwardAck")]
+    ForwardAck,
 }
 
+/// STANDBY is the payload of `Resign` from a leader that stays in the cluster.
+pub const STANDBY: &str = "standby";
+
 #[derive(Display, Debug)]
 pub enum ElectResponse {
     #[display(fmt = "ResponseTimeOut")]
-    ResponseTimeOut = 0,
-    #[display(fmt = "BuillerAlive")]
-    BuillerAlive,
+    ResponseTimeOut,
+    /// the bullier replied the given `Alive` message
+    #[display(fmt = "BuillerAlive({})", _0)]
+    BuillerAlive(Message),
+    /// the bullier is on standby and replied the given `Abstain` message
+    #[display(fmt = "Abstained({})", _0)]
+    Abstained(Message),
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        match code {
+            0 => Ok(MessageType::HeartBeat),
+            1 => Ok(MessageType::Elect),
+            2 => Ok(MessageType::Alive),
+            3 => Ok(MessageType::Victory),
+            4 => Ok(MessageType::Rejoin),
+            5 => Ok(MessageType::Resign),
+            6 => Ok(MessageType::Join),
+            7 => Ok(MessageType::Leave),
+            8 => Ok(MessageType::Members),
+            9 => Ok(MessageType::Abstain),
+            10 => Ok(MessageType::Hello),
+            11 => Ok(MessageType::HelloAck),
+            12 => Ok(MessageType::App),
+            13 => Ok(MessageType::Forward),
+            14 => Ok(MessageType::ForwardAck),
+            _ => Err(new_box_err!(format!("unknown message_type({})", code))),
+        }
+    }
 }
 
 impl FromStr for MessageType {
     type Err = Box<dyn std::error::Error + Send + Sync>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "0" => Ok(MessageType::HeartBeat),
-            "1" => Ok(MessageType::Elect),
-            "2" => Ok(MessageType::Alive),
-            "3" => Ok(MessageType::Victory),
-            _ => Err(new_box_err!("fail to read message_type".to_owned())),
-        }
+        let code = s
+            .parse::<u8>()
+            .map_err(|_| new_box_err!("fail to read message_type".to_owned()))?;
+        MessageType::try_from(code)
     }
 }
 
+/// NEXT_REQUEST_ID is the id of the next request sent by any node of the
+/// process. Ids only need to be unique on a connection.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Display, Debug, PartialEq)]
-#[display(fmt = "[message_type: {}, sender_id: {}]", message_type, sender_id)]
+#[display(
+    fmt = "[message_type: {}, sender_id: {}, term: {}, priority: {}, request_id: {}, payload: {}]",
+    message_type,
+    sender_id,
+    term,
+    priority,
+    request_id,
+    payload
+)]
 pub struct Message {
     message_type: MessageType,
-    sender_id: u8,
+    sender_id: NodeId,
+    /// the election term the sender is in
+    term: u64,
+    /// the election priority of the sender, so that receivers always rank it
+    /// by its latest priority
+    priority: u64,
+    /// the id of a request, which its reply carries too, 0 for messages
+    /// that are neither
+    request_id: u64,
+    /// the content of `Resign`, `Join`, `Members`, `Hello`, `HelloAck`,
+    /// `App`, `Forward` and `ForwardAck`, empty for others
+    payload: String,
 }
 
 impl Message {
-    pub fn new(sender_id: u8, message_type: MessageType) -> Message {
+    pub fn new(sender: Rank, message_type: MessageType, term: u64) -> Message {
         Message {
-            sender_id,
+            sender_id: sender.id,
             message_type,
+            term,
+            priority: sender.priority,
+            request_id: 0,
+            payload: String::new(),
+        }
+    }
+
+    /// new_request creates a message that expects a reply, e.g., `Elect`,
+    /// with a new request id.
+    pub fn new_request(sender: Rank, message_type: MessageType, term: u64) -> Message {
+        Message {
+            request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            ..Message::new(sender, message_type, term)
+        }
+    }
+
+    /// reply creates the reply to the message, which carries its request id.
+    pub fn reply(&self, sender: Rank, message_type: MessageType, term: u64) -> Message {
+        Message {
+            request_id: self.request_id,
+            ..Message::new(sender, message_type, term)
         }
     }
 
+    /// replies_to tells if the message is the reply to the `request`.
+    pub fn replies_to(&self, request: &Message) -> bool {
+        request.request_id != 0 && self.request_id == request.request_id
+    }
+
+    /// with_payload sets the payload of the message.
+    pub fn with_payload(mut self, payload: String) -> Message {
+        self.payload = payload;
+        self
+    }
+
     pub fn get_message_type(&self) -> MessageType {
         self.message_type
     }
 
-    pub fn get_sender_id(&self) -> u8 {
+    pub fn get_sender_id(&self) -> NodeId {
         self.sender_id
     }
+
+    /// get_sender returns the rank of the sender when it sent the message.
+    pub fn get_sender(&self) -> Rank {
+        Rank::new(self.priority, self.sender_id)
+    }
+
+    pub fn get_term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn get_request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    pub fn get_payload(&self) -> &str {
+        &self.payload
+    }
 }
 
 impl FromStr for Message {
     type Err = Box<dyn std::error::Error + Send + Sync>;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut id_type = s.split(':');
+        // the payload may contain colons, e.g., in addresses
+        let mut fields = s.splitn(6, ':');
         Ok(Message {
-            sender_id: id_type
+            sender_id: fields
                 .next()
                 .ok_or(new_box_err!("fail to read id".to_owned()))?
-                .parse::<u8>()?,
-            message_type: id_type
+                .parse::<NodeId>()?,
+            message_type: fields
                 .next()
                 .ok_or(new_box_err!("fail to read type".to_owned()))?
                 .parse::<MessageType>()?,
+            term: fields
+                .next()
+                .ok_or(new_box_err!("fail to read term".to_owned()))?
+                .parse::<u64>()?,
+            priority: fields
+                .next()
+                .ok_or(new_box_err!("fail to read priority".to_owned()))?
+                .parse::<u64>()?,
+            request_id: fields
+                .next()
+                .ok_or(new_box_err!("fail to read request id".to_owned()))?
+                .parse::<u64>()?,
+            payload: fields.next().unwrap_or_default().to_owned(),
         })
     }
 }
 
-pub fn str_to_message(inp_str: &str) -> ThreadSafeResult<Message> {
-    inp_str.trim().parse()
+/// MAX_FRAME_SIZE bounds the length of a frame, a peer announcing a longer
+/// one is refused before it is read.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// CODECS are the names of the codecs known by `new_codec`.
+pub const CODECS: &[&str] = &["text", "json", "binary"];
+
+/// Codec encodes messages into the bodies of frames, and decodes them back.
+/// All nodes of a cluster should use the same codec.
+pub trait Codec: Send + Sync + Debug {
+    fn encode(&self, msg: &Message) -> Vec<u8>;
+
+    fn decode(&self, body: &[u8]) -> ThreadSafeResult<Message>;
+}
+
+/// new_codec returns the codec of the `name`, one of `CODECS`.
+pub fn new_codec(name: &str) -> ThreadSafeResult<Arc<dyn Codec>> {
+    match name {
+        "text" => Ok(Arc::new(TextCodec)),
+        "json" => Ok(Arc::new(JsonCodec)),
+        "binary" => Ok(Arc::new(BinaryCodec)),
+        _ => Err(new_box_err!(format!("unknown codec({})", name))),
+    }
 }
 
-pub fn message_to_str(msg: Message) -> String {
-    format!("{}:{}", msg.sender_id, msg.message_type as u8)
+/// TextCodec encodes messages as `sender:type:term:priority:request[:payload]`,
+/// e.g., `3:3:7:0:0`, the payload is appended only if there is one.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TextCodec;
+
+impl Codec for TextCodec {
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        let mut text = format!(
+            "{}:{}:{}:{}:{}",
+            msg.sender_id, msg.message_type as u8, msg.term, msg.priority, msg.request_id
+        );
+        if !msg.payload.is_empty() {
+            text.push(':');
+            text.push_str(&msg.payload);
+        }
+        text.into_bytes()
+    }
+
+    fn decode(&self, body: &[u8]) -> ThreadSafeResult<Message> {
+        str::from_utf8(body)?.parse()
+    }
 }
 
-pub fn send_message<T: Write>(msg: Message, mut stream: T) -> ThreadSafeResult<()> {
-    Ok(stream.write_all(message_to_str(msg).as_bytes())?)
+/// JsonCodec encodes messages as JSON objects, e.g.,
+/// `{"sender":3,"type":3,"term":7,"priority":0,"request":0}`, the payload is
+/// left out if there is none.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct JsonCodec;
+
+#[derive(Serialize, Deserialize)]
+struct JsonMessage {
+    sender: NodeId,
+    #[serde(rename = "type")]
+    message_type: u8,
+    term: u64,
+    priority: u64,
+    request: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    payload: String,
 }
 
-pub fn receive_message<T: BufRead>(ref mut stream: T) -> ThreadSafeResult<Message> {
-    let mut str_buf = String::new();
-    let num_bytes = stream.read_line(&mut str_buf)?;
-    if num_bytes == 0 {
-        return Err(new_box_err!("0 bytes read".to_owned()));
+impl Codec for JsonCodec {
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        let msg = JsonMessage {
+            sender: msg.sender_id,
+            message_type: msg.message_type as u8,
+            term: msg.term,
+            priority: msg.priority,
+            request: msg.request_id,
+            payload: msg.payload.clone(),
+        };
+        // a plain struct always serializes
+        serde_json::to_vec(&msg).unwrap()
+    }
+
+    fn decode(&self, body: &[u8]) -> ThreadSafeResult<Message> {
+        let msg: JsonMessage = serde_json::from_slice(body)?;
+        Ok(Message {
+            message_type: MessageType::try_from(msg.message_type)?,
+            sender_id: msg.sender,
+            term: msg.term,
+            priority: msg.priority,
+            request_id: msg.request,
+            payload: msg.payload,
+        })
+    }
+}
+
+/// BinaryCodec encodes messages compactly: the sender, the type as a byte,
+/// the term, the priority and the request id, all integers in big endian,
+/// followed by the payload in UTF-8.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BinaryCodec;
+
+const BINARY_HEADER_SIZE: usize = 8 + 1 + 8 + 8 + 8;
+
+impl Codec for BinaryCodec {
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        let mut body = Vec::with_capacity(BINARY_HEADER_SIZE + msg.payload.len());
+        body.extend_from_slice(&msg.sender_id.to_be_bytes());
+        body.push(msg.message_type as u8);
+        body.extend_from_slice(&msg.term.to_be_bytes());
+        body.extend_from_slice(&msg.priority.to_be_bytes());
+        body.extend_from_slice(&msg.request_id.to_be_bytes());
+        body.extend_from_slice(msg.payload.as_bytes());
+        body
+    }
+
+    fn decode(&self, body: &[u8]) -> ThreadSafeResult<Message> {
+        if body.len() < BINARY_HEADER_SIZE {
+            return Err(new_box_err!(format!(
+                "truncated message of {} bytes",
+                body.len()
+            )));
+        }
+        let u64_at = |at: usize| u64::from_be_bytes(body[at..at + 8].try_into().unwrap());
+        Ok(Message {
+            sender_id: u64_at(0),
+            message_type: MessageType::try_from(body[8])?,
+            term: u64_at(9),
+            priority: u64_at(17),
+            request_id: u64_at(25),
+            payload: str::from_utf8(&body[BINARY_HEADER_SIZE..])?.to_owned(),
+        })
     }
-    str_to_message(&str_buf)
+}
+
+/// write_frame writes the `body` prefixed with its length as a big endian
+/// u32 in a single write, so that frames written by clones of a connection
+/// never interleave.
+pub fn write_frame<T: Write>(mut stream: T, body: &[u8]) -> io::Result<()> {
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too large", body.len()),
+        ));
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    stream.write_all(&frame)
+}
+
+/// read_frame reads the body of the next frame from the `stream`, and fails
+/// once the stream is closed, or if the frame is larger than
+/// `MAX_FRAME_SIZE`. Nothing beyond the frame is read.
+pub fn read_frame<T: Read>(mut stream: T) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = frame_len(len)?;
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// frame_len checks the length prefix of a frame.
+pub fn frame_len(prefix: [u8; 4]) -> io::Result<usize> {
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    Ok(len)
+}
+
+/// send_message writes the `msg` to the `stream` in a frame encoded by the
+/// `codec`.
+pub fn send_message<T: Write>(msg: &Message, codec: &dyn Codec, stream: T) -> ThreadSafeResult<()> {
+    Ok(write_frame(stream, &codec.encode(msg))?)
+}
+
+/// receive_message reads the next frame from the `stream`, and decodes the
+/// message in it with the `codec`.
+pub fn receive_message<T: Read>(codec: &dyn Codec, stream: T) -> ThreadSafeResult<Message> {
+    codec.decode(&read_frame(stream)?)
 }
 
 #[cfg(test)]
 mod test {
-    use super::Message;
+    use super::{
+        new_codec, read_frame, receive_message, send_message, write_frame, BinaryCodec, Codec,
+        JsonCodec, Message, MessageType, Rank, TextCodec, CODECS, MAX_FRAME_SIZE,
+    };
     #[test]
     fn from_str() {
-        let msg_str_1 = "1:0";
-        let msg_str_2 = "2:1";
+        let msg_str_1 = "1:0:1:0:0";
+        let msg_str_2 = "2:1:1:0:0";
         assert_ne!(
             msg_str_1.parse::<Message>().unwrap(),
             msg_str_2.parse::<Message>().unwrap()
         );
 
-        let msg_str_3 = "3:2";
-        let msg_str_4 = "3:2";
+        let msg_str_3 = "3:2:7:0:0";
+        let msg_str_4 = "3:2:7:0:0";
         assert_eq!(
             msg_str_3.parse::<Message>().unwrap(),
             msg_str_4.parse::<Message>().unwrap()
         );
+        assert_ne!(
+            "3:2:7:0:0".parse::<Message>().unwrap(),
+            "3:2:8:0:0".parse::<Message>().unwrap()
+        );
+        assert_eq!(
+            "300:2:7:9:0".parse::<Message>().unwrap().get_sender(),
+            Rank::new(9, 300)
+        );
+        assert!("3:2:7:0".parse::<Message>().is_err());
+        assert_eq!(
+            "4:6:7:0:0:4=[::1]:7004"
+                .parse::<Message>()
+                .unwrap()
+                .get_payload(),
+            "4=[::1]:7004"
+        );
+        assert_eq!("0.4".parse::<Rank>().unwrap(), Rank::new(0, 4));
+    }
+
+    #[test]
+    fn match_replies() {
+        let (alice, bob) = (Rank::new(0, 1), Rank::new(0, 2));
+        let elect = Message::new_request(alice, MessageType::Elect, 3);
+        let alive = elect.reply(bob, MessageType::Alive, 3);
+        assert_ne!(elect.get_request_id(), 0);
+        assert!(alive.replies_to(&elect));
+        let next = Message::new_request(alice, MessageType::Elect, 4);
+        assert_ne!(next.get_request_id(), elect.get_request_id());
+        assert!(!alive.replies_to(&next));
+        // messages that are not requests have no reply
+        let heartbeat = Message::new(alice, MessageType::HeartBeat, 3);
+        assert!(!Message::new(bob, MessageType::HeartBeat, 3).replies_to(&heartbeat));
+        let text = TextCodec.encode(&alive);
+        assert_eq!(TextCodec.decode(&text).unwrap(), alive);
+        assert_eq!(
+            text,
+            format!("2:2:3:0:{}", elect.get_request_id()).into_bytes()
+        );
+    }
+
+    #[test]
+    fn receive_consecutive_messages() {
+        let mut buf = Vec::new();
+        let sender = Rank::new(0, 1);
+        let codec = TextCodec;
+        send_message(
+            &Message::new(sender, MessageType::Rejoin, 0),
+            &codec,
+            &mut buf,
+        )
+        .unwrap();
+        send_message(
+            &Message::new(sender, MessageType::Elect, 2),
+            &codec,
+            &mut buf,
+        )
+        .unwrap();
+        let join =
+            Message::new(sender, MessageType::Join, 2).with_payload("1=node-1:80".to_owned());
+        send_message(&join, &codec, &mut buf).unwrap();
+        assert_eq!(&buf[..13], b"\0\0\0\x091:4:0:0:0");
+        let mut rd = buf.as_slice();
+        assert_eq!(
+            receive_message(&codec, &mut rd).unwrap(),
+            Message::new(sender, MessageType::Rejoin, 0)
+        );
+        assert_eq!(
+            receive_message(&codec, &mut rd).unwrap(),
+            Message::new(sender, MessageType::Elect, 2)
+        );
+        assert_eq!(
+            receive_message(&codec, &mut rd).unwrap().get_payload(),
+            "1=node-1:80"
+        );
+        assert!(receive_message(&codec, &mut rd).is_err());
+    }
+
+    #[test]
+    fn codecs() {
+        let messages = [
+            Message::new(Rank::new(5, 300), MessageType::Victory, 7),
+            Message::new_request(Rank::new(0, 4), MessageType::Join, 2)
+                .with_payload("4=[::1]:7004\nwith a newline".to_owned()),
+        ];
+        for name in CODECS {
+            let codec = new_codec(name).unwrap();
+            for msg in messages.iter() {
+                let body = codec.encode(msg);
+                assert_eq!(&codec.decode(&body).unwrap(), msg, "codec {}", name);
+            }
+            // a truncated payload may still be valid, a truncated header not
+            let body = codec.encode(&messages[0]);
+            assert!(codec.decode(&body[..body.len() / 2]).is_err());
+        }
+        assert_eq!(
+            JsonCodec.encode(&messages[0]),
+            br#"{"sender":300,"type":3,"term":7,"priority":5,"request":0}"#
+        );
+        assert_eq!(BinaryCodec.encode(&messages[0]).len(), 33);
+        assert!(BinaryCodec.decode(&[0; 33]).is_ok());
+        assert!(BinaryCodec.decode(&[0xff; 33]).is_err());
+        assert!(new_codec("xml").is_err());
+    }
+
+    #[test]
+    fn refuse_large_frames() {
+        let body = vec![b'0'; MAX_FRAME_SIZE + 1];
+        assert!(write_frame(Vec::new(), &body).is_err());
+        let mut frame = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&body);
+        assert!(read_frame(frame.as_slice()).is_err());
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &body[1..]).unwrap();
+        assert_eq!(read_frame(frame.as_slice()).unwrap().len(), MAX_FRAME_SIZE);
+        // truncated
+        assert!(read_frame(&frame[..100]).is_err());
     }
 }
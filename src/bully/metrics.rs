@@ -0,0 +1,147 @@
+use crate::bully::message::NodeId;
+use crate::error::ThreadSafeResult;
+use prometheus::core::Collector;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+use std::time::Instant;
+
+/// Metrics counts the elections and heartbeats of a node, and is exported in
+/// the Prometheus text format. Every node has its own registry, so that nodes
+/// running in the same process are told apart.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub elections_started: IntCounter,
+    pub elections_won: IntCounter,
+    pub elections_lost: IntCounter,
+    /// how long elections take, whether won or lost
+    pub election_duration: Histogram,
+    pub leader_changes: IntCounter,
+    pub heartbeats_sent: IntCounter,
+    pub heartbeats_received: IntCounter,
+    /// times the leader check found the heartbeat of the leader overdue
+    pub missed_heartbeats: IntCounter,
+    /// messages dropped as unauthenticated or replayed
+    pub rejected_messages: IntCounter,
+    /// failures to connect to peers and seeds, which are not labeled by
+    /// peer, as members come and go
+    pub connect_failures: IntCounter,
+    /// the id of the leader known by the node, -1 if unknown, see
+    /// `set_leader`
+    pub leader: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| register(&registry, IntCounter::new(name, help));
+        let metrics = Metrics {
+            elections_started: counter("bully_elections_started_total", "Elections started"),
+            elections_won: counter("bully_elections_won_total", "Elections won"),
+            elections_lost: counter("bully_elections_lost_total", "Elections lost"),
+            election_duration: register(
+                &registry,
+                Histogram::with_opts(HistogramOpts::new(
+                    "bully_election_duration_seconds",
+                    "Time taken by elections",
+                )),
+            ),
+            leader_changes: counter("bully_leader_changes_total", "Changes of the leader"),
+            heartbeats_sent: counter("bully_heartbeats_sent_total", "Heartbeats sent to peers"),
+            heartbeats_received: counter(
+                "bully_heartbeats_received_total",
+                "Heartbeats received from leaders",
+            ),
+            missed_heartbeats: counter(
+                "bully_missed_heartbeats_total",
+                "Leader heartbeats detected as missed",
+            ),
+            rejected_messages: counter(
+                "bully_rejected_messages_total",
+                "Messages dropped as unauthenticated or replayed",
+            ),
+            connect_failures: counter(
+                "bully_peer_connect_failures_total",
+                "Failures to connect to peers and seeds",
+            ),
+            leader: register(
+                &registry,
+                IntGauge::new(
+                    "bully_leader_id",
+                    "Id of the leader, -1 if unknown, ids above 2^63-1 are clamped to it",
+                ),
+            ),
+            registry: registry.clone(),
+        };
+        metrics.leader.set(-1);
+        metrics
+    }
+}
+
+/// register adds the `collector` to the `registry`. Metrics are defined
+/// statically, so failures are bugs.
+fn register<C: Collector + Clone + 'static>(
+    registry: &Registry,
+    collector: prometheus::Result<C>,
+) -> C {
+    let collector = collector.unwrap();
+    registry.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+impl Metrics {
+    /// set_leader records the `leader` known by the node. The gauge is
+    /// signed, so ids above `i64::MAX` are clamped to it rather than wrapped
+    /// around, e.g., `u64::MAX` would read as -1, i.e., unknown.
+    pub fn set_leader(&self, leader: Option<NodeId>) {
+        let id = leader.map_or(-1, |id| id.min(i64::MAX as u64) as i64);
+        self.leader.set(id);
+    }
+
+    /// observe_election records the result of an election started at
+    /// `start`.
+    pub fn observe_election(&self, won: bool, start: Instant) {
+        match won {
+            true => self.elections_won.inc(),
+            false => self.elections_lost.inc(),
+        }
+        self.election_duration
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    /// encode renders all metrics in the Prometheus text format.
+    pub fn encode(&self) -> ThreadSafeResult<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+
+    #[test]
+    fn encode() {
+        let metrics = Metrics::default();
+        metrics.elections_started.inc();
+        metrics.set_leader(Some(3));
+        metrics.connect_failures.inc();
+        let text = metrics.encode().unwrap();
+        assert!(text.contains("bully_elections_started_total 1"));
+        assert!(text.contains("bully_leader_id 3"));
+        assert!(text.contains("bully_peer_connect_failures_total 1"));
+        assert!(text.contains("bully_election_duration_seconds_count 0"));
+    }
+
+    #[test]
+    fn clamp_leader_ids() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.leader.get(), -1);
+        metrics.set_leader(Some(u64::MAX));
+        assert_eq!(metrics.leader.get(), i64::MAX);
+        metrics.set_leader(Some(1 << 63));
+        assert_eq!(metrics.leader.get(), i64::MAX);
+        metrics.set_leader(None);
+        assert_eq!(metrics.leader.get(), -1);
+    }
+}
@@ -1,4 +1,20 @@
 #[macro_use]
 pub mod message;
+pub mod address;
+pub mod admin;
+pub mod app;
+pub mod async_node;
+pub mod auth;
+#[allow(clippy::module_inception)]
 pub mod bully;
+pub mod config;
 pub mod consts;
+pub mod handshake;
+pub mod membership;
+pub mod memory;
+pub mod metrics;
+pub mod observer;
+pub mod score;
+pub mod timing;
+pub mod tls;
+pub mod transport;
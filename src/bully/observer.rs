@@ -0,0 +1,52 @@
+use crate::bully::message::NodeId;
+use derive_more::Display;
+use std::fmt;
+
+/// LeadershipEvent describes a leadership change seen by a node.
+#[derive(Display, Debug, PartialEq, Copy, Clone)]
+pub enum LeadershipEvent {
+    /// the current node won an election
+    #[display(fmt = "BecameLeader")]
+    BecameLeader,
+    /// the current node was the leader, but is not any more
+    #[display(fmt = "LostLeadership")]
+    LostLeadership,
+    /// the node with the given id is the new leader
+    #[display(fmt = "LeaderChanged({})", _0)]
+    LeaderChanged(NodeId),
+}
+
+/// Observer is notified of the leadership changes of a node. Observers are
+/// called one event at a time from a dedicated thread, so they may query
+/// the node, but a slow observer delays the events behind it.
+pub trait Observer: Send + Sync {
+    fn notify(&self, event: LeadershipEvent);
+}
+
+impl<F: Fn(LeadershipEvent) + Send + Sync> Observer for F {
+    fn notify(&self, event: LeadershipEvent) {
+        self(event)
+    }
+}
+
+/// Observers holds the observers registered on a node before it starts.
+#[derive(Default)]
+pub struct Observers(Vec<Box<dyn Observer>>);
+
+impl Observers {
+    pub fn push(&mut self, observer: Box<dyn Observer>) {
+        self.0.push(observer);
+    }
+
+    pub fn notify(&self, event: LeadershipEvent) {
+        for observer in &self.0 {
+            observer.notify(event);
+        }
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::fs;
+use std::thread;
+
+/// Score rates how fit a node is to lead, and is advertised by the node as
+/// its election priority, so that the fittest node is preferred. It is
+/// polled periodically, and should be cheap.
+pub trait Score: Send + Sync {
+    fn score(&self) -> u64;
+}
+
+impl<F: Fn() -> u64 + Send + Sync> Score for F {
+    fn score(&self) -> u64 {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Score")
+    }
+}
+
+/// LoadAverage scores a node by its idle CPUs, estimated from the 1 minute
+/// load average in /proc/loadavg, from 0 when all CPUs are busy to 100 when
+/// all are idle. Nodes without /proc/loadavg score 0.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct LoadAverage;
+
+impl Score for LoadAverage {
+    fn score(&self) -> u64 {
+        match fs::read_to_string("/proc/loadavg") {
+            Ok(content) => idle_percentage(&content).unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+}
+
+/// idle_percentage estimates the percentage of idle CPUs from the content
+/// of /proc/loadavg.
+fn idle_percentage(loadavg: &str) -> Option<u64> {
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+    Some((100.0 * (1.0 - load / cpus)).max(0.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{idle_percentage, Score};
+    use std::thread;
+
+    #[test]
+    fn idle_cpus() {
+        let cpus = thread::available_parallelism().unwrap().get() as f64;
+        let half_busy = format!("{} 0.10 0.05 1/100 1234", cpus / 2.0);
+        assert_eq!(idle_percentage(&half_busy), Some(50));
+        let overloaded = format!("{} 0.10 0.05 1/100 1234", cpus * 2.0);
+        assert_eq!(idle_percentage(&overloaded), Some(0));
+        assert_eq!(idle_percentage(""), None);
+        let app_score = || 42;
+        assert_eq!(app_score.score(), 42);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::bully::consts::*;
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use clap::Clap;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Timing holds the timeouts and intervals of a node. The defaults suit a
+/// LAN, nodes talking over a WAN need longer ones.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Timing {
+    /// times to retry connecting to a peer when it times out
+    pub retry: u8,
+    pub conn_timeout: Duration,
+    /// how long a candidate waits for `Alive` replies
+    pub alive_timeout: Duration,
+    /// how often the leader sends heartbeats
+    pub heartbeat_interval: Duration,
+    /// how often a follower checks the leader, a leader that has not sent a
+    /// heartbeat for this long is considered dead
+    pub leader_check_interval: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing {
+            retry: RETRY,
+            conn_timeout: INIT_CONN_TIMEOUT,
+            alive_timeout: ALIVE_TIMEOUT,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            leader_check_interval: LEADER_CHECK_INTERVAL,
+        }
+    }
+}
+
+impl Timing {
+    /// validate rejects timings with which a healthy leader could be
+    /// considered dead.
+    pub fn validate(&self) -> ThreadSafeResult<()> {
+        let durations = [
+            ("conn_timeout", self.conn_timeout),
+            ("alive_timeout", self.alive_timeout),
+            ("heartbeat_interval", self.heartbeat_interval),
+            ("leader_check_interval", self.leader_check_interval),
+        ];
+        for (name, dur) in durations.iter() {
+            if dur.is_zero() {
+                return Err(new_box_err!(format!("{} should not be zero", name)));
+            }
+        }
+        if self.heartbeat_interval >= self.leader_check_interval {
+            return Err(new_box_err!(format!(
+                "heartbeat_interval({:?}) should be smaller than leader_check_interval({:?})",
+                self.heartbeat_interval, self.leader_check_interval
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// TimingOverrides holds changes to a timing, given by flags, environment
+/// variables or a cluster file. Durations are in milliseconds.
+#[derive(Clap, Deserialize, Debug, Default, PartialEq, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TimingOverrides {
+    /// Times to retry connecting to a peer when it times out
+    #[clap(long, env = "BULLY_RETRY")]
+    pub retry: Option<u8>,
+    /// Timeout of connecting to a peer in milliseconds
+    #[clap(long, env = "BULLY_CONN_TIMEOUT_MS")]
+    pub conn_timeout_ms: Option<u64>,
+    /// Timeout of waiting for `Alive` replies in milliseconds
+    #[clap(long, env = "BULLY_ALIVE_TIMEOUT_MS")]
+    pub alive_timeout_ms: Option<u64>,
+    /// Interval between heartbeats of the leader in milliseconds
+    #[clap(long, env = "BULLY_HEARTBEAT_INTERVAL_MS")]
+    pub heartbeat_interval_ms: Option<u64>,
+    /// Interval between checks of the leader in milliseconds, should be
+    /// larger than the heartbeat interval
+    #[clap(long, env = "BULLY_LEADER_CHECK_INTERVAL_MS")]
+    pub leader_check_interval_ms: Option<u64>,
+}
+
+impl TimingOverrides {
+    /// apply returns the `timing` changed by the given overrides.
+    pub fn apply(&self, timing: Timing) -> Timing {
+        let millis = |ms: Option<u64>, dur: Duration| ms.map_or(dur, Duration::from_millis);
+        Timing {
+            retry: self.retry.unwrap_or(timing.retry),
+            conn_timeout: millis(self.conn_timeout_ms, timing.conn_timeout),
+            alive_timeout: millis(self.alive_timeout_ms, timing.alive_timeout),
+            heartbeat_interval: millis(self.heartbeat_interval_ms, timing.heartbeat_interval),
+            leader_check_interval: millis(
+                self.leader_check_interval_ms,
+                timing.leader_check_interval,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Timing, TimingOverrides};
+    use std::time::Duration;
+
+    #[test]
+    fn validate() {
+        assert!(Timing::default().validate().is_ok());
+        let timing = Timing {
+            heartbeat_interval: Duration::from_secs(3),
+            ..Timing::default()
+        };
+        assert!(timing.validate().is_err());
+        let timing = Timing {
+            alive_timeout: Duration::from_secs(0),
+            ..Timing::default()
+        };
+        assert!(timing.validate().is_err());
+    }
+
+    #[test]
+    fn apply_overrides() {
+        let overrides = TimingOverrides {
+            alive_timeout_ms: Some(300),
+            ..TimingOverrides::default()
+        };
+        let timing = overrides.apply(Timing::default());
+        assert_eq!(timing.alive_timeout, Duration::from_millis(300));
+        assert_eq!(
+            timing.heartbeat_interval,
+            Timing::default().heartbeat_interval
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use crate::bully::address::Address;
+use crate::bully::message::NodeId;
+use crate::bully::transport::{Conn, Listener, TcpTransport, Transport};
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, Connection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme,
+};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// HANDSHAKE_TIMEOUT bounds how long an accepted connection may take to
+/// complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// NODE_NAME_PREFIX prefixes the id of a node in the DNS names of its
+/// certificate.
+const NODE_NAME_PREFIX: &str = "node-";
+
+/// TlsTransport connects nodes over mutual TLS. All certificates are issued
+/// by the same CA, and the certificate of the node `id` carries the DNS name
+/// `node-{id}` among its subject alternative names, e.g., `node-3`, which is
+/// the identity of the node. The identity is checked against the id of the
+/// peer a node connects to, and against the sender of every message
+/// received, see `Conn::peer_id`.
+#[derive(Clone)]
+pub struct TlsTransport {
+    client: Arc<ClientConfig>,
+    server: Arc<ServerConfig>,
+}
+
+impl fmt::Debug for TlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TlsTransport")
+    }
+}
+
+impl TlsTransport {
+    /// new creates the transport from the PEM files of the CA certificate,
+    /// and the certificate chain and private key of the node.
+    pub fn new(ca_path: &str, cert_path: &str, key_path: &str) -> ThreadSafeResult<TlsTransport> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca_path)? {
+            roots.add(cert)?;
+        }
+        let roots = Arc::new(roots);
+        let certs = load_certs(cert_path)?;
+        let key = load_key(key_path)?;
+        let provider = Arc::new(ring::default_provider());
+
+        let verifier = NodeCertVerifier {
+            inner: WebPkiServerVerifier::builder_with_provider(
+                Arc::clone(&roots),
+                Arc::clone(&provider),
+            )
+            .build()?,
+        };
+        let client = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(certs.clone(), key.clone_key())?;
+
+        let client_verifier =
+            WebPkiClientVerifier::builder_with_provider(roots, Arc::clone(&provider)).build()?;
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(certs, key)?;
+        Ok(TlsTransport {
+            client: Arc::new(client),
+            server: Arc::new(server),
+        })
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connect(&self, address: &Address, timeout: Duration) -> io::Result<Box<dyn Conn>> {
+        // the identity of the peer is checked instead of the server name
+        let name = ServerName::try_from("bully").unwrap();
+        let tls = ClientConnection::new(Arc::clone(&self.client), name).map_err(invalid_data)?;
+        let sock = TcpTransport::connect_tcp(address, timeout)?;
+        let mut conn = TlsConn::new(sock, tls.into());
+        conn.complete_handshake(timeout)?;
+        Ok(Box::new(conn))
+    }
+
+    fn listen(&self, address: &Address) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(TlsListener {
+            listener: TcpListener::bind(&address.resolve()?[..])?,
+            config: Arc::clone(&self.server),
+        }))
+    }
+}
+
+/// TlsListener accepts TLS connections, whose handshake is left to
+/// `Conn::handshake`.
+struct TlsListener {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+impl Listener for TlsListener {
+    fn accept(&self) -> io::Result<(Box<dyn Conn>, String)> {
+        let (sock, addr) = self.listener.accept()?;
+        let tls = ServerConnection::new(Arc::clone(&self.config)).map_err(invalid_data)?;
+        Ok((Box::new(TlsConn::new(sock, tls.into())), addr.to_string()))
+    }
+}
+
+/// TlsConn is a TLS connection. Its clones share the TLS session, so that
+/// a clone can wait for a reply while another one writes. The session is
+/// not locked while reading from the socket.
+pub struct TlsConn {
+    sock: TcpStream,
+    tls: Arc<Mutex<Connection>>,
+    /// the node id proved by the peer, known once the handshake completes
+    peer_id: Option<NodeId>,
+}
+
+impl TlsConn {
+    fn new(sock: TcpStream, tls: Connection) -> TlsConn {
+        TlsConn {
+            sock,
+            tls: Arc::new(Mutex::new(tls)),
+            peer_id: None,
+        }
+    }
+
+    /// complete_handshake completes the TLS handshake over the socket within
+    /// the `timeout`, and fails unless the peer proves its node id.
+    fn complete_handshake(&mut self, timeout: Duration) -> io::Result<()> {
+        let mut tls = self.tls.lock().unwrap();
+        self.sock.set_read_timeout(Some(timeout))?;
+        self.sock.set_write_timeout(Some(timeout))?;
+        while tls.is_handshaking() {
+            tls.complete_io(&mut self.sock)?;
+        }
+        self.sock.set_read_timeout(None)?;
+        self.sock.set_write_timeout(None)?;
+        let peer_id = tls
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(node_id)
+            .ok_or_else(|| invalid_data("the peer certificate has no node id"))?;
+        self.peer_id = Some(peer_id);
+        Ok(())
+    }
+
+    /// check_handshake fails unless the handshake is complete, so that no
+    /// data is exchanged with a peer of unknown identity.
+    fn check_handshake(&self) -> io::Result<()> {
+        match self.peer_id {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "the TLS handshake is not complete",
+            )),
+        }
+    }
+}
+
+impl fmt::Debug for TlsConn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer_id {
+            Some(id) => write!(f, "TlsConn({:?}, node({}))", self.sock, id),
+            None => write!(f, "TlsConn({:?}, handshaking)", self.sock),
+        }
+    }
+}
+
+impl Read for TlsConn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_handshake()?;
+        let mut record = [0; 4096];
+        loop {
+            match self.tls.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            // no plaintext is buffered, read more records
+            let n = self.sock.read(&mut record)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut tls = self.tls.lock().unwrap();
+            let mut records = &record[..n];
+            while !records.is_empty() {
+                tls.read_tls(&mut records)?;
+                tls.process_new_packets().map_err(invalid_data)?;
+            }
+            while tls.wants_write() {
+                tls.write_tls(&mut self.sock)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsConn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_handshake()?;
+        let mut tls = self.tls.lock().unwrap();
+        let n = tls.writer().write(buf)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut self.sock)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // records are written out as soon as they are sealed
+        Ok(())
+    }
+}
+
+impl Conn for TlsConn {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(dur)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.sock.shutdown(Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>> {
+        Ok(Box::new(TlsConn {
+            sock: self.sock.try_clone()?,
+            tls: Arc::clone(&self.tls),
+            peer_id: self.peer_id,
+        }))
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        if self.peer_id.is_some() {
+            return Ok(());
+        }
+        self.complete_handshake(HANDSHAKE_TIMEOUT)
+    }
+
+    fn peer_id(&self) -> Option<NodeId> {
+        self.peer_id
+    }
+}
+
+/// NodeCertVerifier verifies that the certificate of a server is issued by
+/// the CA for a node, whichever node it is.
+#[derive(Debug)]
+struct NodeCertVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for NodeCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let id = node_id(end_entity).ok_or_else(|| {
+            rustls::Error::General("the server certificate has no node id".to_owned())
+        })?;
+        let name = ServerName::try_from(format!("{}{}", NODE_NAME_PREFIX, id))
+            .map_err(|e| rustls::Error::General(e.to_string()))?;
+        self.inner
+            .verify_server_cert(end_entity, intermediates, &name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// node_id returns the id of the node identified by the `cert`, if any.
+fn node_id(cert: &CertificateDer<'_>) -> Option<NodeId> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    let mut names = cert.valid_dns_names();
+    names.find_map(|name| name.strip_prefix(NODE_NAME_PREFIX)?.parse().ok())
+}
+
+fn load_certs(path: &str) -> ThreadSafeResult<Vec<CertificateDer<'static>>> {
+    let file =
+        File::open(path).map_err(|e| new_box_err!(format!("fail to read {}: {}", path, e)))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(new_box_err!(format!("no certificate in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> ThreadSafeResult<PrivateKeyDer<'static>> {
+    let file =
+        File::open(path).map_err(|e| new_box_err!(format!("fail to read {}: {}", path, e)))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file))? {
+        Some(key) => Ok(key),
+        None => Err(new_box_err!(format!("no private key in {}", path))),
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TlsListener, TlsTransport, HANDSHAKE_TIMEOUT};
+    use crate::bully::address::Address;
+    use crate::bully::transport::{Listener, Transport};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn new_ca() -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    /// transport issues a certificate for the DNS `name` by the `ca`, and
+    /// creates the transport trusting the `ca` from PEM files.
+    fn transport(ca: &(Certificate, KeyPair), name: &str) -> TlsTransport {
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        let cert = params.signed_by(&key, &ca.0, &ca.1).unwrap();
+        let dir = std::env::temp_dir().join(format!("bully-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |file: &str, pem: String| -> PathBuf {
+            let path = dir.join(format!("{}-{}", name, file));
+            fs::write(&path, pem).unwrap();
+            path
+        };
+        let ca_path = write("ca.pem", ca.0.pem());
+        let cert_path = write("cert.pem", cert.pem());
+        let key_path = write("key.pem", key.serialize_pem());
+        TlsTransport::new(
+            ca_path.to_str().unwrap(),
+            cert_path.to_str().unwrap(),
+            key_path.to_str().unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mutual_tls() {
+        let ca = new_ca();
+        let (one, two) = (transport(&ca, "node-1"), transport(&ca, "node-2"));
+        let foreign = transport(&new_ca(), "node-3");
+        let anonymous = transport(&ca, "localhost");
+        let listener = TlsListener {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            config: Arc::clone(&two.server),
+        };
+        let address = Address::from(listener.listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            // peers failing the handshake are skipped
+            let conn = loop {
+                let (mut conn, _) = listener.accept().unwrap();
+                if conn.handshake().is_ok() {
+                    break conn;
+                }
+            };
+            assert_eq!(conn.peer_id(), Some(1));
+            let mut reader = BufReader::new(conn);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            reader.get_mut().write_all(line.as_bytes()).unwrap();
+        });
+
+        let timeout = Duration::from_secs(1);
+        assert!(foreign.connect(&address, timeout).is_err());
+        // the server refuses a peer without node id once the handshake is
+        // done
+        if let Ok(conn) = anonymous.connect(&address, timeout) {
+            let mut line = String::new();
+            let res = BufReader::new(conn).read_line(&mut line);
+            assert!(!matches!(res, Ok(n) if n > 0));
+        }
+        let conn = one.connect(&address, timeout).unwrap();
+        assert_eq!(conn.peer_id(), Some(2));
+        let mut reader = BufReader::new(conn);
+        reader.get_mut().write_all(b"1:0:1:0\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "1:0:1:0\n");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn accept_despite_stalled_peers() {
+        let ca = new_ca();
+        let (one, two) = (transport(&ca, "node-1"), transport(&ca, "node-2"));
+        let listener = TlsListener {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            config: Arc::clone(&two.server),
+        };
+        let addr = listener.listener.local_addr().unwrap();
+        // a peer that connects, but never starts the handshake
+        let stalled = TcpStream::connect(addr).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stalled, _) = listener.accept().unwrap();
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.handshake().unwrap();
+            assert_eq!(conn.peer_id(), Some(1));
+            assert!(stalled.read(&mut [0]).is_err());
+            assert!(stalled.handshake().is_err());
+        });
+        let start = Instant::now();
+        let conn = one.connect(&Address::from(addr), Duration::from_secs(1));
+        assert_eq!(conn.unwrap().peer_id(), Some(2));
+        assert!(start.elapsed() < HANDSHAKE_TIMEOUT);
+        drop(stalled);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use crate::bully::address::Address;
+use crate::bully::message::NodeId;
+use std::fmt::Debug;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Conn is a bidirectional byte stream between two nodes.
+pub trait Conn: Read + Write + Send + Sync + Debug {
+    /// set_read_timeout bounds how long a read blocks, a timed out read
+    /// fails with `TimedOut` or `WouldBlock`.
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+
+    /// shutdown closes both directions of the stream, including for the
+    /// clones of the connection.
+    fn shutdown(&self) -> io::Result<()>;
+
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>>;
+
+    /// handshake sets up a connection returned by `Listener::accept` before
+    /// it is used, e.g., runs the TLS handshake. It may block for a while,
+    /// so it runs on the thread serving the connection rather than in the
+    /// accept loop, where a stalled peer would hold up every other one.
+    fn handshake(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// peer_id returns the id the peer proved to have, e.g., by its TLS
+    /// certificate, or `None` if the transport does not authenticate peers.
+    fn peer_id(&self) -> Option<NodeId> {
+        None
+    }
+}
+
+/// Listener accepts connections from peers.
+pub trait Listener: Send {
+    /// accept blocks until a peer connects, and returns the connection with
+    /// a description of the remote end. The connection should complete its
+    /// `Conn::handshake` before it is used.
+    fn accept(&self) -> io::Result<(Box<dyn Conn>, String)>;
+}
+
+/// Transport sets up connections between nodes.
+pub trait Transport: Send + Sync + Debug {
+    fn connect(&self, address: &Address, timeout: Duration) -> io::Result<Box<dyn Conn>>;
+
+    fn listen(&self, address: &Address) -> io::Result<Box<dyn Listener>>;
+}
+
+/// TcpTransport is the default transport, connecting nodes over TCP.
+#[derive(Debug, Default)]
+pub struct TcpTransport;
+
+impl TcpTransport {
+    /// connect_tcp resolves the `address` and connects to the first
+    /// reachable socket address, the `timeout` applies to each of them.
+    pub fn connect_tcp(address: &Address, timeout: Duration) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in address.resolve()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            let msg = format!("{} is resolved to no address", address);
+            io::Error::new(ErrorKind::AddrNotAvailable, msg)
+        }))
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, address: &Address, timeout: Duration) -> io::Result<Box<dyn Conn>> {
+        Ok(Box::new(TcpTransport::connect_tcp(address, timeout)?))
+    }
+
+    fn listen(&self, address: &Address) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(TcpListener::bind(&address.resolve()?[..])?))
+    }
+}
+
+impl Conn for TcpStream {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<(Box<dyn Conn>, String)> {
+        let (conn, addr) = TcpListener::accept(self)?;
+        Ok((Box::new(conn), addr.to_string()))
+    }
+}
@@ -3,3 +3,4 @@ pub mod error;
 pub mod bully;
 pub mod linked_list;
 pub mod logger;
+pub mod ring;
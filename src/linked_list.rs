@@ -88,26 +88,28 @@ impl<T: PartialEq> List<T> {
         }
     }
 
-    pub fn iter(&self) -> Iter<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
         Iter {
             next: self.head.as_deref(),
         }
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<T> {
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
         IterMut {
             next: self.head.as_deref_mut(),
         }
     }
 }
 
-impl<T: PartialEq> PartialEq for List<T> {
-    fn eq(&self, other: &Self) -> bool {
-        !self.ne(other)
+impl<T: PartialEq> Default for List<T> {
+    fn default() -> Self {
+        List::new()
     }
+}
 
-    fn ne(&self, other: &Self) -> bool {
-        self.iter().zip(other.iter()).any(|(x, y)| x != y)
+impl<T: PartialEq> PartialEq for List<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().zip(other.iter()).all(|(x, y)| x == y)
     }
 }
 
@@ -0,0 +1,10 @@
+use std::time::Duration;
+
+pub const CONN_TIMEOUT: Duration = Duration::from_secs(1);
+/// LEADER_CHECK_INTERVAL is how often a member checks that the leader is
+/// reachable, and that the ongoing election, if any, is not stuck.
+pub const LEADER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// ELECTION_TIMEOUT bounds how long an election may take before the member
+/// starts a new one, e.g., when the election message is lost with a crashed
+/// member.
+pub const ELECTION_TIMEOUT: Duration = Duration::from_secs(5);
@@ -0,0 +1,103 @@
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use derive_more::Display;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+/// Message is passed from a node to its successor in the ring.
+#[derive(Display, Debug, PartialEq, Copy, Clone)]
+pub enum Message {
+    /// Election carries the largest candidate id seen so far.
+    #[display(fmt = "Election({})", _0)]
+    Election(u8),
+    /// Elected announces the id of the new leader.
+    #[display(fmt = "Elected({})", _0)]
+    Elected(u8),
+    /// Probe asks the leader if it is alive, and carries the id of the
+    /// member asking. It is answered instead of passed on.
+    #[display(fmt = "Probe({})", _0)]
+    Probe(u8),
+    /// Alive answers a `Probe` with the id of the leader.
+    #[display(fmt = "Alive({})", _0)]
+    Alive(u8),
+}
+
+impl Message {
+    /// originator returns the id of the member the message is about, i.e.,
+    /// the candidate, the leader, or the member probing the leader.
+    pub fn originator(&self) -> u8 {
+        match self {
+            Message::Election(id)
+            | Message::Elected(id)
+            | Message::Probe(id)
+            | Message::Alive(id) => *id,
+        }
+    }
+}
+
+impl FromStr for Message {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut type_id = s.split(':');
+        let msg_type = type_id
+            .next()
+            .ok_or(new_box_err!("fail to read type".to_owned()))?;
+        let id = type_id
+            .next()
+            .ok_or(new_box_err!("fail to read id".to_owned()))?
+            .parse::<u8>()?;
+        match msg_type {
+            "0" => Ok(Message::Election(id)),
+            "1" => Ok(Message::Elected(id)),
+            "2" => Ok(Message::Probe(id)),
+            "3" => Ok(Message::Alive(id)),
+            _ => Err(new_box_err!("fail to read message_type".to_owned())),
+        }
+    }
+}
+
+pub fn str_to_message(inp_str: &str) -> ThreadSafeResult<Message> {
+    inp_str.trim().parse()
+}
+
+/// message_to_str encodes the message as a newline terminated line.
+pub fn message_to_str(msg: Message) -> String {
+    match msg {
+        Message::Election(id) => format!("0:{}\n", id),
+        Message::Elected(id) => format!("1:{}\n", id),
+        Message::Probe(id) => format!("2:{}\n", id),
+        Message::Alive(id) => format!("3:{}\n", id),
+    }
+}
+
+pub fn send_message<T: Write>(msg: Message, mut stream: T) -> ThreadSafeResult<()> {
+    Ok(stream.write_all(message_to_str(msg).as_bytes())?)
+}
+
+pub fn receive_message<T: BufRead>(mut stream: T) -> ThreadSafeResult<Option<Message>> {
+    let mut str_buf = String::new();
+    let num_bytes = stream.read_line(&mut str_buf)?;
+    if num_bytes == 0 {
+        // the predecessor closed the connection
+        return Ok(None);
+    }
+    str_to_message(&str_buf).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{message_to_str, str_to_message, Message};
+    #[test]
+    fn round_trip() {
+        let msgs = [
+            Message::Election(7),
+            Message::Elected(255),
+            Message::Probe(1),
+            Message::Alive(2),
+        ];
+        for msg in msgs.iter() {
+            assert_eq!(str_to_message(&message_to_str(*msg)).unwrap(), *msg);
+        }
+        assert!("4:1".parse::<Message>().is_err());
+        assert!("0:".parse::<Message>().is_err());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod consts;
+pub mod message;
+#[allow(clippy::module_inception)]
+pub mod ring;
@@ -0,0 +1,535 @@
+use crate::error::{LeaderElectError, ThreadSafeResult};
+use crate::linked_list::List;
+use crate::ring::consts::*;
+use crate::ring::message::{self, Message};
+use clap::{AppSettings, Clap};
+use log::{debug, info, trace, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Run a node for leader election using the ring (Chang–Roberts) algorithm.
+#[derive(Clap)]
+#[clap(version = "1.0", author = "Charles Zheng. <charleszheng44@gmail.com>")]
+#[clap(setting = AppSettings::ColoredHelp)]
+pub struct Opts {
+    /// ID of the current candidate
+    #[clap(short, long)]
+    id: u8,
+    /// Peers' id, addresses pair e.g., --peers="1=0.0.0.0:1234,2=0.0.0.0:5678"
+    #[clap(short, long)]
+    peers: String,
+    /// Address that can be visited by peers
+    #[clap(short, long, default_value = "127.0.0.1:5678")]
+    advertise_address: String,
+    /// A level of verbosity, and can be used multiple times
+    #[clap(short, long, default_value = "info")]
+    pub log_level: String,
+}
+
+pub fn run(opts: &Opts) -> ThreadSafeResult<()> {
+    // 1. initialize the node object
+    let node = Node::new(opts.id, &opts.peers, &opts.advertise_address)?;
+    debug!("node({}) initialized", opts.id);
+
+    // 2. listen on the advertise address
+    let listener = TcpListener::bind(node.advertise_address)?;
+
+    // 3. run the node until its handlers finish
+    start(node, listener).join()
+}
+
+/// start runs the `node` in background threads, serving predecessors through
+/// the `listener`, and returns a handle to control it.
+pub fn start(node: Node, listener: TcpListener) -> NodeHandle {
+    let arc_rw_node = Arc::new(RwLock::new(node));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let mut handlers = Vec::new();
+
+    // 1. serve predecessors on the listener
+    let ls_clone = Arc::clone(&arc_rw_node);
+    let ls_stopped = Arc::clone(&stopped);
+    handlers.push(thread::spawn(move || {
+        listen_and_serve(ls_clone, listener, ls_stopped)
+    }));
+
+    // 2. start an election, nodes joining later will start their own
+    // election, which makes the ring converge on the largest live id
+    start_election(&mut arc_rw_node.write().unwrap());
+
+    // 3. check if the leader is alive
+    let (stop, stop_rx) = mpsc::channel();
+    let cl_clone = Arc::clone(&arc_rw_node);
+    handlers.push(thread::spawn(move || check_leader(cl_clone, stop_rx)));
+
+    NodeHandle {
+        node: arc_rw_node,
+        stopped,
+        stop,
+        handlers,
+    }
+}
+
+/// NodeHandle controls a node started by `start`.
+pub struct NodeHandle {
+    node: Arc<RwLock<Node>>,
+    stopped: Arc<AtomicBool>,
+    /// dropped to stop the leader checker
+    stop: Sender<()>,
+    handlers: Vec<JoinHandle<ThreadSafeResult<()>>>,
+}
+
+impl NodeHandle {
+    /// leader returns the leader known by the node, if any.
+    pub fn leader(&self) -> Option<u8> {
+        self.node.read().unwrap().leader
+    }
+
+    /// join waits for all handlers of the node to finish, and fails if any
+    /// of them does.
+    pub fn join(self) -> ThreadSafeResult<()> {
+        for handler in self.handlers {
+            match handler.join() {
+                Ok(res) => res?,
+                Err(e) => return Err(new_box_err!(format!("handler failed: {:?}", e))),
+            }
+        }
+        Ok(())
+    }
+
+    /// kill stops the node abruptly, as if its process crashed: all
+    /// connections are closed and members are not notified.
+    pub fn kill(self) {
+        let NodeHandle {
+            node,
+            stopped,
+            stop,
+            handlers,
+        } = self;
+        stopped.store(true, Ordering::SeqCst);
+        drop(stop);
+        {
+            let mut node = node.write().unwrap();
+            info!("node({}) is killed", node.id);
+            if let Some((_, conn)) = node.successor.take() {
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+            for conn in node.inbound.values() {
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+            // wake up the listener blocked on accepting connections, which
+            // returns without handling the connection
+            let _ = TcpStream::connect_timeout(&node.advertise_address.into(), CONN_TIMEOUT);
+        }
+        for handler in handlers {
+            let _ = handler.join();
+        }
+    }
+}
+
+/// start_election marks the node as a participant and sends its own id to
+/// the successor. If no other member is reachable, the node elects itself.
+fn start_election(node: &mut Node) {
+    info!("node({}) starts an election", node.id);
+    node.participant = Some(Instant::now());
+    let msg = Message::Election(node.id);
+    if let Err(e) = forward(node, msg) {
+        info!("{}, node({}) will be the leader", e, node.id);
+        node.participant = None;
+        node.leader = Some(node.id);
+    }
+}
+
+/// check_leader periodically checks that the leader is reachable, and that
+/// the ongoing election does not take too long, and starts a new election
+/// otherwise, until the node stops.
+fn check_leader(arc_rw_node: Arc<RwLock<Node>>, stop: Receiver<()>) -> ThreadSafeResult<()> {
+    let id = arc_rw_node.read().unwrap().id;
+    // the connection probing the leader is kept across checks
+    let mut prober = None;
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(LEADER_CHECK_INTERVAL) {
+        let (leader, address) = {
+            let mut node = arc_rw_node.write().unwrap();
+            match (node.participant, node.leader) {
+                (Some(since), _) => {
+                    if since.elapsed() > ELECTION_TIMEOUT {
+                        warn!("the election of node({}) times out", node.id);
+                        start_election(&mut node);
+                    }
+                    continue;
+                }
+                (None, None) => {
+                    start_election(&mut node);
+                    continue;
+                }
+                (None, Some(leader)) if leader == node.id => continue,
+                (None, Some(leader)) => match node.address_of(leader) {
+                    Some(address) => (leader, address),
+                    None => continue,
+                },
+            }
+        };
+        // the leader is probed without locking the node
+        if let Err(e) = probe(&mut prober, id, leader, address) {
+            let mut node = arc_rw_node.write().unwrap();
+            if node.leader == Some(leader) && node.participant.is_none() {
+                warn!("leader({}) is unreachable: {}", leader, e);
+                node.leader = None;
+                start_election(&mut node);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// probe sends `Probe` as the member `id` to the `leader` at the `address`
+/// and waits for its `Alive`. The `conn` to the leader is made unless it is
+/// kept from the previous probe, and is dropped if the leader fails to
+/// answer.
+fn probe(
+    conn: &mut Option<(u8, BufReader<TcpStream>)>,
+    id: u8,
+    leader: u8,
+    address: SocketAddrV4,
+) -> ThreadSafeResult<()> {
+    let reuse = matches!(conn, Some((probed, _)) if *probed == leader);
+    if !reuse {
+        // the connection to a former leader is of no use any more
+        *conn = None;
+        let stream = TcpStream::connect_timeout(&address.into(), CONN_TIMEOUT)?;
+        stream.set_read_timeout(Some(CONN_TIMEOUT))?;
+        *conn = Some((leader, BufReader::new(stream)));
+    }
+    let (_, buf_rd) = conn.as_mut().unwrap();
+    let res = message::send_message(Message::Probe(id), buf_rd.get_mut())
+        .and_then(|_| message::receive_message(&mut *buf_rd));
+    match res {
+        Ok(Some(Message::Alive(_))) => Ok(()),
+        res => {
+            *conn = None;
+            match res {
+                Err(e) => Err(e),
+                Ok(reply) => Err(new_box_err!(format!(
+                    "leader({}) answers {:?} to Probe",
+                    leader, reply
+                ))),
+            }
+        }
+    }
+}
+
+/// forward sends `msg` to the closest live successor of the node. Members
+/// that cannot be reached are skipped, so the ring heals itself when nodes
+/// crash and is restored once they come back. The message is dropped
+/// instead of passing the position of its originator, which is unreachable,
+/// as it would go around the ring forever otherwise. Returns whether the
+/// message is sent.
+fn forward(node: &mut Node, msg: Message) -> ThreadSafeResult<bool> {
+    let successors: Vec<(u8, SocketAddrV4)> = node
+        .successors()
+        .map(|member| (member.id, member.address))
+        .collect();
+    for (id, address) in successors {
+        match send_to(node, id, address, msg) {
+            Ok(()) => return Ok(true),
+            Err(e) => debug!("member({}) is unreachable: {}", id, e),
+        }
+        if node.leader == Some(id) {
+            warn!("leader({}) is unreachable", id);
+            node.leader = None;
+        }
+        if id == msg.originator() {
+            info!("drop {}, as member({}) is unreachable", msg, id);
+            return Ok(false);
+        }
+    }
+    Err(new_box_err!(format!(
+        "no successor of node({}) is reachable",
+        node.id
+    )))
+}
+
+/// send_to sends `msg` to the member `id` at the `address`, connecting to it
+/// unless it is the current successor.
+fn send_to(node: &mut Node, id: u8, address: SocketAddrV4, msg: Message) -> ThreadSafeResult<()> {
+    let reuse = matches!(node.successor, Some((succ_id, _)) if succ_id == id);
+    if !reuse {
+        let conn = TcpStream::connect_timeout(&(address.into()), CONN_TIMEOUT)?;
+        debug!("node({}) connected to successor({})", node.id, id);
+        node.successor = Some((id, conn));
+    }
+    let (_, conn) = node.successor.as_mut().unwrap();
+    debug!("send message {} to member({})", msg, id);
+    let res = message::send_message(msg, conn);
+    if res.is_err() {
+        node.successor = None;
+    }
+    res
+}
+
+/// pass_on forwards the `msg` received from the predecessor, and starts a
+/// new election if the message is dropped, or if no successor is reachable,
+/// which elects the node. Either way the connection to the predecessor is
+/// kept.
+fn pass_on(node: &mut Node, msg: Message) {
+    match forward(node, msg) {
+        Ok(true) => {}
+        Ok(false) => start_election(node),
+        Err(e) => {
+            warn!("fail to pass on {}: {}", msg, e);
+            start_election(node);
+        }
+    }
+}
+
+/// listen_and_serve accepts connections from predecessors and handles the
+/// messages sent through them.
+fn listen_and_serve(
+    arc_rw_node: Arc<RwLock<Node>>,
+    listener: TcpListener,
+    stopped: Arc<AtomicBool>,
+) -> ThreadSafeResult<()> {
+    loop {
+        let (conn, addr) = listener.accept()?;
+        if stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        debug!("accept connection from {}", addr);
+        // keep a clone of the connection, so that it can be closed when the
+        // node is killed
+        let clone = conn.try_clone()?;
+        arc_rw_node.write().unwrap().inbound.insert(addr, clone);
+        let node_clone = arc_rw_node.clone();
+        let hdl_stopped = Arc::clone(&stopped);
+        thread::spawn(move || {
+            if let Err(e) = handle_message(Arc::clone(&node_clone), conn, hdl_stopped) {
+                warn!("connection from {} closed: {}", addr, e);
+            }
+            node_clone.write().unwrap().inbound.remove(&addr);
+        });
+    }
+}
+
+/// handle_message keeps reading messages from the conn and handling them
+/// following the Chang–Roberts algorithm. Probes of the leader are answered
+/// through the conn.
+fn handle_message(
+    arc_rw_node: Arc<RwLock<Node>>,
+    conn: TcpStream,
+    stopped: Arc<AtomicBool>,
+) -> ThreadSafeResult<()> {
+    let mut buf_rd = BufReader::new(conn);
+    while let Some(msg) = message::receive_message(&mut buf_rd)? {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        debug!("receive message {}", msg);
+        let mut node = arc_rw_node.write().unwrap();
+        match msg {
+            Message::Probe(prober) => {
+                trace!("member({}) probes node({})", prober, node.id);
+                message::send_message(Message::Alive(node.id), buf_rd.get_mut())?;
+            }
+            Message::Alive(id) => {
+                warn!("discard {} not asked by a probe", Message::Alive(id));
+            }
+            Message::Election(candidate) if candidate > node.id => {
+                // a better candidate, pass it on
+                node.participant.get_or_insert_with(Instant::now);
+                pass_on(&mut node, msg);
+            }
+            Message::Election(candidate) if candidate < node.id => {
+                if node.participant.is_some() {
+                    // our own id is already circulating
+                    debug!("discard election message for candidate({})", candidate);
+                    continue;
+                }
+                node.participant = Some(Instant::now());
+                let node_id = node.id;
+                pass_on(&mut node, Message::Election(node_id));
+            }
+            Message::Election(_) => {
+                // our id went around the ring, no larger id is alive
+                info!("node({}) is elected as the leader", node.id);
+                node.participant = None;
+                node.leader = Some(node.id);
+                let node_id = node.id;
+                pass_on(&mut node, Message::Elected(node_id));
+            }
+            Message::Elected(leader) => {
+                node.participant = None;
+                node.leader = Some(leader);
+                if leader != node.id {
+                    info!("member({}) is the leader", leader);
+                    pass_on(&mut node, msg);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Node {
+    id: u8,
+    advertise_address: SocketAddrV4,
+    ring: List<Member>,
+    successor: Option<(u8, TcpStream)>,
+    /// when the node took part in the ongoing election, if any
+    participant: Option<Instant>,
+    leader: Option<u8>,
+    inbound: HashMap<SocketAddr, TcpStream>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Member {
+    id: u8,
+    address: SocketAddrV4,
+}
+
+impl Node {
+    pub fn new(id: u8, peer_str: &str, advertise_address: &str) -> ThreadSafeResult<Node> {
+        let advertise_address: SocketAddrV4 = advertise_address.parse()?;
+        let mut members = parse_peer_opt(peer_str)?;
+        if members.insert(id, advertise_address).is_some() {
+            return Err(new_box_err!(format!(
+                "peers should not contain the node's own id({})",
+                id
+            )));
+        }
+        // members are placed on the ring in ascending order of their ids
+        let mut ring = List::new();
+        for (id, address) in members {
+            ring.push_tail(Member { id, address });
+        }
+        Ok(Node {
+            id,
+            advertise_address,
+            ring,
+            successor: None,
+            participant: None,
+            leader: None,
+            inbound: HashMap::new(),
+        })
+    }
+
+    /// address_of returns the address of the member `id`, if any.
+    fn address_of(&self, id: u8) -> Option<SocketAddrV4> {
+        self.ring
+            .iter()
+            .find(|member| member.id == id)
+            .map(|member| member.address)
+    }
+
+    /// successors iterates the other members in ring order, starting from
+    /// the member right after the current node.
+    fn successors(&self) -> impl Iterator<Item = &Member> {
+        let id = self.id;
+        self.ring
+            .iter()
+            .skip_while(move |member| member.id != id)
+            .skip(1)
+            .chain(self.ring.iter().take_while(move |member| member.id != id))
+    }
+}
+
+/// parse_peer_opt parses the value of the command line options `peers`
+fn parse_peer_opt(peer_str: &str) -> ThreadSafeResult<BTreeMap<u8, SocketAddrV4>> {
+    let mut peers = BTreeMap::new();
+    for pair in peer_str.split(',') {
+        let mut id_addr_pair = pair.split('=');
+        let id = id_addr_pair
+            .next()
+            .ok_or(new_box_err!(peer_str.to_owned()))?
+            .parse::<u8>()?;
+        let address = id_addr_pair
+            .next()
+            .ok_or(new_box_err!(peer_str.to_owned()))?
+            .parse::<SocketAddrV4>()?;
+        peers.insert(id, address);
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forward, listen_and_serve, pass_on, probe, Node};
+    use crate::ring::message::Message;
+    use std::net::{SocketAddr, SocketAddrV4, TcpListener};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    #[test]
+    fn successors_wrap_around() {
+        let node = Node::new(
+            2,
+            "3=127.0.0.1:7003,1=127.0.0.1:7001,4=127.0.0.1:7004",
+            "127.0.0.1:7002",
+        )
+        .unwrap();
+        let ids: Vec<u8> = node.successors().map(|member| member.id).collect();
+        assert_eq!(ids, vec![3, 4, 1]);
+        assert!(Node::new(2, "2=127.0.0.1:7003", "127.0.0.1:7002").is_err());
+    }
+
+    #[test]
+    fn drop_messages_of_unreachable_originators() {
+        // members 3 and 1 are unreachable, as nothing listens on their ports
+        let closed = || {
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let peers = format!("3={},1={}", closed(), closed());
+        let mut node = Node::new(2, &peers, "127.0.0.1:7002").unwrap();
+        node.leader = Some(3);
+        assert!(!forward(&mut node, Message::Elected(3)).unwrap());
+        assert_eq!(node.leader, None);
+        // member 3 is skipped, but the message dies with member 1
+        assert!(!forward(&mut node, Message::Election(1)).unwrap());
+        assert!(forward(&mut node, Message::Election(2)).is_err());
+    }
+
+    #[test]
+    fn pass_on_without_successors() {
+        let closed = || {
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let peers = format!("3={},1={}", closed(), closed());
+        let mut node = Node::new(2, &peers, "127.0.0.1:7002").unwrap();
+        // the node is the only live member, and elects itself
+        pass_on(&mut node, Message::Election(3));
+        assert_eq!(node.leader, Some(2));
+    }
+
+    #[test]
+    fn probe_over_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = match listener.local_addr().unwrap() {
+            SocketAddr::V4(address) => address,
+            address => panic!("unexpected address {}", address),
+        };
+        let node = Node::new(3, "1=127.0.0.1:7001", &address.to_string()).unwrap();
+        let arc_rw_node = Arc::new(RwLock::new(node));
+        let node_clone = Arc::clone(&arc_rw_node);
+        let stopped = Arc::new(AtomicBool::new(false));
+        thread::spawn(move || listen_and_serve(node_clone, listener, stopped));
+        let mut conn = None;
+        for _ in 0..3 {
+            probe(&mut conn, 1, 3, address).unwrap();
+        }
+        assert_eq!(arc_rw_node.read().unwrap().inbound.len(), 1);
+        let closed: SocketAddrV4 = "127.0.0.1:1".parse().unwrap();
+        assert!(probe(&mut conn, 1, 2, closed).is_err());
+        assert!(conn.is_none());
+    }
+}
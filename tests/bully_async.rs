@@ -0,0 +1,162 @@
+use leader_elect::bully::async_node::{self, NodeHandle};
+use leader_elect::bully::auth::Auth;
+use leader_elect::bully::bully;
+use leader_elect::bully::message::NodeId;
+use leader_elect::logger;
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use tokio::net::TcpListener;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+use tokio_stream::StreamExt;
+
+/// ELECTION_DEADLINE bounds how long a cluster may take to agree on a leader.
+const ELECTION_DEADLINE: Duration = Duration::from_secs(20);
+
+const SECRET: &[u8] = b"the cluster secret";
+
+async fn bind() -> (SocketAddrV4, TcpListener) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => (addr, listener),
+        addr => panic!("unexpected address {}", addr),
+    }
+}
+
+fn peer_str(id: NodeId, addresses: &BTreeMap<NodeId, SocketAddrV4>) -> String {
+    addresses
+        .iter()
+        .filter(|(peer_id, _)| **peer_id != id)
+        .map(|(peer_id, addr)| format!("{}={}", peer_id, addr))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// wait_until polls `leaders` until all of them are `expected`.
+async fn wait_until<F: Fn() -> Vec<Option<NodeId>>>(leaders: F, expected: NodeId) {
+    let start = Instant::now();
+    while leaders().iter().any(|leader| *leader != Some(expected)) {
+        assert!(
+            start.elapsed() < ELECTION_DEADLINE,
+            "expect node({}) to be the leader, got {:?}",
+            expected,
+            leaders()
+        );
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_cluster_failover() {
+    let _ = logger::init("info");
+    let mut listeners = BTreeMap::new();
+    let mut addresses = BTreeMap::new();
+    for id in 1..=3 {
+        let (addr, listener) = bind().await;
+        addresses.insert(id, addr);
+        listeners.insert(id, listener);
+    }
+    let starting: Vec<_> = listeners
+        .into_iter()
+        .map(|(id, listener)| {
+            let node = async_node::Node::new(id, &peer_str(id, &addresses)).unwrap();
+            tokio::spawn(async_node::start(node, listener))
+        })
+        .collect();
+    let mut handles: Vec<NodeHandle> = Vec::new();
+    for hdl in starting {
+        handles.push(hdl.await.unwrap().unwrap());
+    }
+
+    // the leader is observed through the stream of leadership changes
+    let mut leaders = handles[0].leader_stream();
+    while time::timeout(ELECTION_DEADLINE, leaders.next())
+        .await
+        .unwrap()
+        != Some(Some(3))
+    {}
+    wait_until(|| handles.iter().map(|hdl| hdl.leader()).collect(), 3).await;
+
+    handles.pop().unwrap().kill().await;
+    wait_until(|| handles.iter().map(|hdl| hdl.leader()).collect(), 2).await;
+    for hdl in handles {
+        hdl.kill().await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mixed_cluster() {
+    run_mixed_cluster(None).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn authenticated_mixed_cluster() {
+    run_mixed_cluster(Some(SECRET)).await;
+}
+
+/// run_mixed_cluster runs threaded nodes 1 and 2 with the async node 3,
+/// all of them authenticating messages with the `secret` if any.
+async fn run_mixed_cluster(secret: Option<&'static [u8]>) {
+    let _ = logger::init("info");
+    let mut addresses = BTreeMap::new();
+    let mut std_listeners = BTreeMap::new();
+    for id in 1..=2 {
+        let (addr, listener) = bind().await;
+        addresses.insert(id, addr);
+        std_listeners.insert(id, listener.into_std().unwrap());
+    }
+    let (addr, listener) = bind().await;
+    addresses.insert(3, addr);
+
+    // threaded nodes 1 and 2 block while starting
+    let threaded: Vec<_> = std_listeners
+        .into_iter()
+        .map(|(id, listener)| {
+            let peers = peer_str(id, &addresses);
+            let address = addresses[&id].to_string();
+            task::spawn_blocking(move || {
+                listener.set_nonblocking(false).unwrap();
+                let mut node = bully::Node::new(id, &peers, &address).unwrap();
+                if let Some(secret) = secret {
+                    node.set_auth(Auth::new(secret).unwrap());
+                }
+                bully::start(node, Box::new(listener))
+            })
+        })
+        .collect();
+    let mut node = async_node::Node::new(3, &peer_str(3, &addresses)).unwrap();
+    if let Some(secret) = secret {
+        node.set_auth(Auth::new(secret).unwrap());
+    }
+    let async_handle = async_node::start(node, listener).await.unwrap();
+    let mut handles = Vec::new();
+    for hdl in threaded {
+        handles.push(hdl.await.unwrap().unwrap());
+    }
+
+    wait_until(
+        || {
+            let mut leaders: Vec<_> = handles.iter().map(|hdl| hdl.leader()).collect();
+            leaders.push(async_handle.leader());
+            leaders
+        },
+        3,
+    )
+    .await;
+    assert!(async_handle.term().await > 0);
+    // the async node does not claim to receive application messages
+    assert!(handles[0].send_to(3, "greet", "hello").is_err());
+    // nor to handle requests, which fail without being forwarded
+    let forward =
+        task::block_in_place(|| handles[0].forward("echo", "hello", Duration::from_secs(1)));
+    assert!(forward
+        .unwrap_err()
+        .to_string()
+        .contains("protocol version"));
+
+    async_handle.kill().await;
+    wait_until(|| handles.iter().map(|hdl| hdl.leader()).collect(), 2).await;
+    task::spawn_blocking(move || handles.iter().for_each(|hdl| hdl.kill()))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,525 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{fast_timing, wait_until, Cluster, Settings, ELECTION_DEADLINE};
+use leader_elect::bully::admin::AdminServer;
+use leader_elect::bully::auth::Auth;
+use leader_elect::bully::bully::Liveness;
+use leader_elect::bully::handshake::{Hello, DEFAULT_CLUSTER, PROTOCOL_VERSION};
+use leader_elect::bully::message::{
+    new_codec, read_frame, write_frame, Codec, Message, MessageType, Rank, TextCodec,
+};
+use leader_elect::bully::observer::LeadershipEvent::*;
+use leader_elect::bully::timing::Timing;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn elect_largest_id() {
+    let cluster = Cluster::start(&[1, 2, 3]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn leader_crash() {
+    let mut cluster = Cluster::start(&[1, 2, 3]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.kill(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+}
+
+#[test]
+fn leader_rejoin() {
+    let mut cluster = Cluster::start(&[1, 2, 3]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.kill(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    cluster.restart(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn follower_rejoin() {
+    let mut cluster = Cluster::start(&[1, 2, 3]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.kill(1);
+    cluster.restart(1);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn simultaneous_elections() {
+    // all followers notice the leader is gone at about the same time
+    let mut cluster = Cluster::start(&[1, 2, 3, 4, 5]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.kill(5);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 4);
+}
+
+#[test]
+fn multiple_crashes() {
+    let mut cluster = Cluster::start(&[1, 2, 3, 4, 5]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.kill(5);
+    cluster.kill(4);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    cluster.restart(4);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 4);
+}
+
+#[test]
+fn observe_failover() {
+    let mut cluster = Cluster::start(&[1, 2, 3]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.kill(3);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    // events are delivered asynchronously
+    thread::sleep(Duration::from_millis(100));
+    let events = cluster.events(2);
+    assert_eq!(events.last(), Some(&BecameLeader));
+    assert!(events.contains(&LeaderChanged(3)));
+    assert_eq!(cluster.events(1).last(), Some(&LeaderChanged(2)));
+    assert!(!cluster.events(1).contains(&BecameLeader));
+}
+
+#[test]
+fn fencing_token_increases() {
+    let mut cluster = Cluster::start(&[1, 2, 3]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    let old_token = cluster.fencing_token(3).unwrap();
+    assert_eq!(cluster.fencing_token(2), None);
+    cluster.kill(3);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    let new_token = cluster.fencing_token(2).unwrap();
+    assert!(new_token > old_token);
+    assert_eq!(cluster.fencing_token(1), None);
+}
+
+#[test]
+fn elect_highest_priority() {
+    // node 1 outranks the others, nodes 2 and 3 tie and larger id wins
+    let mut cluster = Cluster::start_with_priorities(&[(1, 10), (2, 5), (3, 5)]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 1);
+    cluster.kill(1);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn join_and_leave() {
+    let mut cluster = Cluster::start(&[1, 2]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    cluster.join(3, &[1]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    cluster.wait_for_members(&[1, 2, 3], ELECTION_DEADLINE);
+    cluster.join(4, &[3]);
+    cluster.wait_for_members(&[1, 2, 3, 4], ELECTION_DEADLINE);
+    cluster.leave(4);
+    cluster.leave(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    cluster.wait_for_members(&[1, 2], ELECTION_DEADLINE);
+}
+
+#[test]
+fn join_with_auth() {
+    let settings =
+        Settings::with_setup(|_, node| node.set_auth(Auth::new(b"cluster secret").unwrap()));
+    let mut cluster = Cluster::start_with_settings(&[1, 2], settings);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    // the joining node reaches the seed before it knows the id of the seed
+    cluster.join(3, &[1]);
+    cluster.wait_for_members(&[1, 2, 3], ELECTION_DEADLINE);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn leader_resigns() {
+    let mut cluster = Cluster::start(&[1, 2, 3]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.shutdown(3);
+    // peers take over without waiting for the leader check
+    let leader_check_interval = Timing::default().leader_check_interval;
+    assert_eq!(cluster.wait_for_leader(leader_check_interval), 2);
+    cluster.restart(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn in_memory_leader_rejoin() {
+    let mut cluster = Cluster::start_in_memory(&[1, 2, 3]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    cluster.kill(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    cluster.restart(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn host_name_leader_rejoin() {
+    // host names are resolved again when the restarted leader reconnects
+    let mut cluster = Cluster::start_on_localhost(&[1, 2, 3]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    cluster.kill(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    cluster.restart(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn isolated_leader() {
+    let cluster = Cluster::start_in_memory(&[1, 2, 3]);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    let old_token = cluster.fencing_token(3).unwrap();
+    cluster.partition(3, 1);
+    cluster.partition(3, 2);
+    // the majority side elects a new leader, while the isolated one still
+    // believes it leads, its token is fenced off by the newer one
+    assert_eq!(cluster.wait_for_leader_among(&[1, 2], ELECTION_DEADLINE), 2);
+    let new_token = cluster.fencing_token(2).unwrap();
+    assert!(new_token > old_token);
+
+    // once healed, the isolated leader takes over in a newer term instead of
+    // leading alongside the new one
+    cluster.heal(3, 1);
+    cluster.heal(3, 2);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    assert!(cluster.fencing_token(3).unwrap() > new_token);
+    assert_eq!(cluster.fencing_token(1), None);
+    assert_eq!(cluster.fencing_token(2), None);
+}
+
+#[test]
+fn healed_peer_reconnects() {
+    let cluster = Cluster::start_in_memory(&[1, 2, 3]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    let liveness = |id, peer_id| {
+        let status = cluster.handle(id).status();
+        let peer = status.peers.iter().find(|peer| peer.id == peer_id);
+        peer.unwrap().liveness
+    };
+    let wait_for_liveness = |id, peer_id, expected| {
+        assert!(
+            wait_until(ELECTION_DEADLINE, || liveness(id, peer_id) == expected),
+            "peer({}) is not {} to node({})",
+            peer_id,
+            expected,
+            id
+        );
+    };
+    cluster.partition(3, 1);
+    wait_for_liveness(1, 3, Liveness::Dead);
+    wait_for_liveness(3, 1, Liveness::Dead);
+    cluster.heal(3, 1);
+    wait_for_liveness(1, 3, Liveness::Alive);
+    wait_for_liveness(3, 1, Liveness::Alive);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+}
+
+#[test]
+fn fast_failover() {
+    let timing = fast_timing();
+    let mut cluster = Cluster::start_with_timing(&[1, 2, 3], timing);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    cluster.kill(3);
+    // well before the default leader check interval
+    assert_eq!(cluster.wait_for_leader(Duration::from_secs(2)), 2);
+}
+
+#[test]
+fn leadership_follows_scores() {
+    let scores: Arc<BTreeMap<_, _>> = Arc::new(
+        [(1, 10), (2, 20), (3, 30)]
+            .iter()
+            .map(|(id, score)| (*id, AtomicU64::new(*score)))
+            .collect(),
+    );
+    let node_scores = Arc::clone(&scores);
+    let settings = Settings {
+        timing: Timing {
+            heartbeat_interval: Duration::from_millis(100),
+            leader_check_interval: Duration::from_millis(300),
+            ..Timing::default()
+        },
+        ..Settings::with_setup(move |id, node| {
+            let scores = Arc::clone(&node_scores);
+            node.set_score(move || scores[&id].load(Ordering::SeqCst));
+            node.set_hysteresis(5);
+        })
+    };
+    let cluster = Cluster::start_with_settings(&[1, 2, 3], settings);
+    cluster.wait_for(&[1, 2, 3], 3, ELECTION_DEADLINE);
+
+    // outranking the leader within the hysteresis changes nothing
+    scores[&1].store(33, Ordering::SeqCst);
+    thread::sleep(Duration::from_secs(1));
+    assert!(cluster.leaders().values().all(|leader| *leader == Some(3)));
+
+    scores[&1].store(40, Ordering::SeqCst);
+    cluster.wait_for(&[1, 2, 3], 1, ELECTION_DEADLINE);
+    // the previous leader recovers, but not enough to take over
+    scores[&3].store(42, Ordering::SeqCst);
+    thread::sleep(Duration::from_secs(1));
+    assert!(cluster.leaders().values().all(|leader| *leader == Some(1)));
+}
+
+#[test]
+fn resigned_leader_stands_by() {
+    let timing = fast_timing();
+    let cluster = Cluster::start_with_timing(&[1, 2, 3], timing);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    assert!(!cluster.handle(2).resign());
+    assert!(cluster.handle(3).resign());
+    cluster.wait_for(&[1, 2, 3], 2, ELECTION_DEADLINE);
+    // the node on standby does not take over again
+    thread::sleep(Duration::from_secs(2));
+    assert!(cluster.leaders().values().all(|leader| *leader == Some(2)));
+    assert!(cluster.handle(3).status().standby);
+
+    assert!(cluster.handle(3).elect().unwrap());
+    cluster.wait_for(&[1, 2, 3], 3, ELECTION_DEADLINE);
+    assert!(!cluster.handle(3).status().standby);
+}
+
+/// request sends an HTTP request without body to the admin server at `addr`,
+/// and returns the status code and JSON body of the response.
+fn request(addr: SocketAddr, method: &str, path: &str) -> (u16, Value) {
+    let (code, body) = send_request(addr, method, path);
+    (code, serde_json::from_str(&body).unwrap())
+}
+
+/// send_request is like `request`, but returns the body as is.
+fn send_request(addr: SocketAddr, method: &str, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        method, path, addr
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let code = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (code, body.to_owned())
+}
+
+#[test]
+fn admin_api() {
+    let cluster = Cluster::start(&[1, 2]);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    let admin = AdminServer::serve(cluster.handle(2), "127.0.0.1:0").unwrap();
+    let addr = admin.local_addr().unwrap();
+
+    let (code, status) = request(addr, "GET", "/status");
+    assert_eq!(code, 200);
+    assert_eq!(status["id"], 2);
+    assert_eq!(status["leader"], 2);
+    assert_eq!(status["term"], cluster.handle(2).term());
+    assert_eq!(status["peers"][0]["id"], 1);
+    assert_eq!(status["peers"][0]["liveness"], "Alive");
+    assert_eq!(status["peers"][0]["connected"], true);
+    assert_eq!(request(addr, "GET", "/healthz").0, 200);
+    assert_eq!(request(addr, "GET", "/readyz").1["leader"], 2);
+
+    let (code, resigned) = request(addr, "POST", "/resign");
+    assert_eq!(code, 200);
+    assert_eq!(resigned["resigned"], true);
+    cluster.wait_for(&[1, 2], 1, ELECTION_DEADLINE);
+    assert_eq!(request(addr, "POST", "/resign").0, 409);
+    let (_, status) = request(addr, "GET", "/status");
+    assert_eq!(status["standby"], true);
+    assert!(status["last_leader_heartbeat"].is_string());
+
+    let (code, elected) = request(addr, "POST", "/elect");
+    assert_eq!(code, 200);
+    assert_eq!(elected["won"], true);
+    cluster.wait_for(&[1, 2], 2, ELECTION_DEADLINE);
+
+    assert_eq!(request(addr, "GET", "/elect").0, 405);
+    assert_eq!(request(addr, "GET", "/nowhere").0, 404);
+    let (code, metrics) = send_request(addr, "GET", "/metrics");
+    assert_eq!(code, 200);
+    assert!(metrics.contains("bully_leader_id 2"));
+    admin.shutdown();
+}
+
+/// metric returns the value of the metric `name` in the Prometheus text
+/// format.
+fn metric(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no metric {}", name))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn count_failovers() {
+    let timing = fast_timing();
+    let mut cluster = Cluster::start_with_timing(&[1, 2, 3], timing);
+    cluster.wait_for_leader(ELECTION_DEADLINE);
+    thread::sleep(2 * timing.heartbeat_interval);
+    let metrics = cluster.handle(2).metrics().unwrap();
+    assert!(metric(&metrics, "bully_heartbeats_received_total") >= 1.0);
+    assert_eq!(metric(&metrics, "bully_leader_id"), 3.0);
+    cluster.kill(3);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+
+    // either survivor may be the first to miss the heartbeats of the leader
+    let missed: f64 = [1, 2]
+        .iter()
+        .map(|id| cluster.handle(*id).metrics().unwrap())
+        .map(|metrics| metric(&metrics, "bully_missed_heartbeats_total"))
+        .sum();
+    assert!(missed >= 1.0);
+    let metrics = cluster.handle(2).metrics().unwrap();
+    assert!(metric(&metrics, "bully_elections_won_total") >= 1.0);
+    assert!(metric(&metrics, "bully_leader_changes_total") >= 2.0);
+    assert_eq!(metric(&metrics, "bully_leader_id"), 2.0);
+    let started = metric(&metrics, "bully_elections_started_total");
+    let finished = metric(&metrics, "bully_election_duration_seconds_count");
+    assert_eq!(started, finished);
+}
+
+#[test]
+fn drop_spoofed_messages() {
+    const SECRET: &[u8] = b"cluster secret";
+    let settings = Settings::with_setup(|_, node| node.set_auth(Auth::new(SECRET).unwrap()));
+    let cluster = Cluster::start_with_settings(&[1, 2, 3], settings);
+    assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+    let term = cluster.handle(1).term();
+
+    let mut conn = TcpStream::connect(cluster.address(1).to_string()).unwrap();
+    let auth = Auth::new(SECRET).unwrap();
+    let hello = Message::new(Rank::new(0, 9), MessageType::Hello, 0)
+        .with_payload(Hello::new(DEFAULT_CLUSTER).to_string());
+    write_frame(&mut conn, &auth.seal(TextCodec.encode(&hello), Some(1))).unwrap();
+    read_frame(&mut conn).unwrap();
+    // an unknown node claims to lead a later term without the secret
+    let victory = Message::new(Rank::new(0, 9), MessageType::Victory, term + 1);
+    write_frame(&mut conn, &TextCodec.encode(&victory)).unwrap();
+    // a heartbeat of the leader is replayed
+    let heartbeat = Message::new(Rank::new(0, 3), MessageType::HeartBeat, term);
+    let frame = auth.seal(TextCodec.encode(&heartbeat), Some(1));
+    write_frame(&mut conn, &frame).unwrap();
+    write_frame(&mut conn, &frame).unwrap();
+    // a heartbeat of the leader to node 2 is replayed to node 1
+    let frame = auth.seal(TextCodec.encode(&heartbeat), Some(2));
+    write_frame(&mut conn, &frame).unwrap();
+
+    let handle = cluster.handle(1);
+    let rejected = || metric(&handle.metrics().unwrap(), "bully_rejected_messages_total");
+    assert!(
+        wait_until(ELECTION_DEADLINE, || rejected() >= 3.0),
+        "messages are not dropped"
+    );
+    assert_eq!(handle.leader(), Some(3));
+}
+
+#[test]
+fn elect_with_codecs() {
+    for name in ["json", "binary"].iter() {
+        let settings =
+            Settings::with_setup(move |_, node| node.set_codec(new_codec(name).unwrap()));
+        let mut cluster = Cluster::start_with_settings(&[1, 2, 3], settings);
+        assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 3);
+        cluster.shutdown(3);
+        assert_eq!(cluster.wait_for_leader(ELECTION_DEADLINE), 2);
+    }
+}
+
+#[test]
+fn refuse_other_clusters() {
+    let settings =
+        Settings::with_setup(|id, node| node.set_cluster(if id == 3 { "other" } else { "prod" }));
+    let cluster = Cluster::start_with_settings(&[1, 2, 3], settings);
+    cluster.wait_for(&[1, 2], 2, ELECTION_DEADLINE);
+    let status = cluster.handle(1).status();
+    let version = |id| {
+        let peer = status.peers.iter().find(|peer| peer.id == id).unwrap();
+        peer.protocol_version
+    };
+    assert_eq!(version(2), Some(PROTOCOL_VERSION));
+    assert_eq!(version(3), None);
+    // the node of the other cluster is left alone
+    cluster.wait_for(&[3], 3, ELECTION_DEADLINE);
+}
+
+#[test]
+fn app_messages() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let clone = Arc::clone(&received);
+    let settings = Settings::with_setup(move |id, node| {
+        let received = Arc::clone(&clone);
+        node.on_message("config", move |sender, body: &str| {
+            received.lock().unwrap().push((id, sender, body.to_owned()))
+        })
+        .unwrap();
+    });
+    let cluster = Cluster::start_with_settings(&[1, 2, 3], settings);
+    cluster.wait_for(&[1, 2, 3], 3, ELECTION_DEADLINE);
+    let received_sorted = || {
+        let mut got = received.lock().unwrap().clone();
+        got.sort();
+        got
+    };
+    let wait_for = |expected: Vec<(u64, u64, String)>| {
+        assert!(
+            wait_until(ELECTION_DEADLINE, || received_sorted() == expected),
+            "received {:?}",
+            received_sorted()
+        );
+        received.lock().unwrap().clear();
+    };
+
+    cluster.handle(3).broadcast("config", "v2:eu").unwrap();
+    wait_for(vec![(1, 3, "v2:eu".to_owned()), (2, 3, "v2:eu".to_owned())]);
+    // only the leader broadcasts
+    assert!(cluster.handle(1).broadcast("config", "v3").is_err());
+    assert!(cluster.handle(3).broadcast("a:b", "v3").is_err());
+
+    cluster.handle(1).send_to(2, "config", "ack").unwrap();
+    wait_for(vec![(2, 1, "ack".to_owned())]);
+    assert!(cluster.handle(1).send_to(9, "config", "ack").is_err());
+}
+
+#[test]
+fn forward_requests() {
+    let settings = Settings::with_setup(|id, node| {
+        node.on_request("lock", move |sender, body: &str| {
+            Ok(format!(
+                "{} locked by node({}) for node({})",
+                body, id, sender
+            ))
+        })
+        .unwrap();
+    });
+    let mut cluster = Cluster::start_with_settings(&[1, 2, 3], settings);
+    cluster.wait_for(&[1, 2, 3], 3, ELECTION_DEADLINE);
+    let forward =
+        |cluster: &Cluster, id, kind| cluster.handle(id).forward(kind, "jobs", ELECTION_DEADLINE);
+    assert_eq!(
+        forward(&cluster, 1, "lock").unwrap(),
+        "jobs locked by node(3) for node(1)"
+    );
+    // the leader handles its own requests
+    assert_eq!(
+        forward(&cluster, 3, "lock").unwrap(),
+        "jobs locked by node(3) for node(3)"
+    );
+    // failed requests are not retried
+    assert!(forward(&cluster, 1, "unknown").is_err());
+
+    // the request is retried until the next leader handles it
+    cluster.kill(3);
+    assert_eq!(
+        forward(&cluster, 1, "lock").unwrap(),
+        "jobs locked by node(2) for node(1)"
+    );
+}
@@ -0,0 +1,29 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{fast_timing, Ca, Cluster, Settings, ELECTION_DEADLINE};
+use leader_elect::bully::bully::Liveness;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn refuse_impostor() {
+    let ca = Ca::new();
+    let settings = Settings {
+        timing: fast_timing(),
+        // node 3 outranks the others, but holds the certificate of node 2
+        tls: Some(Box::new(move |id| {
+            ca.transport(&format!("node-{}", id.min(2)))
+        })),
+        ..Settings::default()
+    };
+    let cluster = Cluster::start_with_settings(&[1, 2, 3], settings);
+    cluster.wait_for(&[1, 2], 2, ELECTION_DEADLINE);
+    thread::sleep(Duration::from_secs(2));
+    cluster.wait_for(&[1, 2], 2, Duration::from_secs(0));
+    for id in [1, 2].iter() {
+        let status = cluster.handle(*id).status();
+        let peer = status.peers.iter().find(|peer| peer.id == 3).unwrap();
+        assert_eq!(peer.liveness, Liveness::Dead);
+    }
+}
@@ -0,0 +1,481 @@
+//! Test support for running a whole bully cluster in one process.
+use leader_elect::bully::address::Address;
+use leader_elect::bully::bully::{self, FencingToken, Node, NodeHandle};
+use leader_elect::bully::memory::MemoryNetwork;
+use leader_elect::bully::message::NodeId;
+use leader_elect::bully::observer::LeadershipEvent;
+use leader_elect::bully::timing::Timing;
+use leader_elect::bully::tls::TlsTransport;
+use leader_elect::bully::transport::{Listener, Transport};
+use leader_elect::logger;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// ELECTION_DEADLINE bounds how long a cluster may take to agree on a leader.
+pub const ELECTION_DEADLINE: Duration = Duration::from_secs(20);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Setup changes a node before it starts.
+pub type Setup = Box<dyn Fn(NodeId, &mut Node)>;
+
+/// Tls creates the TLS transport of a node, see `Ca::transport`.
+pub type Tls = Box<dyn Fn(NodeId) -> TlsTransport>;
+
+/// Settings apply to all nodes of a cluster.
+#[derive(Default)]
+pub struct Settings {
+    pub timing: Timing,
+    /// priorities of nodes, 0 if absent
+    pub priorities: BTreeMap<NodeId, u64>,
+    pub setup: Option<Setup>,
+    /// nodes connect over mutual TLS if set, over TCP otherwise
+    pub tls: Option<Tls>,
+}
+
+/// Ca issues certificates to the nodes of a cluster, which are written to a
+/// temporary directory for `TlsTransport` to load them.
+pub struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+    dir: PathBuf,
+    issued: AtomicUsize,
+}
+
+impl Ca {
+    pub fn new() -> Ca {
+        static CAS: AtomicUsize = AtomicUsize::new(0);
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let dir = std::env::temp_dir().join(format!(
+            "bully-tls-{}-{}",
+            std::process::id(),
+            CAS.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        Ca {
+            cert: params.self_signed(&key).unwrap(),
+            key,
+            dir,
+            issued: AtomicUsize::new(0),
+        }
+    }
+
+    /// transport issues a certificate for the DNS `name`, e.g., `node-3`,
+    /// and returns the transport using it.
+    pub fn transport(&self, name: &str) -> TlsTransport {
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let issued = self.issued.fetch_add(1, Ordering::SeqCst);
+        let paths: Vec<String> = [
+            ("ca.pem", self.cert.pem()),
+            ("cert.pem", cert.pem()),
+            ("key.pem", key.serialize_pem()),
+        ]
+        .iter()
+        .map(|(suffix, pem)| {
+            let path = self.dir.join(format!("{}-{}-{}", name, issued, suffix));
+            fs::write(&path, pem).unwrap();
+            path.to_str().unwrap().to_owned()
+        })
+        .collect();
+        TlsTransport::new(&paths[0], &paths[1], &paths[2]).unwrap()
+    }
+}
+
+impl Settings {
+    /// with_setup changes every node with `setup` before it starts, other
+    /// settings are the default ones.
+    pub fn with_setup<F: Fn(NodeId, &mut Node) + 'static>(setup: F) -> Settings {
+        Settings {
+            setup: Some(Box::new(setup)),
+            ..Settings::default()
+        }
+    }
+}
+
+/// fast_timing detects a crashed leader and elects the next one within a
+/// second or so, well before the default timing does.
+pub fn fast_timing() -> Timing {
+    Timing {
+        alive_timeout: Duration::from_millis(200),
+        heartbeat_interval: Duration::from_millis(200),
+        leader_check_interval: Duration::from_millis(600),
+        ..Timing::default()
+    }
+}
+
+/// wait_until polls the `predicate` until it holds, and returns false if it
+/// does not within `timeout`.
+pub fn wait_until<F: FnMut() -> bool>(timeout: Duration, mut predicate: F) -> bool {
+    let start = Instant::now();
+    while !predicate() {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    true
+}
+
+/// Cluster runs bully nodes on ephemeral loopback ports, or on an in-memory
+/// network.
+pub struct Cluster {
+    addresses: BTreeMap<NodeId, Address>,
+    nodes: BTreeMap<NodeId, Option<Arc<NodeHandle>>>,
+    events: Arc<Mutex<Vec<(NodeId, LeadershipEvent)>>>,
+    network: Option<MemoryNetwork>,
+    settings: Settings,
+}
+
+impl Cluster {
+    /// start runs one node for each of the `ids` over TCP.
+    pub fn start(ids: &[NodeId]) -> Cluster {
+        Cluster::start_with_settings(ids, Settings::default())
+    }
+
+    /// start_with_timing runs one node for each of the `ids` over TCP, all
+    /// nodes use the given `timing`.
+    pub fn start_with_timing(ids: &[NodeId], timing: Timing) -> Cluster {
+        let settings = Settings {
+            timing,
+            ..Settings::default()
+        };
+        Cluster::start_with_settings(ids, settings)
+    }
+
+    /// start_with_priorities runs one node for each of the id, priority
+    /// pairs over TCP.
+    pub fn start_with_priorities(priorities: &[(NodeId, u64)]) -> Cluster {
+        let ids: Vec<NodeId> = priorities.iter().map(|(id, _)| *id).collect();
+        let settings = Settings {
+            priorities: priorities.iter().copied().collect(),
+            ..Settings::default()
+        };
+        Cluster::start_with_settings(&ids, settings)
+    }
+
+    /// start_with_settings runs one node for each of the `ids` over TCP.
+    pub fn start_with_settings(ids: &[NodeId], settings: Settings) -> Cluster {
+        Cluster::start_tcp(ids, loopback, settings)
+    }
+
+    /// start_on_localhost is like `start`, but nodes know each other by the
+    /// host name `localhost`.
+    pub fn start_on_localhost(ids: &[NodeId]) -> Cluster {
+        let address = |port| Address::Host("localhost".to_owned(), port);
+        Cluster::start_tcp(ids, address, Settings::default())
+    }
+
+    fn start_tcp<F: Fn(u16) -> Address>(ids: &[NodeId], address: F, settings: Settings) -> Cluster {
+        let _ = logger::init("info");
+        let mut listeners: BTreeMap<NodeId, Box<dyn Listener>> = BTreeMap::new();
+        let mut addresses = BTreeMap::new();
+        for id in ids {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = address(listener.local_addr().unwrap().port());
+            listeners.insert(*id, listen(&settings, *id, listener, &addr));
+            addresses.insert(*id, addr);
+        }
+        Cluster::start_with(addresses, listeners, None, settings)
+    }
+
+    /// start_in_memory runs one node for each of the `ids` on an in-memory
+    /// network, whose links can be cut with `partition`.
+    pub fn start_in_memory(ids: &[NodeId]) -> Cluster {
+        let _ = logger::init("info");
+        let network = MemoryNetwork::new();
+        let mut listeners = BTreeMap::new();
+        let mut addresses = BTreeMap::new();
+        for id in ids {
+            let addr: Address = format!("node-{}:7000", id).parse().unwrap();
+            let listener = network.transport(addr.clone()).listen(&addr).unwrap();
+            addresses.insert(*id, addr);
+            listeners.insert(*id, listener);
+        }
+        Cluster::start_with(addresses, listeners, Some(network), Settings::default())
+    }
+
+    fn start_with(
+        addresses: BTreeMap<NodeId, Address>,
+        listeners: BTreeMap<NodeId, Box<dyn Listener>>,
+        network: Option<MemoryNetwork>,
+        settings: Settings,
+    ) -> Cluster {
+        let mut cluster = Cluster {
+            addresses,
+            nodes: BTreeMap::new(),
+            events: Arc::default(),
+            network,
+            settings,
+        };
+        // start the nodes concurrently, as a cluster usually comes up
+        let starting: Vec<_> = listeners
+            .into_iter()
+            .map(|(id, listener)| {
+                let node = cluster.new_node(id);
+                (id, thread::spawn(move || bully::start(node, listener)))
+            })
+            .collect();
+        for (id, hdl) in starting {
+            let handle = hdl.join().unwrap().unwrap();
+            cluster.nodes.insert(id, Some(Arc::new(handle)));
+        }
+        cluster
+    }
+
+    /// kill crashes the node `id`.
+    pub fn kill(&mut self, id: NodeId) {
+        let handle = self.nodes.get_mut(&id).unwrap().take();
+        handle.expect("the node is not running").kill();
+    }
+
+    /// shutdown stops the node `id` gracefully.
+    pub fn shutdown(&mut self, id: NodeId) {
+        let handle = self.nodes.get_mut(&id).unwrap().take();
+        handle.expect("the node is not running").shutdown();
+    }
+
+    /// restart starts the stopped node `id` on its original address.
+    pub fn restart(&mut self, id: NodeId) {
+        assert!(self.nodes[&id].is_none(), "the node is running");
+        let addr = &self.addresses[&id];
+        let listener: Box<dyn Listener> = match self.network.as_ref() {
+            Some(network) => network.transport(addr.clone()).listen(addr).unwrap(),
+            None => {
+                let listener = TcpListener::bind(("127.0.0.1", addr.port())).unwrap();
+                listen(&self.settings, id, listener, addr)
+            }
+        };
+        let handle = bully::start(self.new_node(id), listener).unwrap();
+        self.nodes.insert(id, Some(Arc::new(handle)));
+    }
+
+    /// join starts the node `id`, which joins the cluster through the nodes
+    /// `seeds`.
+    pub fn join(&mut self, id: NodeId, seeds: &[NodeId]) {
+        let (addr, listener): (Address, Box<dyn Listener>) = match self.network.as_ref() {
+            Some(network) => {
+                let addr: Address = format!("node-{}:7000", id).parse().unwrap();
+                let listener = network.transport(addr.clone()).listen(&addr).unwrap();
+                (addr, listener)
+            }
+            None => {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = loopback(listener.local_addr().unwrap().port());
+                let listener = listen(&self.settings, id, listener, &addr);
+                (addr, listener)
+            }
+        };
+        let seeds = seeds
+            .iter()
+            .map(|seed| self.addresses[seed].clone())
+            .collect();
+        self.addresses.insert(id, addr.clone());
+        let mut node = Node::with_seeds(id, seeds, addr);
+        self.configure(id, &mut node);
+        let handle = bully::start(node, listener).unwrap();
+        self.nodes.insert(id, Some(Arc::new(handle)));
+    }
+
+    /// leave removes the node `id` from the cluster gracefully.
+    pub fn leave(&mut self, id: NodeId) {
+        let handle = self.nodes.remove(&id).flatten();
+        handle.expect("the node is not running").leave();
+        self.addresses.remove(&id);
+    }
+
+    /// wait_for_members waits until the running nodes agree that `expected`
+    /// are the members of the cluster, and panics after `deadline`.
+    pub fn wait_for_members(&self, expected: &[NodeId], deadline: Duration) {
+        let start = Instant::now();
+        loop {
+            let members: BTreeMap<NodeId, Vec<NodeId>> = self
+                .nodes
+                .iter()
+                .filter_map(|(id, handle)| handle.as_ref().map(|hdl| (*id, hdl.members())))
+                .collect();
+            if members.values().all(|members| members == expected) {
+                return;
+            }
+            if start.elapsed() > deadline {
+                panic!(
+                    "expect members {:?} within {:?}, got {:?}",
+                    expected, deadline, members
+                );
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// partition cuts the link between the nodes `a` and `b` of an in-memory
+    /// cluster.
+    pub fn partition(&self, a: NodeId, b: NodeId) {
+        let network = self.network.as_ref().expect("not an in-memory cluster");
+        network.partition(&self.addresses[&a], &self.addresses[&b]);
+    }
+
+    /// heal restores the link between the nodes `a` and `b` of an in-memory
+    /// cluster.
+    pub fn heal(&self, a: NodeId, b: NodeId) {
+        let network = self.network.as_ref().expect("not an in-memory cluster");
+        network.heal(&self.addresses[&a], &self.addresses[&b]);
+    }
+
+    /// live_ids returns the ids of the running nodes.
+    pub fn live_ids(&self) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|(_, handle)| handle.is_some())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// leaders returns the leader known by each running node.
+    pub fn leaders(&self) -> BTreeMap<NodeId, Option<NodeId>> {
+        self.nodes
+            .iter()
+            .filter_map(|(id, handle)| handle.as_ref().map(|hdl| (*id, hdl.leader())))
+            .collect()
+    }
+
+    /// wait_for_leader waits until all running nodes agree that the live node
+    /// with the highest priority, or the largest id among equal priorities,
+    /// is the leader, and panics after `deadline`.
+    pub fn wait_for_leader(&self, deadline: Duration) -> NodeId {
+        self.wait_for_leader_among(&self.live_ids(), deadline)
+    }
+
+    /// wait_for_leader_among waits until the running nodes `ids` agree that
+    /// the highest ranked of them is the leader, and panics after `deadline`.
+    pub fn wait_for_leader_among(&self, ids: &[NodeId], deadline: Duration) -> NodeId {
+        let priority = |id: &NodeId| self.settings.priorities.get(id).copied().unwrap_or(0);
+        let expected = *ids
+            .iter()
+            .max_by_key(|id| (priority(id), **id))
+            .expect("no live node");
+        self.wait_for(ids, expected, deadline);
+        expected
+    }
+
+    /// wait_for waits until the running nodes `ids` agree that `expected` is
+    /// the leader, and panics after `deadline`.
+    pub fn wait_for(&self, ids: &[NodeId], expected: NodeId, deadline: Duration) {
+        let start = Instant::now();
+        loop {
+            let leaders: BTreeMap<NodeId, Option<NodeId>> = self
+                .leaders()
+                .into_iter()
+                .filter(|(id, _)| ids.contains(id))
+                .collect();
+            if leaders.values().all(|leader| *leader == Some(expected)) {
+                return;
+            }
+            if start.elapsed() > deadline {
+                panic!(
+                    "expect node({}) to be the only leader within {:?}, got {:?}",
+                    expected, deadline, leaders
+                );
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// address returns the address the node `id` listens on.
+    pub fn address(&self, id: NodeId) -> Address {
+        self.addresses[&id].clone()
+    }
+
+    /// handle returns the handle of the running node `id`.
+    pub fn handle(&self, id: NodeId) -> Arc<NodeHandle> {
+        let handle = self.nodes[&id].as_ref().expect("the node is not running");
+        Arc::clone(handle)
+    }
+
+    /// fencing_token returns the fencing token of the node `id` if it leads.
+    pub fn fencing_token(&self, id: NodeId) -> Option<FencingToken> {
+        self.nodes[&id].as_ref().and_then(|hdl| hdl.fencing_token())
+    }
+
+    /// events returns the leadership events observed by the node `id` so far.
+    pub fn events(&self, id: NodeId) -> Vec<LeadershipEvent> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(node_id, _)| *node_id == id)
+            .map(|(_, event)| *event)
+            .collect()
+    }
+
+    fn new_node(&self, id: NodeId) -> Node {
+        let peers = self
+            .addresses
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != id)
+            .map(|(peer_id, addr)| format!("{}={}", peer_id, addr))
+            .collect::<Vec<String>>()
+            .join(",");
+        let mut node = Node::new(id, &peers, &self.addresses[&id].to_string()).unwrap();
+        self.configure(id, &mut node);
+        node
+    }
+
+    /// configure applies the settings of the cluster to the node `id`.
+    fn configure(&self, id: NodeId, node: &mut Node) {
+        node.set_timing(self.settings.timing).unwrap();
+        node.set_priority(self.settings.priorities.get(&id).copied().unwrap_or(0));
+        if let Some(setup) = self.settings.setup.as_ref() {
+            setup(id, node);
+        }
+        if let Some(network) = self.network.as_ref() {
+            node.set_transport(network.transport(self.addresses[&id].clone()));
+        }
+        if let Some(tls) = self.settings.tls.as_ref() {
+            node.set_transport(tls(id));
+        }
+        let events = Arc::clone(&self.events);
+        node.add_observer(move |event| events.lock().unwrap().push((id, event)));
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for handle in self.nodes.values_mut() {
+            if let Some(handle) = handle.take() {
+                handle.kill();
+            }
+        }
+    }
+}
+
+/// listen serves the node `id` on the `listener` bound to `addr`, over TLS
+/// if the `settings` ask for it.
+fn listen(
+    settings: &Settings,
+    id: NodeId,
+    listener: TcpListener,
+    addr: &Address,
+) -> Box<dyn Listener> {
+    match settings.tls.as_ref() {
+        Some(tls) => {
+            // release the port for the TLS listener to bind it again
+            drop(listener);
+            tls(id).listen(addr).unwrap()
+        }
+        None => Box::new(listener),
+    }
+}
+
+fn loopback(port: u16) -> Address {
+    Address::from(SocketAddr::from(([127, 0, 0, 1], port)))
+}
@@ -0,0 +1,69 @@
+use leader_elect::logger;
+use leader_elect::ring::ring::{self, Node, NodeHandle};
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// ELECTION_DEADLINE bounds how long a ring may take to agree on a leader.
+const ELECTION_DEADLINE: Duration = Duration::from_secs(20);
+
+/// start runs one ring node for each of the `ids` on ephemeral loopback
+/// ports.
+fn start(ids: &[u8]) -> BTreeMap<u8, NodeHandle> {
+    let _ = logger::init("info");
+    let listeners: BTreeMap<u8, TcpListener> = ids
+        .iter()
+        .map(|id| (*id, TcpListener::bind("127.0.0.1:0").unwrap()))
+        .collect();
+    let addresses: BTreeMap<u8, SocketAddr> = listeners
+        .iter()
+        .map(|(id, listener)| (*id, listener.local_addr().unwrap()))
+        .collect();
+    listeners
+        .into_iter()
+        .map(|(id, listener)| {
+            let peers = addresses
+                .iter()
+                .filter(|(peer_id, _)| **peer_id != id)
+                .map(|(peer_id, addr)| format!("{}={}", peer_id, addr))
+                .collect::<Vec<String>>()
+                .join(",");
+            let node = Node::new(id, &peers, &addresses[&id].to_string()).unwrap();
+            (id, ring::start(node, listener))
+        })
+        .collect()
+}
+
+/// wait_for waits until all `nodes` agree that `expected` is the leader, and
+/// panics after the deadline.
+fn wait_for(nodes: &BTreeMap<u8, NodeHandle>, expected: u8) {
+    let start = Instant::now();
+    loop {
+        let leaders: BTreeMap<u8, Option<u8>> =
+            nodes.iter().map(|(id, hdl)| (*id, hdl.leader())).collect();
+        if leaders.values().all(|leader| *leader == Some(expected)) {
+            return;
+        }
+        assert!(
+            start.elapsed() < ELECTION_DEADLINE,
+            "expect member({}) to be the leader, got {:?}",
+            expected,
+            leaders
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn ring_leader_crash() {
+    let mut nodes = start(&[1, 2, 3, 4]);
+    wait_for(&nodes, 4);
+    nodes.remove(&4).unwrap().kill();
+    wait_for(&nodes, 3);
+    nodes.remove(&3).unwrap().kill();
+    wait_for(&nodes, 2);
+    for (_, hdl) in nodes {
+        hdl.kill();
+    }
+}